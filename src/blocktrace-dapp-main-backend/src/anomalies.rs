@@ -0,0 +1,365 @@
+// 🚨 Anomaly detection on step sequences.
+//
+// Every recorded step is compared with the product's earlier steps, and each serial it references
+// with that serial's last sighting. Physically impossible movement, the same item seen at distant
+// places at once, arrivals well after the promised estimate, estimates revised backwards and
+// sudden quality drops are our main counterfeit and diversion signals; they are stored with a
+// severity and can be queried per product and per organization.
+use candid::{candid_method, CandidType};
+use ic_cdk_macros::query;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+use crate::governance::is_admin;
+use crate::history::{self, is_involved, LifecycleStage};
+use crate::logs;
+use crate::organizations::organization_of;
+use crate::serials::SerialSighting;
+use crate::webhooks::{self, WebhookAlert};
+use crate::{authenticated_caller, Step};
+
+const MAX_ANOMALIES: usize = 10_000;
+const NANOS_PER_HOUR: f64 = 3_600_000_000_000.0;
+const EARTH_RADIUS_KM: f64 = 6_371.0;
+// Sightings this close in time are treated as simultaneous
+const SIMULTANEOUS_WINDOW_NANOS: u64 = 15 * 60 * 1_000_000_000;
+// Positions further apart than this cannot be the same place
+const DISTANT_LOCATION_KM: f64 = 50.0;
+const QUALITY_DROP_MEDIUM: u8 = 25;
+const QUALITY_DROP_HIGH: u8 = 50;
+// Slack allowed against the latest estimated arrival before an arrival or revision is flagged
+const ARRIVAL_TOLERANCE_NANOS: u64 = 6 * 3_600_000_000_000;
+const LATE_ARRIVAL_HIGH_NANOS: u64 = 7 * 24 * 3_600_000_000_000;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum AnomalyKind {
+    ImpossibleTravel,
+    DuplicateSighting,
+    TimestampRegression,
+    QualityDrop,
+    LateArrival,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum AnomalySeverity {
+    Low,
+    Medium,
+    High,
+    Critical,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct Anomaly {
+    pub id: u64,
+    pub product_id: String,
+    pub sequence: u64,
+    pub organization_id: String,
+    pub kind: AnomalyKind,
+    pub severity: AnomalySeverity,
+    pub message: String,
+    pub detected_at: u64,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+pub struct AnomalyState {
+    pub anomalies: Vec<Anomaly>,
+    pub next_id: u64,
+}
+
+thread_local! {
+    static ANOMALIES: RefCell<AnomalyState> = RefCell::new(AnomalyState::default());
+}
+
+pub(crate) fn snapshot() -> AnomalyState {
+    ANOMALIES.with(|a| a.borrow().clone())
+}
+
+pub(crate) fn restore(state: AnomalyState) {
+    ANOMALIES.with(|a| *a.borrow_mut() = state);
+}
+
+pub(crate) fn clear() {
+    ANOMALIES.with(|a| a.borrow_mut().anomalies.clear());
+}
+
+pub struct Finding {
+    pub kind: AnomalyKind,
+    pub severity: AnomalySeverity,
+    pub message: String,
+}
+
+fn finding(kind: AnomalyKind, severity: AnomalySeverity, message: String) -> Finding {
+    Finding { kind, severity, message }
+}
+
+fn position(step: &Step) -> Option<(f64, f64)> {
+    step.gps_latitude.zip(step.gps_longitude)
+}
+
+fn distance_km((lat1, lon1): (f64, f64), (lat2, lon2): (f64, f64)) -> f64 {
+    let (dlat, dlon) = ((lat2 - lat1).to_radians(), (lon2 - lon1).to_radians());
+    let a = (dlat / 2.0).sin().powi(2) + lat1.to_radians().cos() * lat2.to_radians().cos() * (dlon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+}
+
+/// Fastest plausible speed for the transport mode; unknown modes are allowed airliner speed.
+fn max_speed_kmh(transport_mode: Option<&str>) -> f64 {
+    let mode = transport_mode.unwrap_or_default().to_lowercase();
+    if mode.contains("sea") || mode.contains("ship") || mode.contains("vessel") {
+        80.0
+    } else if mode.contains("truck") || mode.contains("road") || mode.contains("van") {
+        160.0
+    } else if mode.contains("rail") || mode.contains("train") {
+        350.0
+    } else {
+        1_000.0
+    }
+}
+
+/// Anomalies raised by `step` against the product's earlier steps, oldest first.
+pub fn detect(history: &[Step], step: &Step) -> Vec<Finding> {
+    let mut found = Vec::new();
+
+    if let Some(here) = position(step) {
+        let simultaneous = history.iter().filter_map(|s| position(s).map(|p| (s, p))).find(|(s, p)| {
+            step.timestamp.abs_diff(s.timestamp) <= SIMULTANEOUS_WINDOW_NANOS && distance_km(*p, here) > DISTANT_LOCATION_KM
+        });
+        let previous = history.iter().rev().find_map(|s| position(s).map(|p| (s, p)));
+        if let Some((other, there)) = simultaneous {
+            found.push(finding(
+                AnomalyKind::DuplicateSighting,
+                AnomalySeverity::Critical,
+                format!(
+                    "Seen at {} while step {} places it {:.0} km away at {}",
+                    step.location,
+                    other.sequence.unwrap_or_default(),
+                    distance_km(there, here),
+                    other.location
+                ),
+            ));
+        } else if let Some((previous, there)) = previous {
+            let hours = step.timestamp.saturating_sub(previous.timestamp) as f64 / NANOS_PER_HOUR;
+            let speed = distance_km(there, here) / hours;
+            let limit = max_speed_kmh(step.transport_mode.as_deref().or(previous.transport_mode.as_deref()));
+            if speed > limit {
+                found.push(finding(
+                    AnomalyKind::ImpossibleTravel,
+                    AnomalySeverity::High,
+                    format!("Moving from {} to {} implies {:.0} km/h (limit {:.0} km/h)", previous.location, step.location, speed, limit),
+                ));
+            }
+        }
+    }
+
+    if let Some(previous) = history.last() {
+        if step.actual_arrival.is_some_and(|arrival| arrival < previous.timestamp) {
+            found.push(finding(
+                AnomalyKind::TimestampRegression,
+                AnomalySeverity::High,
+                format!("Arrival reported before the previous step ({}) was recorded", previous.action),
+            ));
+        }
+        if step.actual_arrival.is_some_and(|arrival| arrival > step.timestamp) {
+            found.push(finding(
+                AnomalyKind::TimestampRegression,
+                AnomalySeverity::Medium,
+                "Arrival reported later than the step itself".to_string(),
+            ));
+        }
+    }
+    // Validation already keeps a step's own estimate after the step, so estimates are compared
+    // with the latest one promised by an earlier step
+    if let Some((promised_by, promised)) = history.iter().rev().find_map(|s| s.estimated_arrival.map(|eta| (s, eta))) {
+        let arrival = step.actual_arrival.or_else(|| {
+            matches!(history::lifecycle_stage(step), LifecycleStage::Received | LifecycleStage::Delivered).then_some(step.timestamp)
+        });
+        if let Some(late) = arrival.map(|a| a.saturating_sub(promised)).filter(|late| *late > ARRIVAL_TOLERANCE_NANOS) {
+            let severity = if late > LATE_ARRIVAL_HIGH_NANOS { AnomalySeverity::High } else { AnomalySeverity::Medium };
+            found.push(finding(
+                AnomalyKind::LateArrival,
+                severity,
+                format!("Arrived {:.0} h after the arrival estimated at step {}", late as f64 / NANOS_PER_HOUR, promised_by.action),
+            ));
+        }
+        if step.estimated_arrival.is_some_and(|eta| eta.saturating_add(ARRIVAL_TOLERANCE_NANOS) < promised) {
+            found.push(finding(
+                AnomalyKind::TimestampRegression,
+                AnomalySeverity::Medium,
+                format!("Estimated arrival moved back before the one given at step {}", promised_by.action),
+            ));
+        }
+    }
+
+    let last_quality = history.iter().rev().find_map(|s| s.quality_score);
+    if let (Some(before), Some(now)) = (last_quality, step.quality_score) {
+        let drop = before.saturating_sub(now);
+        if drop >= QUALITY_DROP_MEDIUM {
+            let severity = if drop >= QUALITY_DROP_HIGH { AnomalySeverity::High } else { AnomalySeverity::Medium };
+            found.push(finding(AnomalyKind::QualityDrop, severity, format!("Quality score fell from {} to {}", before, now)));
+        }
+    }
+    found
+}
+
+/// A serial referenced by `step` whose previous sighting under another product puts it somewhere
+/// distant at the same time: a cloned pack identifier.
+pub fn serial_seen_elsewhere(serial: &str, previous: &SerialSighting, step: &Step) -> Option<Finding> {
+    let there = previous.gps_latitude.zip(previous.gps_longitude)?;
+    let here = position(step)?;
+    let distance = distance_km(there, here);
+    if previous.product_id == step.product_id
+        || step.timestamp.abs_diff(previous.timestamp) > SIMULTANEOUS_WINDOW_NANOS
+        || distance <= DISTANT_LOCATION_KM
+    {
+        return None;
+    }
+    Some(finding(
+        AnomalyKind::DuplicateSighting,
+        AnomalySeverity::Critical,
+        format!(
+            "Serial {} seen at {} while product {} places it {:.0} km away at {}",
+            serial, step.location, previous.product_id, distance, previous.location
+        ),
+    ))
+}
+
+/// Stores findings for a step that has just been appended and alerts subscribers on severe ones.
+pub(crate) fn record(step: &Step, findings: Vec<Finding>) {
+    let organization_id = organization_of(&step.user_id);
+    for Finding { kind, severity, message } in findings {
+        let anomaly = ANOMALIES.with(|a| {
+            let mut state = a.borrow_mut();
+            state.next_id += 1;
+            let anomaly = Anomaly {
+                id: state.next_id,
+                product_id: step.product_id.clone(),
+                sequence: step.sequence.unwrap_or_default(),
+                organization_id: organization_id.clone(),
+                kind,
+                severity,
+                message,
+                detected_at: step.timestamp,
+            };
+            state.anomalies.push(anomaly.clone());
+            if state.anomalies.len() > MAX_ANOMALIES {
+                state.anomalies.remove(0);
+            }
+            anomaly
+        });
+        logs::warn(
+            "anomalies",
+            &anomaly.message,
+            vec![
+                ("product_id", anomaly.product_id.clone()),
+                ("sequence", anomaly.sequence.to_string()),
+                ("kind", format!("{:?}", anomaly.kind)),
+                ("severity", format!("{:?}", anomaly.severity)),
+            ],
+        );
+        if anomaly.severity >= AnomalySeverity::High {
+            webhooks::notify_alert(WebhookAlert {
+                alert_type: "anomaly".to_string(),
+                product_id: anomaly.product_id.clone(),
+                batch_number: step.batch_number.clone(),
+                message: format!("{:?} ({:?}): {}", anomaly.kind, anomaly.severity, anomaly.message),
+                timestamp: anomaly.detected_at,
+            });
+        }
+    }
+}
+
+fn matching(keep: impl Fn(&Anomaly) -> bool) -> Vec<Anomaly> {
+    ANOMALIES.with(|a| a.borrow().anomalies.iter().filter(|anomaly| keep(anomaly)).cloned().collect())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_product_anomalies(product_id: String, caller_principal: String) -> Vec<Anomaly> {
+    let Ok(caller) = authenticated_caller(&caller_principal) else {
+        return vec![];
+    };
+    if !is_involved(&product_id, &caller) && !is_admin(&caller) {
+        return vec![];
+    }
+    matching(|anomaly| anomaly.product_id == product_id)
+}
+
+// Anomalies on steps recorded by members of the organization, optionally above a severity.
+#[query]
+#[candid_method(query)]
+fn get_organization_anomalies(
+    organization_id: String,
+    min_severity: Option<AnomalySeverity>,
+    caller_principal: String,
+) -> Vec<Anomaly> {
+    let Ok(caller) = authenticated_caller(&caller_principal) else {
+        return vec![];
+    };
+    if organization_of(&caller) != organization_id && !is_admin(&caller) {
+        return vec![];
+    }
+    let min_severity = min_severity.unwrap_or(AnomalySeverity::Low);
+    matching(|anomaly| anomaly.organization_id == organization_id && anomaly.severity >= min_severity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_step;
+
+    const HOUR: u64 = 3_600_000_000_000;
+
+    fn at(product_id: &str, timestamp: u64, lat: f64, lon: f64, quality: Option<u8>) -> Step {
+        let mut step = test_step(product_id, "carrier", timestamp);
+        step.gps_latitude = Some(lat);
+        step.gps_longitude = Some(lon);
+        step.quality_score = quality;
+        step
+    }
+
+    #[test]
+    fn flags_travel_sightings_and_quality_drops() {
+        // Paris, then Berlin (~880 km) two hours later by truck
+        let paris = at("P1", 10 * HOUR, 48.85, 2.35, Some(95));
+        let mut berlin = at("P1", 12 * HOUR, 52.52, 13.40, Some(40));
+        berlin.transport_mode = Some("truck".to_string());
+        let kinds: Vec<AnomalyKind> = detect(std::slice::from_ref(&paris), &berlin).into_iter().map(|f| f.kind).collect();
+        assert_eq!(kinds, vec![AnomalyKind::ImpossibleTravel, AnomalyKind::QualityDrop]);
+
+        let clone = at("P1", 10 * HOUR + HOUR / 10, 52.52, 13.40, None);
+        let found = detect(std::slice::from_ref(&paris), &clone);
+        assert_eq!(found[0].kind, AnomalyKind::DuplicateSighting);
+        assert_eq!(found[0].severity, AnomalySeverity::Critical);
+
+        // Same place a day later is fine
+        let next_day = at("P1", 34 * HOUR, 48.86, 2.36, Some(90));
+        assert!(detect(std::slice::from_ref(&paris), &next_day).is_empty());
+
+    }
+
+    #[test]
+    fn flags_arrivals_against_earlier_estimates_when_steps_are_appended() {
+        let options = crate::AddStepOptions::default();
+        let mut shipped = test_step("P1", "carrier", 0);
+        shipped.action = "Shipped".to_string();
+        shipped.estimated_arrival = Some(48 * HOUR);
+        crate::append_step(shipped, HOUR, &options).unwrap();
+
+        // Revised to arrive a day earlier than promised
+        let mut revised = test_step("P1", "carrier", 0);
+        revised.action = "In transit".to_string();
+        revised.estimated_arrival = Some(24 * HOUR);
+        crate::append_step(revised, 2 * HOUR, &options).unwrap();
+
+        // Received two days after the revised estimate
+        let mut received = test_step("P1", "warehouse", 0);
+        received.action = "Received".to_string();
+        crate::append_step(received, 72 * HOUR, &options).unwrap();
+
+        let found: Vec<(AnomalyKind, AnomalySeverity)> = matching(|a| a.product_id == "P1").into_iter().map(|a| (a.kind, a.severity)).collect();
+        assert_eq!(
+            found,
+            vec![(AnomalyKind::TimestampRegression, AnomalySeverity::Medium), (AnomalyKind::LateArrival, AnomalySeverity::Medium)]
+        );
+    }
+}
@@ -0,0 +1,103 @@
+// 🧾 Append-only audit log for admin operations that change or erase tenant data.
+//
+// Entries are never edited or removed (clear_all_data leaves the log intact). They are appended
+// to a StableLog as they are recorded, so the log survives upgrades, and even a failed
+// pre_upgrade, without passing through the heap.
+use candid::{candid_method, CandidType, Decode, Encode};
+use ic_cdk::api::time;
+use ic_cdk_macros::query;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableLog, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+use crate::memory::{self, Memory};
+
+const MAX_PAGE_SIZE: u32 = 100;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct AuditEntry {
+    pub id: u64,
+    pub caller: String,
+    pub method: String,
+    pub arguments: Vec<(String, String)>,
+    pub affected_products: u64,
+    pub affected_steps: u64,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct AuditLogPage {
+    pub entries: Vec<AuditEntry>,
+    pub total: u64,
+}
+
+impl Storable for AuditEntry {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode audit entry"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode audit entry")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static AUDIT_LOG: RefCell<StableLog<AuditEntry, Memory, Memory>> = RefCell::new(
+        StableLog::init(memory::get(memory::AUDIT_LOG_INDEX), memory::get(memory::AUDIT_LOG_DATA))
+            .expect("Failed to initialize the audit log"),
+    );
+}
+
+fn append(log: &StableLog<AuditEntry, Memory, Memory>, entry: &AuditEntry) {
+    if log.append(entry).is_err() {
+        ic_cdk::trap("Out of stable memory for the audit log");
+    }
+}
+
+/// Append an entry for the current caller.
+pub fn record(method: &str, arguments: Vec<(&str, String)>, affected_products: u64, affected_steps: u64) {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let entry = AuditEntry {
+            id: log.len(),
+            caller: format!("{}", ic_cdk::caller()),
+            method: method.to_string(),
+            arguments: arguments.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+            affected_products,
+            affected_steps,
+            timestamp: time(),
+        };
+        append(&log, &entry);
+    });
+}
+
+// Moves entries from upgrade snapshots written before the log lived in stable memory.
+pub(crate) fn migrate(entries: Vec<AuditEntry>) {
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        if log.is_empty() {
+            entries.iter().for_each(|entry| append(&log, entry));
+        }
+    });
+}
+
+// Oldest first; `offset` counts from the first entry ever recorded.
+#[query]
+#[candid_method(query)]
+fn get_audit_log(offset: u64, limit: u32) -> ApiResult<AuditLogPage> {
+    if !is_admin(&format!("{}", ic_cdk::caller())) {
+        return Err(BlockTraceError::unauthorized("Only an admin can read the audit log"));
+    }
+    AUDIT_LOG.with(|log| {
+        let log = log.borrow();
+        let end = offset.saturating_add(limit.clamp(1, MAX_PAGE_SIZE) as u64).min(log.len());
+        let entries = (offset..end).filter_map(|id| log.get(id)).collect();
+        Ok(AuditLogPage { entries, total: log.len() })
+    })
+}
@@ -0,0 +1,307 @@
+// 🏅 Peer benchmarking of ESG scores.
+//
+// Places a product's score within the distribution of comparable products across the canister,
+// grouped by the category its owner assigned or by the route type derived from its transport
+// modes. Only aggregates are returned, never other tenants' product IDs, and groups smaller than
+// MIN_PEERS are refused so that a single competitor's score cannot be read off best-in-class.
+//
+// Scores cover the product owner's own steps. Each product's peer entry is computed when its ESG
+// series records a point or its category changes, and kept in per-group distributions, so a
+// benchmark query scores only the product being benchmarked.
+use candid::{candid_method, CandidType};
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+use crate::history::{self, is_involved, product_owner};
+use crate::{authenticated_caller, calculate_esg_score, ESGScore, Step};
+
+// Comparable products needed besides the one being benchmarked
+const MIN_PEERS: usize = 4;
+const MAX_CATEGORY_LENGTH: usize = 100;
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum PeerGroup {
+    Category,
+    RouteType,
+}
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum RouteType {
+    Road,
+    Rail,
+    Sea,
+    Air,
+    Multimodal,
+    Unspecified,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EsgBenchmark {
+    pub product_id: String,
+    pub peer_group: PeerGroup,
+    // Category name or route type the product was compared within
+    pub group: String,
+    pub peer_count: u32,
+    pub sustainability_score: u8,
+    // Share of peers scoring lower, ties counting half
+    pub percentile: f64,
+    pub median_score: f64,
+    pub best_in_class_score: u8,
+    pub carbon_footprint_kg: f64,
+    pub median_carbon_kg: f64,
+    pub best_in_class_carbon_kg: f64,
+    pub methodology_version: u32,
+}
+
+// A product's owner-scoped score and the groups it is compared within.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct PeerEntry {
+    pub category: Option<String>,
+    pub route_type: RouteType,
+    pub sustainability_score: u8,
+    pub carbon_footprint_kg: f64,
+    pub methodology_version: u32,
+}
+
+impl PeerEntry {
+    fn groups(&self) -> Vec<(PeerGroup, String)> {
+        let mut groups = vec![(PeerGroup::RouteType, format!("{:?}", self.route_type))];
+        groups.extend(self.category.clone().map(|c| (PeerGroup::Category, c)));
+        groups
+    }
+}
+
+type Distributions = HashMap<(PeerGroup, String), BTreeMap<String, PeerEntry>>;
+
+thread_local! {
+    static CATEGORIES: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+    static PEERS: RefCell<HashMap<String, PeerEntry>> = RefCell::new(HashMap::new());
+    // Derived from PEERS: entries of every group, keyed by product
+    static DISTRIBUTIONS: RefCell<Distributions> = RefCell::new(HashMap::new());
+}
+
+pub(crate) fn snapshot() -> HashMap<String, String> {
+    CATEGORIES.with(|c| c.borrow().clone())
+}
+
+pub(crate) fn peers_snapshot() -> HashMap<String, PeerEntry> {
+    PEERS.with(|p| p.borrow().clone())
+}
+
+pub(crate) fn restore(categories: HashMap<String, String>, peers: HashMap<String, PeerEntry>) {
+    CATEGORIES.with(|c| *c.borrow_mut() = categories);
+    DISTRIBUTIONS.with(|d| {
+        let mut distributions = d.borrow_mut();
+        distributions.clear();
+        for (product_id, entry) in &peers {
+            index(&mut distributions, product_id, None, Some(entry));
+        }
+    });
+    PEERS.with(|p| *p.borrow_mut() = peers);
+}
+
+pub(crate) fn clear() {
+    CATEGORIES.with(|c| c.borrow_mut().clear());
+    PEERS.with(|p| p.borrow_mut().clear());
+    DISTRIBUTIONS.with(|d| d.borrow_mut().clear());
+}
+
+// Moves a product from the groups of its previous entry to those of its new one.
+fn index(distributions: &mut Distributions, product_id: &str, previous: Option<&PeerEntry>, entry: Option<&PeerEntry>) {
+    for group in previous.map(PeerEntry::groups).unwrap_or_default() {
+        if let Some(members) = distributions.get_mut(&group) {
+            members.remove(product_id);
+            if members.is_empty() {
+                distributions.remove(&group);
+            }
+        }
+    }
+    if let Some(entry) = entry {
+        for group in entry.groups() {
+            distributions.entry(group).or_default().insert(product_id.to_string(), entry.clone());
+        }
+    }
+}
+
+// The owner-scoped score of a product and its steps, None for unknown products.
+fn owner_score(product_id: &str) -> Option<(ESGScore, Vec<Step>)> {
+    let owner = product_owner(product_id)?;
+    let score = calculate_esg_score(product_id.to_string(), owner.clone(), None, None)?;
+    let steps = history::history_as_of(product_id, None).into_iter().filter(|s| s.user_id == owner).collect();
+    Some((score, steps))
+}
+
+fn peer_entry(product_id: &str, score: &ESGScore, steps: &[Step]) -> PeerEntry {
+    PeerEntry {
+        category: CATEGORIES.with(|c| c.borrow().get(product_id).cloned()),
+        route_type: route_type(steps),
+        sustainability_score: score.sustainability_score,
+        carbon_footprint_kg: score.carbon_footprint_kg,
+        methodology_version: score.methodology_version,
+    }
+}
+
+/// Recomputes the product's peer entry; called whenever its ESG series records a point.
+pub(crate) fn refresh(product_id: &str) {
+    let entry = owner_score(product_id).map(|(score, steps)| peer_entry(product_id, &score, &steps));
+    let previous = PEERS.with(|p| {
+        let mut peers = p.borrow_mut();
+        match &entry {
+            Some(entry) => peers.insert(product_id.to_string(), entry.clone()),
+            None => peers.remove(product_id),
+        }
+    });
+    DISTRIBUTIONS.with(|d| index(&mut d.borrow_mut(), product_id, previous.as_ref(), entry.as_ref()));
+}
+
+fn mode_route(transport_mode: &str) -> Option<RouteType> {
+    let mode = transport_mode.to_lowercase();
+    if mode.contains("truck") || mode.contains("road") || mode.contains("van") {
+        Some(RouteType::Road)
+    } else if mode.contains("rail") || mode.contains("train") {
+        Some(RouteType::Rail)
+    } else if mode.contains("sea") || mode.contains("ship") || mode.contains("vessel") {
+        Some(RouteType::Sea)
+    } else if mode.contains("air") || mode.contains("plane") {
+        Some(RouteType::Air)
+    } else {
+        None
+    }
+}
+
+pub fn route_type(steps: &[Step]) -> RouteType {
+    let routes: Vec<RouteType> = steps.iter().filter_map(|s| s.transport_mode.as_deref().and_then(mode_route)).collect();
+    match routes.as_slice() {
+        [] => RouteType::Unspecified,
+        [first, rest @ ..] if rest.iter().all(|r| r == first) => *first,
+        _ => RouteType::Multimodal,
+    }
+}
+
+fn median(sorted: &[f64]) -> f64 {
+    let mid = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    }
+}
+
+/// Percentile of `score` among `peers`: peers below count fully, ties half.
+fn percentile(score: f64, peers: &[f64]) -> f64 {
+    let below = peers.iter().filter(|p| **p < score).count() as f64;
+    let ties = peers.iter().filter(|p| **p == score).count() as f64;
+    (below + ties / 2.0) / peers.len() as f64 * 100.0
+}
+
+// Categories are free text chosen by the product owner, compared case-insensitively.
+#[update]
+#[candid_method(update)]
+fn set_product_category(product_id: String, category: String, caller_principal: String) -> ApiResult<String> {
+    let caller = authenticated_caller(&caller_principal)?;
+    if product_owner(&product_id).as_deref() != Some(caller.as_str()) {
+        return Err(BlockTraceError::unauthorized("Only the product owner can set its category"));
+    }
+    let category = category.trim().to_lowercase();
+    if category.is_empty() || category.len() > MAX_CATEGORY_LENGTH {
+        return Err(BlockTraceError::invalid("category", format!("must be 1 to {} bytes", MAX_CATEGORY_LENGTH)));
+    }
+    CATEGORIES.with(|c| c.borrow_mut().insert(product_id.clone(), category.clone()));
+    refresh(&product_id);
+    Ok(category)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_esg_benchmark(product_id: String, peer_group: PeerGroup, caller_principal: String) -> ApiResult<EsgBenchmark> {
+    let caller = authenticated_caller(&caller_principal)?;
+    if !is_involved(&product_id, &caller) && !is_admin(&caller) {
+        return Err(BlockTraceError::unauthorized("Only parties involved in the product can benchmark it"));
+    }
+    let (score, steps) = owner_score(&product_id).ok_or_else(|| BlockTraceError::not_found("Product", product_id.clone()))?;
+    let entry = peer_entry(&product_id, &score, &steps);
+    let (_, group) = entry
+        .groups()
+        .into_iter()
+        .find(|(g, _)| *g == peer_group)
+        .ok_or_else(|| BlockTraceError::invalid("peer_group", "the product has no category; set one with set_product_category"))?;
+
+    // Peers scored under another methodology version are not comparable
+    let peers: Vec<(f64, f64)> = DISTRIBUTIONS.with(|d| {
+        d.borrow()
+            .get(&(peer_group, group.clone()))
+            .map(|members| {
+                members
+                    .iter()
+                    .filter(|(id, peer)| **id != product_id && peer.methodology_version == score.methodology_version)
+                    .map(|(_, peer)| (peer.sustainability_score as f64, peer.carbon_footprint_kg))
+                    .collect()
+            })
+            .unwrap_or_default()
+    });
+    if peers.len() < MIN_PEERS {
+        return Err(BlockTraceError::invalid(
+            "peer_group",
+            format!("only {} comparable products in '{}'; at least {} are needed", peers.len(), group, MIN_PEERS),
+        ));
+    }
+    let mut scores: Vec<f64> = peers.iter().map(|(s, _)| *s).collect();
+    let mut carbon: Vec<f64> = peers.iter().map(|(_, c)| *c).collect();
+    scores.sort_by(f64::total_cmp);
+    carbon.sort_by(f64::total_cmp);
+    Ok(EsgBenchmark {
+        product_id,
+        peer_group,
+        group,
+        peer_count: peers.len() as u32,
+        sustainability_score: score.sustainability_score,
+        percentile: percentile(score.sustainability_score as f64, &scores),
+        median_score: median(&scores),
+        best_in_class_score: scores.last().copied().unwrap_or_default() as u8,
+        carbon_footprint_kg: score.carbon_footprint_kg,
+        median_carbon_kg: median(&carbon),
+        best_in_class_carbon_kg: carbon.first().copied().unwrap_or_default(),
+        methodology_version: score.methodology_version,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_step;
+
+    #[test]
+    fn ranks_against_peers_and_derives_route_type() {
+        let peers = [55.0, 62.0, 78.0, 81.0, 90.0];
+        assert_eq!(percentile(78.0, &peers), 50.0);
+        assert_eq!(median(&peers), 78.0);
+        assert_eq!(median(&peers[..4]), 70.0);
+
+        let mut truck = test_step("P1", "maker", 1);
+        truck.transport_mode = Some("Truck".to_string());
+        let mut ship = test_step("P1", "maker", 2);
+        ship.transport_mode = Some("ship".to_string());
+        assert_eq!(route_type(&[truck.clone(), truck.clone()]), RouteType::Road);
+        assert_eq!(route_type(&[truck, ship]), RouteType::Multimodal);
+        assert_eq!(route_type(&[test_step("P1", "maker", 3)]), RouteType::Unspecified);
+
+        let entry = |category: Option<&str>, route_type| PeerEntry {
+            category: category.map(str::to_string),
+            route_type,
+            sustainability_score: 70,
+            carbon_footprint_kg: 10.0,
+            methodology_version: 1,
+        };
+        let mut distributions = Distributions::new();
+        let before = entry(Some("coffee"), RouteType::Road);
+        index(&mut distributions, "P1", None, Some(&before));
+        assert_eq!(distributions.len(), 2);
+        let after = entry(None, RouteType::Sea);
+        index(&mut distributions, "P1", Some(&before), Some(&after));
+        assert_eq!(distributions.keys().collect::<Vec<_>>(), vec![&(PeerGroup::RouteType, "Sea".to_string())]);
+    }
+}
@@ -0,0 +1,437 @@
+// 📜 Certificate registry: issuer, standard, subject, validity window and revocation.
+//
+// `Step.certification_hash` and `SupplierVerification.certifications` resolve against this
+// registry so that steps recorded under lapsed or revoked certificates can be flagged. Only admins
+// and the issuers they approve can register certificates, so hashes cannot be squatted.
+use candid::{candid_method, CandidType, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::audit;
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+use crate::logs;
+use crate::SUPPLIER_VERIFICATIONS;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum CertificateStandard {
+    Iso14001,
+    Iso9001,
+    FairTrade,
+    Organic,
+    RainforestAlliance,
+    Other(String),
+}
+
+impl CertificateStandard {
+    /// Normalized code, matching the strings external supplier APIs return (e.g. "ISO14001").
+    pub fn code(&self) -> String {
+        match self {
+            CertificateStandard::Iso14001 => "ISO14001".to_string(),
+            CertificateStandard::Iso9001 => "ISO9001".to_string(),
+            CertificateStandard::FairTrade => "FAIR_TRADE".to_string(),
+            CertificateStandard::Organic => "ORGANIC".to_string(),
+            CertificateStandard::RainforestAlliance => "RAINFOREST_ALLIANCE".to_string(),
+            CertificateStandard::Other(name) => normalize_code(name),
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum CertificateSubject {
+    Supplier(String),
+    Product(String),
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct CertificateRevocation {
+    pub revoked_by: String,
+    pub revoked_at: u64,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct Certificate {
+    pub document_hash: String,
+    pub issuer: String,
+    pub standard: CertificateStandard,
+    pub subject: CertificateSubject,
+    pub valid_from: u64,
+    pub valid_until: u64,
+    pub registered_by: String,
+    pub registered_at: u64,
+    pub revocation: Option<CertificateRevocation>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct CertificateInput {
+    pub document_hash: String,
+    pub issuer: String,
+    pub standard: CertificateStandard,
+    pub subject: CertificateSubject,
+    pub valid_from: u64,
+    pub valid_until: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum CertificateStatus {
+    Valid,
+    NotYetValid,
+    Expired,
+    Revoked,
+    Unregistered,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct StepCertificateFlag {
+    pub product_id: String,
+    pub step_index: u32,
+    pub step_timestamp: u64,
+    pub actor_name: String,
+    pub action: String,
+    pub certification_hash: String,
+    pub status: CertificateStatus,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct SupplierCertificateCheck {
+    pub certification: String,
+    pub certificate: Option<Certificate>,
+    pub status: CertificateStatus,
+}
+
+thread_local! {
+    static CERTIFICATES: RefCell<HashMap<String, Certificate>> = RefCell::new(HashMap::new());
+    // Principals allowed to register certificates besides admins
+    static APPROVED_ISSUERS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+}
+
+fn normalize_code(value: &str) -> String {
+    value
+        .trim()
+        .to_uppercase()
+        .chars()
+        .filter_map(|c| match c {
+            ' ' | '-' | '_' => Some('_'),
+            c if c.is_ascii_alphanumeric() => Some(c),
+            _ => None,
+        })
+        .collect::<String>()
+        .replace("ISO_", "ISO")
+}
+
+/// Hashes are compared case-insensitively and without an optional algorithm prefix ("sha256:").
+pub fn normalize_hash(hash: &str) -> String {
+    let trimmed = hash.trim().to_lowercase();
+    match trimmed.split_once(':') {
+        Some((_, digest)) => digest.to_string(),
+        None => trimmed.trim_start_matches("0x").to_string(),
+    }
+}
+
+/// Status of a certificate at the given moment (nanoseconds since epoch).
+pub fn status_at(cert: &Certificate, at: u64) -> CertificateStatus {
+    if let Some(revocation) = &cert.revocation {
+        if revocation.revoked_at <= at {
+            return CertificateStatus::Revoked;
+        }
+    }
+    if at < cert.valid_from {
+        CertificateStatus::NotYetValid
+    } else if at > cert.valid_until {
+        CertificateStatus::Expired
+    } else {
+        CertificateStatus::Valid
+    }
+}
+
+pub fn lookup(document_hash: &str) -> Option<Certificate> {
+    let key = normalize_hash(document_hash);
+    CERTIFICATES.with(|store| store.borrow().get(&key).cloned())
+}
+
+pub fn certificate_status(document_hash: &str, at: u64) -> CertificateStatus {
+    lookup(document_hash)
+        .map(|cert| status_at(&cert, at))
+        .unwrap_or(CertificateStatus::Unregistered)
+}
+
+pub(crate) fn snapshot() -> HashMap<String, Certificate> {
+    CERTIFICATES.with(|store| store.borrow().clone())
+}
+
+pub(crate) fn restore(certificates: HashMap<String, Certificate>) {
+    CERTIFICATES.with(|store| *store.borrow_mut() = certificates);
+}
+
+pub(crate) fn clear() {
+    CERTIFICATES.with(|store| store.borrow_mut().clear());
+}
+
+pub(crate) fn issuers_snapshot() -> Vec<String> {
+    APPROVED_ISSUERS.with(|issuers| issuers.borrow().clone())
+}
+
+pub(crate) fn restore_issuers(issuers: Vec<String>) {
+    APPROVED_ISSUERS.with(|store| *store.borrow_mut() = issuers);
+}
+
+fn is_approved_issuer(principal: &str) -> bool {
+    APPROVED_ISSUERS.with(|issuers| issuers.borrow().iter().any(|i| i == principal))
+}
+
+fn require_admin() -> ApiResult<()> {
+    if !is_admin(&format!("{}", ic_cdk::caller())) {
+        return Err(BlockTraceError::unauthorized("Only an admin can manage certificate issuers"));
+    }
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+fn approve_certificate_issuer(principal: Principal) -> ApiResult<Vec<String>> {
+    require_admin()?;
+    let principal = principal.to_text();
+    APPROVED_ISSUERS.with(|issuers| {
+        let mut issuers = issuers.borrow_mut();
+        if !issuers.contains(&principal) {
+            issuers.push(principal.clone());
+        }
+    });
+    audit::record("approve_certificate_issuer", vec![("principal", principal)], 0, 0);
+    Ok(issuers_snapshot())
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_certificate_issuer(principal: Principal) -> ApiResult<Vec<String>> {
+    require_admin()?;
+    let principal = principal.to_text();
+    APPROVED_ISSUERS.with(|issuers| issuers.borrow_mut().retain(|i| *i != principal));
+    audit::record("remove_certificate_issuer", vec![("principal", principal)], 0, 0);
+    Ok(issuers_snapshot())
+}
+
+#[query]
+#[candid_method(query)]
+fn list_certificate_issuers() -> Vec<String> {
+    issuers_snapshot()
+}
+
+#[update]
+#[candid_method(update)]
+fn register_certificate(input: CertificateInput) -> ApiResult<Certificate> {
+    let caller = format!("{}", ic_cdk::caller());
+    if !is_admin(&caller) && !is_approved_issuer(&caller) {
+        return Err(BlockTraceError::unauthorized("Only admins and approved issuers can register certificates"));
+    }
+    let key = normalize_hash(&input.document_hash);
+    if key.is_empty() {
+        return Err(BlockTraceError::invalid("document_hash", "cannot be empty"));
+    }
+    if input.issuer.trim().is_empty() {
+        return Err(BlockTraceError::invalid("issuer", "cannot be empty"));
+    }
+    if input.valid_until <= input.valid_from {
+        return Err(BlockTraceError::invalid("valid_until", "must be after valid_from"));
+    }
+    let subject_id = match &input.subject {
+        CertificateSubject::Supplier(id) | CertificateSubject::Product(id) => id,
+    };
+    if subject_id.trim().is_empty() {
+        return Err(BlockTraceError::invalid("subject", "cannot be empty"));
+    }
+
+    let certificate = Certificate {
+        document_hash: key.clone(),
+        issuer: input.issuer.trim().to_string(),
+        standard: input.standard,
+        subject: input.subject,
+        valid_from: input.valid_from,
+        valid_until: input.valid_until,
+        registered_by: caller,
+        registered_at: time(),
+        revocation: None,
+    };
+
+    CERTIFICATES.with(|store| {
+        let mut map = store.borrow_mut();
+        if map.contains_key(&key) {
+            return Err(BlockTraceError::conflict(format!("Certificate {} is already registered", key)));
+        }
+        map.insert(key.clone(), certificate.clone());
+        Ok(())
+    })?;
+
+    logs::info(
+        "certificates",
+        "Certificate registered",
+        vec![("document_hash", key), ("standard", certificate.standard.code()), ("registered_by", certificate.registered_by.clone())],
+    );
+    Ok(certificate)
+}
+
+#[update]
+#[candid_method(update)]
+fn revoke_certificate(document_hash: String, reason: String) -> ApiResult<Certificate> {
+    let caller = format!("{}", ic_cdk::caller());
+    let key = normalize_hash(&document_hash);
+
+    let revoked = CERTIFICATES.with(|store| {
+        let mut map = store.borrow_mut();
+        let cert = map.get_mut(&key).ok_or_else(|| BlockTraceError::not_found("Certificate", key.clone()))?;
+        if cert.registered_by != caller && !is_admin(&caller) {
+            return Err(BlockTraceError::unauthorized("Only the registrant or an admin can revoke a certificate"));
+        }
+        if cert.revocation.is_some() {
+            return Err(BlockTraceError::conflict(format!("Certificate {} is already revoked", key)));
+        }
+        cert.revocation = Some(CertificateRevocation {
+            revoked_by: caller.clone(),
+            revoked_at: time(),
+            reason,
+        });
+        Ok(cert.clone())
+    })?;
+
+    logs::warn("certificates", "Certificate revoked", vec![("document_hash", key), ("revoked_by", caller)]);
+    Ok(revoked)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_certificate(document_hash: String) -> Option<Certificate> {
+    lookup(&document_hash)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_certificates_for_subject(subject: CertificateSubject) -> Vec<Certificate> {
+    CERTIFICATES.with(|store| {
+        let mut certs: Vec<Certificate> = store
+            .borrow()
+            .values()
+            .filter(|cert| cert.subject == subject)
+            .cloned()
+            .collect();
+        certs.sort_by_key(|cert| cert.valid_from);
+        certs
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn check_certificate(document_hash: String, at: Option<u64>) -> CertificateStatus {
+    certificate_status(&document_hash, at.unwrap_or_else(time))
+}
+
+// Steps whose certification_hash was not valid at the moment the step was recorded. The step
+// index is the step's position in the full product history, whoever is asking.
+#[query]
+#[candid_method(query)]
+fn get_step_certificate_flags(product_id: String, caller_principal: String) -> Vec<StepCertificateFlag> {
+    crate::history::history_as_of(&product_id, None)
+        .iter()
+        .enumerate()
+        .filter(|(_, step)| step.user_id == caller_principal)
+        .filter_map(|(index, step)| {
+            let hash = step.certification_hash.as_ref()?;
+            if hash.trim().is_empty() {
+                return None;
+            }
+            let status = certificate_status(hash, step.timestamp);
+            if status == CertificateStatus::Valid {
+                return None;
+            }
+            Some(StepCertificateFlag {
+                product_id: product_id.clone(),
+                step_index: index as u32,
+                step_timestamp: step.timestamp,
+                actor_name: step.actor_name.clone(),
+                action: step.action.clone(),
+                certification_hash: hash.clone(),
+                status,
+            })
+        })
+        .collect()
+}
+
+// Resolve each entry of SupplierVerification.certifications, either by document hash or by
+// the supplier's registered certificate for that standard code.
+#[query]
+#[candid_method(query)]
+fn get_supplier_certificate_status(supplier_id: String) -> Vec<SupplierCertificateCheck> {
+    let certifications = SUPPLIER_VERIFICATIONS.with(|store| {
+        store.borrow().get(&supplier_id).map(|v| v.certifications.clone()).unwrap_or_default()
+    });
+    let now = time();
+    let subject = CertificateSubject::Supplier(supplier_id);
+
+    certifications
+        .into_iter()
+        .map(|certification| {
+            let certificate = lookup(&certification).or_else(|| {
+                let code = normalize_code(&certification);
+                CERTIFICATES.with(|store| {
+                    store
+                        .borrow()
+                        .values()
+                        .filter(|cert| cert.subject == subject && cert.standard.code() == code)
+                        .max_by_key(|cert| cert.valid_until)
+                        .cloned()
+                })
+            });
+            let status = certificate
+                .as_ref()
+                .map(|cert| status_at(cert, now))
+                .unwrap_or(CertificateStatus::Unregistered);
+            SupplierCertificateCheck { certification, certificate, status }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cert(valid_from: u64, valid_until: u64, revoked_at: Option<u64>) -> Certificate {
+        Certificate {
+            document_hash: "abc123".to_string(),
+            issuer: "Control Union".to_string(),
+            standard: CertificateStandard::Organic,
+            subject: CertificateSubject::Supplier("supplier_1".to_string()),
+            valid_from,
+            valid_until,
+            registered_by: "registrar".to_string(),
+            registered_at: valid_from,
+            revocation: revoked_at.map(|revoked_at| CertificateRevocation {
+                revoked_by: "registrar".to_string(),
+                revoked_at,
+                reason: "audit failure".to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn status_follows_validity_window_and_revocation() {
+        let c = cert(100, 200, None);
+        assert_eq!(status_at(&c, 50), CertificateStatus::NotYetValid);
+        assert_eq!(status_at(&c, 150), CertificateStatus::Valid);
+        assert_eq!(status_at(&c, 250), CertificateStatus::Expired);
+
+        let revoked = cert(100, 200, Some(150));
+        assert_eq!(status_at(&revoked, 120), CertificateStatus::Valid);
+        assert_eq!(status_at(&revoked, 150), CertificateStatus::Revoked);
+    }
+
+    #[test]
+    fn codes_and_hashes_are_normalized() {
+        assert_eq!(normalize_code("ISO 14001"), CertificateStandard::Iso14001.code());
+        assert_eq!(normalize_code("fair-trade"), CertificateStandard::FairTrade.code());
+        assert_eq!(normalize_hash("sha256:ABC123"), "abc123");
+        assert_eq!(normalize_hash(" 0xAbC123 "), "abc123");
+    }
+}
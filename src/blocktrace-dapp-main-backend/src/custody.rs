@@ -0,0 +1,443 @@
+// 🤝 Two-party custody handover.
+//
+// The current custodian proposes to hand a product (or every product of a batch it holds) to a
+// named principal. Nothing is written to the ledger until the receiver accepts; the accepted
+// handover is then recorded as a "Received" step owned by the receiver, which makes them the
+// custodian. Only such steps move custody, and add_step refuses to record them, so nobody becomes
+// custodian by appending a step of their own. Rejections and cancellations leave the ledger
+// untouched.
+use candid::{candid_method, CandidType};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::history;
+use crate::logs;
+use crate::webhooks::{self, WebhookAlert};
+use crate::{authenticated_caller, commit_step, prepare_step, AddStepOptions, Step, PRODUCT_HISTORY};
+
+pub const HANDOVER_STATUS: &str = "handover-confirmed";
+
+/// Whether `step` records an accepted custody handover.
+pub fn is_handover_step(step: &Step) -> bool {
+    step.status.as_deref().is_some_and(|s| s.trim().eq_ignore_ascii_case(HANDOVER_STATUS))
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum HandoverSubject {
+    Product { product_id: String },
+    // Every product of the batch that the proposer currently holds
+    Batch { batch_number: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum HandoverStatus {
+    Pending,
+    Accepted,
+    Rejected,
+    Cancelled,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum GoodsCondition {
+    Intact,
+    Damaged,
+    Incomplete,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct HandoverAcceptance {
+    pub actor_name: String,
+    pub location: String,
+    pub received_quantity: Option<f64>,
+    pub condition: Option<GoodsCondition>,
+    pub notes: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct HandoverProposal {
+    pub id: u64,
+    pub subject: HandoverSubject,
+    pub product_ids: Vec<String>,
+    pub from_principal: String,
+    pub to_principal: String,
+    pub quantity: Option<f64>,
+    pub notes: Option<String>,
+    pub status: HandoverStatus,
+    pub proposed_at: u64,
+    pub responded_at: Option<u64>,
+    pub acceptance: Option<HandoverAcceptance>,
+    pub rejection_reason: Option<String>,
+    // (product_id, sequence) of the steps recorded on acceptance
+    pub recorded_steps: Vec<(String, u64)>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+pub struct CustodyState {
+    pub proposals: BTreeMap<u64, HandoverProposal>,
+    pub next_id: u64,
+}
+
+thread_local! {
+    static CUSTODY: RefCell<CustodyState> = RefCell::new(CustodyState::default());
+}
+
+pub(crate) fn snapshot() -> CustodyState {
+    CUSTODY.with(|c| c.borrow().clone())
+}
+
+pub(crate) fn restore(state: CustodyState) {
+    CUSTODY.with(|c| *c.borrow_mut() = state);
+}
+
+pub(crate) fn clear() {
+    CUSTODY.with(|c| c.borrow_mut().proposals.clear());
+}
+
+fn custodian_of(product_id: &str) -> Option<String> {
+    history::product_state(product_id, None).map(|state| state.custodian)
+}
+
+fn products_in_custody(subject: &HandoverSubject, principal: &str) -> Vec<String> {
+    let mut product_ids: Vec<String> = match subject {
+        HandoverSubject::Product { product_id } => vec![product_id.clone()],
+        HandoverSubject::Batch { batch_number } => PRODUCT_HISTORY.with(|store| {
+            store
+                .borrow()
+                .iter()
+                .filter(|(_, steps)| steps.iter().any(|s| s.batch_number.as_deref() == Some(batch_number.as_str())))
+                .map(|(product_id, _)| product_id.clone())
+                .collect()
+        }),
+    };
+    product_ids.retain(|product_id| custodian_of(product_id).as_deref() == Some(principal));
+    product_ids.sort();
+    product_ids
+}
+
+fn quantity_note(proposed: Option<f64>, received: Option<f64>) -> Option<String> {
+    match (proposed, received) {
+        (Some(sent), Some(got)) if (sent - got).abs() > f64::EPSILON => {
+            Some(format!("quantity discrepancy: {} handed over, {} received", sent, got))
+        }
+        (_, Some(got)) => Some(format!("quantity received: {}", got)),
+        _ => None,
+    }
+}
+
+fn handover_step(proposal: &HandoverProposal, acceptance: &HandoverAcceptance, product_id: &str, batch_number: Option<String>) -> Step {
+    let mut notes = vec![format!("Custody handover #{} from {}", proposal.id, proposal.from_principal)];
+    notes.extend(quantity_note(proposal.quantity, acceptance.received_quantity));
+    if let Some(condition) = &acceptance.condition {
+        notes.push(format!("condition: {:?}", condition));
+    }
+    notes.extend(acceptance.notes.clone().filter(|n| !n.trim().is_empty()));
+    Step {
+        user_id: proposal.to_principal.clone(),
+        product_id: product_id.to_string(),
+        actor_name: acceptance.actor_name.clone(),
+        role: "Receiver".to_string(),
+        action: "Received via custody handover".to_string(),
+        location: acceptance.location.clone(),
+        notes: Some(notes.join("; ")),
+        timestamp: 0,
+        status: Some(HANDOVER_STATUS.to_string()),
+        transport_mode: None,
+        vehicle_class: None,
+        fuel_type: None,
+        load_tonnes: None,
+        transport_owned: None,
+        temperature_celsius: None,
+        humidity_percent: None,
+        gps_latitude: None,
+        gps_longitude: None,
+        batch_number,
+        certification_hash: None,
+        estimated_arrival: None,
+        actual_arrival: None,
+        quality_score: None,
+        carbon_footprint_kg: None,
+        distance_km: None,
+        cost_usd: None,
+        blockchain_hash: None,
+        serials: None,
+        sequence: None,
+        encryption: None,
+    }
+}
+
+fn pending_proposal(id: u64) -> ApiResult<HandoverProposal> {
+    let proposal = CUSTODY
+        .with(|c| c.borrow().proposals.get(&id).cloned())
+        .ok_or_else(|| BlockTraceError::not_found("Handover", id.to_string()))?;
+    if proposal.status != HandoverStatus::Pending {
+        return Err(BlockTraceError::conflict(format!("Handover {} is already {:?}", id, proposal.status)));
+    }
+    Ok(proposal)
+}
+
+fn store(proposal: &HandoverProposal) {
+    CUSTODY.with(|c| c.borrow_mut().proposals.insert(proposal.id, proposal.clone()));
+}
+
+#[update]
+#[candid_method(update)]
+fn propose_handover(
+    subject: HandoverSubject,
+    to_principal: String,
+    quantity: Option<f64>,
+    notes: Option<String>,
+    caller_principal: String,
+) -> ApiResult<HandoverProposal> {
+    let from = authenticated_caller(&caller_principal)?;
+    let to_principal = to_principal.trim().to_string();
+    if to_principal.is_empty() || to_principal == from {
+        return Err(BlockTraceError::invalid("to_principal", "must name another principal"));
+    }
+    if quantity.is_some_and(|q| !q.is_finite() || q <= 0.0) {
+        return Err(BlockTraceError::invalid("quantity", "must be a positive number"));
+    }
+    let product_ids = products_in_custody(&subject, &from);
+    if product_ids.is_empty() {
+        return Err(BlockTraceError::unauthorized("Only the current custodian can hand over a product"));
+    }
+    let busy = CUSTODY.with(|c| {
+        c.borrow()
+            .proposals
+            .values()
+            .filter(|p| p.status == HandoverStatus::Pending)
+            .flat_map(|p| p.product_ids.clone())
+            .find(|id| product_ids.contains(id))
+    });
+    if let Some(product_id) = busy {
+        return Err(BlockTraceError::conflict(format!("Product {} already has a pending handover", product_id)));
+    }
+
+    let id = CUSTODY.with(|c| {
+        let mut c = c.borrow_mut();
+        c.next_id += 1;
+        c.next_id
+    });
+    let proposal = HandoverProposal {
+        id,
+        subject,
+        product_ids,
+        from_principal: from,
+        to_principal,
+        quantity,
+        notes,
+        status: HandoverStatus::Pending,
+        proposed_at: time(),
+        responded_at: None,
+        acceptance: None,
+        rejection_reason: None,
+        recorded_steps: vec![],
+    };
+    store(&proposal);
+    logs::info(
+        "custody",
+        "Handover proposed",
+        vec![
+            ("handover_id", id.to_string()),
+            ("from", proposal.from_principal.clone()),
+            ("to", proposal.to_principal.clone()),
+            ("products", proposal.product_ids.len().to_string()),
+        ],
+    );
+    Ok(proposal)
+}
+
+// Every step is prepared before any is committed, and committing without head expectations
+// cannot fail, so a batch handover is recorded all or nothing.
+fn record_handover_steps(
+    proposal: &mut HandoverProposal,
+    acceptance: &HandoverAcceptance,
+    batch_number: Option<String>,
+    now: u64,
+) -> ApiResult<()> {
+    let steps = proposal
+        .product_ids
+        .iter()
+        .map(|product_id| prepare_step(handover_step(proposal, acceptance, product_id, batch_number.clone()), now))
+        .collect::<ApiResult<Vec<Step>>>()?;
+    for step in steps {
+        let recorded = commit_step(step, &AddStepOptions::default())?;
+        proposal.recorded_steps.push((recorded.product_id, recorded.sequence.unwrap_or_default()));
+    }
+    Ok(())
+}
+
+// The receiver confirms the handover; custody must not have moved since it was proposed.
+#[update]
+#[candid_method(update)]
+fn accept_handover(id: u64, acceptance: HandoverAcceptance, caller_principal: String) -> ApiResult<HandoverProposal> {
+    let caller = authenticated_caller(&caller_principal)?;
+    let mut proposal = pending_proposal(id)?;
+    if proposal.to_principal != caller {
+        return Err(BlockTraceError::unauthorized("Only the named receiver can accept a handover"));
+    }
+    if acceptance.received_quantity.is_some_and(|q| !q.is_finite() || q < 0.0) {
+        return Err(BlockTraceError::invalid("received_quantity", "must be a non-negative number"));
+    }
+    if let Some(product_id) = proposal
+        .product_ids
+        .iter()
+        .find(|p| custodian_of(p).as_deref() != Some(proposal.from_principal.as_str()))
+    {
+        return Err(BlockTraceError::conflict(format!(
+            "Custody of {} changed after the handover was proposed",
+            product_id
+        )));
+    }
+
+    let now = time();
+    let batch_number = match &proposal.subject {
+        HandoverSubject::Batch { batch_number } => Some(batch_number.clone()),
+        HandoverSubject::Product { .. } => None,
+    };
+    record_handover_steps(&mut proposal, &acceptance, batch_number.clone(), now)?;
+
+    let discrepancy = quantity_note(proposal.quantity, acceptance.received_quantity).filter(|n| n.starts_with("quantity discrepancy"));
+    let damaged = acceptance.condition.as_ref().is_some_and(|c| *c != GoodsCondition::Intact);
+    if discrepancy.is_some() || damaged {
+        for product_id in &proposal.product_ids {
+            webhooks::notify_alert(WebhookAlert {
+                alert_type: "handover_exception".to_string(),
+                product_id: product_id.clone(),
+                batch_number: batch_number.clone(),
+                message: format!(
+                    "Handover #{} accepted with exceptions: {}",
+                    id,
+                    discrepancy.clone().unwrap_or_else(|| format!("condition {:?}", acceptance.condition))
+                ),
+                timestamp: now,
+            });
+        }
+    }
+
+    proposal.status = HandoverStatus::Accepted;
+    proposal.responded_at = Some(now);
+    proposal.acceptance = Some(acceptance);
+    store(&proposal);
+    logs::info(
+        "custody",
+        "Handover accepted",
+        vec![("handover_id", id.to_string()), ("receiver", caller), ("steps", proposal.recorded_steps.len().to_string())],
+    );
+    Ok(proposal)
+}
+
+#[update]
+#[candid_method(update)]
+fn reject_handover(id: u64, reason: String, caller_principal: String) -> ApiResult<HandoverProposal> {
+    let caller = authenticated_caller(&caller_principal)?;
+    let mut proposal = pending_proposal(id)?;
+    if proposal.to_principal != caller {
+        return Err(BlockTraceError::unauthorized("Only the named receiver can reject a handover"));
+    }
+    if reason.trim().is_empty() {
+        return Err(BlockTraceError::invalid("reason", "a rejection reason is required"));
+    }
+    proposal.status = HandoverStatus::Rejected;
+    proposal.responded_at = Some(time());
+    proposal.rejection_reason = Some(reason);
+    store(&proposal);
+    logs::info("custody", "Handover rejected", vec![("handover_id", id.to_string()), ("receiver", caller)]);
+    Ok(proposal)
+}
+
+#[update]
+#[candid_method(update)]
+fn cancel_handover(id: u64, caller_principal: String) -> ApiResult<HandoverProposal> {
+    let caller = authenticated_caller(&caller_principal)?;
+    let mut proposal = pending_proposal(id)?;
+    if proposal.from_principal != caller {
+        return Err(BlockTraceError::unauthorized("Only the proposer can cancel a handover"));
+    }
+    proposal.status = HandoverStatus::Cancelled;
+    proposal.responded_at = Some(time());
+    store(&proposal);
+    Ok(proposal)
+}
+
+// Handovers sent or received by the caller, newest first.
+#[query]
+#[candid_method(query)]
+fn list_handovers(caller_principal: String, pending_only: bool) -> Vec<HandoverProposal> {
+    let Ok(caller) = authenticated_caller(&caller_principal) else {
+        return vec![];
+    };
+    CUSTODY.with(|c| {
+        c.borrow()
+            .proposals
+            .values()
+            .rev()
+            .filter(|p| p.from_principal == caller || p.to_principal == caller)
+            .filter(|p| !pending_only || p.status == HandoverStatus::Pending)
+            .cloned()
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proposal(product_ids: &[&str]) -> HandoverProposal {
+        HandoverProposal {
+            id: 7,
+            subject: HandoverSubject::Product { product_id: product_ids[0].to_string() },
+            product_ids: product_ids.iter().map(|p| p.to_string()).collect(),
+            from_principal: "carrier".to_string(),
+            to_principal: "warehouse".to_string(),
+            quantity: Some(10.0),
+            notes: None,
+            status: HandoverStatus::Pending,
+            proposed_at: 0,
+            responded_at: None,
+            acceptance: None,
+            rejection_reason: None,
+            recorded_steps: vec![],
+        }
+    }
+
+    fn acceptance() -> HandoverAcceptance {
+        HandoverAcceptance {
+            actor_name: "Dock 4".to_string(),
+            location: "Rotterdam".to_string(),
+            received_quantity: Some(9.0),
+            condition: Some(GoodsCondition::Damaged),
+            notes: None,
+        }
+    }
+
+    #[test]
+    fn accepted_handover_becomes_a_received_step_owned_by_the_receiver() {
+        let proposal = proposal(&["P1"]);
+        let acceptance = acceptance();
+        let step = handover_step(&proposal, &acceptance, "P1", None);
+        assert_eq!(step.user_id, "warehouse");
+        assert!(is_handover_step(&step));
+        assert_eq!(history::lifecycle_stage(&step), history::LifecycleStage::Received);
+        assert_eq!(
+            step.notes.as_deref(),
+            Some("Custody handover #7 from carrier; quantity discrepancy: 10 handed over, 9 received; condition: Damaged")
+        );
+    }
+
+    #[test]
+    fn batch_handover_records_nothing_when_one_step_is_rejected() {
+        let too_long = "P".repeat(200);
+        let mut failing = proposal(&["P1", &too_long, "P3"]);
+        assert!(record_handover_steps(&mut failing, &acceptance(), Some("B-1".to_string()), 1).is_err());
+        assert!(failing.recorded_steps.is_empty());
+        assert_eq!(PRODUCT_HISTORY.with(|store| store.borrow().len()), 0);
+
+        let mut accepted = proposal(&["P1", "P3"]);
+        record_handover_steps(&mut accepted, &acceptance(), Some("B-1".to_string()), 1).unwrap();
+        assert_eq!(accepted.recorded_steps, vec![("P1".to_string(), 0), ("P3".to_string(), 0)]);
+    }
+}
@@ -0,0 +1,378 @@
+// ⚖️ Disputes raised against individual steps.
+//
+// Any party involved in a product can dispute one of its steps (damage, quantity, temperature,
+// ...), attaching evidence document hashes. Both sides comment on the same record and an
+// arbiter designated by an admin resolves it. Open disputes show up in the product state.
+use candid::{candid_method, CandidType};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::certificates::normalize_hash;
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+use crate::history::is_involved;
+use crate::logs;
+use crate::webhooks::{self, WebhookAlert};
+use crate::{authenticated_caller, PRODUCT_HISTORY};
+
+const MAX_MESSAGE_LENGTH: usize = 4_096;
+const MAX_EVIDENCE_PER_ENTRY: usize = 20;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum DisputeCategory {
+    Damage,
+    Quantity,
+    Temperature,
+    Quality,
+    Documentation,
+    Other,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum DisputeStatus {
+    Open,
+    Resolved,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum DisputeOutcome {
+    Upheld,
+    Rejected,
+    Settled,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct DisputeComment {
+    pub author: String,
+    pub message: String,
+    pub evidence_hashes: Vec<String>,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct DisputeResolution {
+    pub arbiter: String,
+    pub outcome: DisputeOutcome,
+    pub summary: String,
+    pub resolved_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct Dispute {
+    pub id: u64,
+    pub product_id: String,
+    pub sequence: u64,
+    pub category: DisputeCategory,
+    pub description: String,
+    pub opened_by: String,
+    pub opened_at: u64,
+    pub evidence_hashes: Vec<String>,
+    pub comments: Vec<DisputeComment>,
+    pub arbiter: Option<String>,
+    pub status: DisputeStatus,
+    pub resolution: Option<DisputeResolution>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+pub struct DisputeState {
+    pub disputes: BTreeMap<u64, Dispute>,
+    pub next_id: u64,
+}
+
+thread_local! {
+    static DISPUTES: RefCell<DisputeState> = RefCell::new(DisputeState::default());
+}
+
+pub(crate) fn snapshot() -> DisputeState {
+    DISPUTES.with(|d| d.borrow().clone())
+}
+
+pub(crate) fn restore(state: DisputeState) {
+    DISPUTES.with(|d| *d.borrow_mut() = state);
+}
+
+pub(crate) fn clear() {
+    DISPUTES.with(|d| d.borrow_mut().disputes.clear());
+}
+
+fn was_open_at(dispute: &Dispute, at: u64) -> bool {
+    dispute.opened_at <= at && dispute.resolution.as_ref().is_none_or(|r| r.resolved_at > at)
+}
+
+/// Disputes on the product that were open at `as_of` (or are open now).
+pub fn open_dispute_count(product_id: &str, as_of: Option<u64>) -> u32 {
+    DISPUTES.with(|d| {
+        d.borrow()
+            .disputes
+            .values()
+            .filter(|dispute| dispute.product_id == product_id)
+            .filter(|dispute| match as_of {
+                Some(at) => was_open_at(dispute, at),
+                None => dispute.status == DisputeStatus::Open,
+            })
+            .count() as u32
+    })
+}
+
+fn normalize_evidence(hashes: Vec<String>) -> ApiResult<Vec<String>> {
+    if hashes.len() > MAX_EVIDENCE_PER_ENTRY {
+        return Err(BlockTraceError::invalid("evidence_hashes", format!("at most {} per entry", MAX_EVIDENCE_PER_ENTRY)));
+    }
+    let normalized: Vec<String> = hashes.iter().map(|h| normalize_hash(h)).collect();
+    if normalized.iter().any(|h| h.is_empty() || h.len() > 128 || !h.chars().all(|c| c.is_ascii_hexdigit())) {
+        return Err(BlockTraceError::invalid("evidence_hashes", "must be hex digests of the evidence documents"));
+    }
+    Ok(normalized)
+}
+
+fn check_message(field: &str, message: &str) -> ApiResult<()> {
+    if message.trim().is_empty() || message.len() > MAX_MESSAGE_LENGTH {
+        return Err(BlockTraceError::invalid(field, format!("must be 1 to {} bytes", MAX_MESSAGE_LENGTH)));
+    }
+    Ok(())
+}
+
+fn dispute(id: u64) -> ApiResult<Dispute> {
+    DISPUTES
+        .with(|d| d.borrow().disputes.get(&id).cloned())
+        .ok_or_else(|| BlockTraceError::not_found("Dispute", id.to_string()))
+}
+
+fn can_view(dispute: &Dispute, principal: &str) -> bool {
+    is_involved(&dispute.product_id, principal) || dispute.arbiter.as_deref() == Some(principal) || is_admin(principal)
+}
+
+fn store(dispute: &Dispute) {
+    DISPUTES.with(|d| d.borrow_mut().disputes.insert(dispute.id, dispute.clone()));
+}
+
+fn alert(dispute: &Dispute, message: String) {
+    webhooks::notify_alert(WebhookAlert {
+        alert_type: "dispute".to_string(),
+        product_id: dispute.product_id.clone(),
+        batch_number: None,
+        message,
+        timestamp: time(),
+    });
+}
+
+#[update]
+#[candid_method(update)]
+fn open_dispute(
+    product_id: String,
+    sequence: u64,
+    category: DisputeCategory,
+    description: String,
+    evidence_hashes: Vec<String>,
+    caller_principal: String,
+) -> ApiResult<Dispute> {
+    let caller = authenticated_caller(&caller_principal)?;
+    if !is_involved(&product_id, &caller) {
+        return Err(BlockTraceError::unauthorized("Only parties involved in the product can open a dispute"));
+    }
+    let step_exists = PRODUCT_HISTORY.with(|store| {
+        store.borrow().get(&product_id).is_some_and(|steps| steps.iter().any(|s| s.sequence == Some(sequence)))
+    });
+    if !step_exists {
+        return Err(BlockTraceError::not_found("Step", format!("{}/{}", product_id, sequence)));
+    }
+    check_message("description", &description)?;
+    let evidence_hashes = normalize_evidence(evidence_hashes)?;
+
+    let id = DISPUTES.with(|d| {
+        let mut d = d.borrow_mut();
+        d.next_id += 1;
+        d.next_id
+    });
+    let dispute = Dispute {
+        id,
+        product_id,
+        sequence,
+        category,
+        description,
+        opened_by: caller,
+        opened_at: time(),
+        evidence_hashes,
+        comments: vec![],
+        arbiter: None,
+        status: DisputeStatus::Open,
+        resolution: None,
+    };
+    store(&dispute);
+    alert(&dispute, format!("Dispute #{} opened on step {} ({:?})", id, dispute.sequence, dispute.category));
+    logs::info(
+        "disputes",
+        "Dispute opened",
+        vec![("dispute_id", id.to_string()), ("product_id", dispute.product_id.clone()), ("sequence", dispute.sequence.to_string())],
+    );
+    Ok(dispute)
+}
+
+#[update]
+#[candid_method(update)]
+fn comment_on_dispute(id: u64, message: String, evidence_hashes: Vec<String>, caller_principal: String) -> ApiResult<Dispute> {
+    let caller = authenticated_caller(&caller_principal)?;
+    let mut dispute = dispute(id)?;
+    if !can_view(&dispute, &caller) {
+        return Err(BlockTraceError::unauthorized("Only involved parties and the arbiter can comment"));
+    }
+    if dispute.status != DisputeStatus::Open {
+        return Err(BlockTraceError::conflict(format!("Dispute {} is already resolved", id)));
+    }
+    check_message("message", &message)?;
+    dispute.comments.push(DisputeComment {
+        author: caller,
+        message,
+        evidence_hashes: normalize_evidence(evidence_hashes)?,
+        created_at: time(),
+    });
+    store(&dispute);
+    Ok(dispute)
+}
+
+// The arbiter must not be a party to the product.
+#[update]
+#[candid_method(update)]
+fn assign_dispute_arbiter(id: u64, arbiter: String) -> ApiResult<Dispute> {
+    if !is_admin(&format!("{}", ic_cdk::caller())) {
+        return Err(BlockTraceError::unauthorized("Only an admin can designate arbiters"));
+    }
+    let mut dispute = dispute(id)?;
+    if dispute.status != DisputeStatus::Open {
+        return Err(BlockTraceError::conflict(format!("Dispute {} is already resolved", id)));
+    }
+    let arbiter = arbiter.trim().to_string();
+    if arbiter.is_empty() || is_involved(&dispute.product_id, &arbiter) {
+        return Err(BlockTraceError::invalid("arbiter", "must be a principal that is not involved in the product"));
+    }
+    dispute.arbiter = Some(arbiter);
+    store(&dispute);
+    Ok(dispute)
+}
+
+#[update]
+#[candid_method(update)]
+fn resolve_dispute(id: u64, outcome: DisputeOutcome, summary: String, caller_principal: String) -> ApiResult<Dispute> {
+    let caller = authenticated_caller(&caller_principal)?;
+    let mut dispute = dispute(id)?;
+    if dispute.arbiter.as_deref() != Some(caller.as_str()) {
+        return Err(BlockTraceError::unauthorized("Only the designated arbiter can resolve a dispute"));
+    }
+    if dispute.status != DisputeStatus::Open {
+        return Err(BlockTraceError::conflict(format!("Dispute {} is already resolved", id)));
+    }
+    check_message("summary", &summary)?;
+    dispute.status = DisputeStatus::Resolved;
+    dispute.resolution = Some(DisputeResolution { arbiter: caller, outcome, summary, resolved_at: time() });
+    store(&dispute);
+    alert(&dispute, format!("Dispute #{} resolved: {:?}", id, dispute.resolution.as_ref().map(|r| &r.outcome)));
+    Ok(dispute)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_dispute(id: u64, caller_principal: String) -> ApiResult<Dispute> {
+    let caller = authenticated_caller(&caller_principal)?;
+    let dispute = dispute(id)?;
+    if !can_view(&dispute, &caller) {
+        return Err(BlockTraceError::unauthorized("Only involved parties and the arbiter can view a dispute"));
+    }
+    Ok(dispute)
+}
+
+#[query]
+#[candid_method(query)]
+fn list_product_disputes(product_id: String, caller_principal: String) -> Vec<Dispute> {
+    let Ok(caller) = authenticated_caller(&caller_principal) else {
+        return vec![];
+    };
+    DISPUTES.with(|d| {
+        d.borrow()
+            .disputes
+            .values()
+            .filter(|dispute| dispute.product_id == product_id && can_view(dispute, &caller))
+            .cloned()
+            .collect()
+    })
+}
+
+// Open disputes waiting on the caller as arbiter.
+#[query]
+#[candid_method(query)]
+fn list_arbiter_disputes(caller_principal: String) -> Vec<Dispute> {
+    let Ok(caller) = authenticated_caller(&caller_principal) else {
+        return vec![];
+    };
+    DISPUTES.with(|d| {
+        d.borrow()
+            .disputes
+            .values()
+            .filter(|dispute| dispute.status == DisputeStatus::Open && dispute.arbiter.as_deref() == Some(caller.as_str()))
+            .cloned()
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_step;
+
+    #[test]
+    fn open_count_follows_resolution_time() {
+        let mut dispute = Dispute {
+            id: 1,
+            product_id: "P1".to_string(),
+            sequence: 0,
+            category: DisputeCategory::Damage,
+            description: "crushed pallet".to_string(),
+            opened_by: "receiver".to_string(),
+            opened_at: 100,
+            evidence_hashes: normalize_evidence(vec!["SHA256:ABCDEF".to_string()]).unwrap(),
+            comments: vec![],
+            arbiter: Some("arbiter".to_string()),
+            status: DisputeStatus::Resolved,
+            resolution: None,
+        };
+        dispute.resolution = Some(DisputeResolution {
+            arbiter: "arbiter".to_string(),
+            outcome: DisputeOutcome::Settled,
+            summary: "credit note issued".to_string(),
+            resolved_at: 200,
+        });
+        assert_eq!(dispute.evidence_hashes, vec!["abcdef"]);
+        assert!(!was_open_at(&dispute, 50));
+        assert!(was_open_at(&dispute, 150));
+        assert!(!was_open_at(&dispute, 200));
+        assert!(normalize_evidence(vec!["not a hash".to_string()]).is_err());
+    }
+
+    #[test]
+    fn appending_a_step_does_not_make_a_party() {
+        let mut handover = test_step("P1", "warehouse", 3);
+        handover.status = Some(crate::custody::HANDOVER_STATUS.to_string());
+        let steps = vec![test_step("P1", "maker", 1), test_step("P1", "stranger", 2), handover];
+        PRODUCT_HISTORY.with(|store| store.borrow_mut().insert("P1".to_string(), steps));
+        let dispute = Dispute {
+            id: 1,
+            product_id: "P1".to_string(),
+            sequence: 0,
+            category: DisputeCategory::Damage,
+            description: "crushed pallet".to_string(),
+            opened_by: "warehouse".to_string(),
+            opened_at: 100,
+            evidence_hashes: vec![],
+            comments: vec![],
+            arbiter: None,
+            status: DisputeStatus::Open,
+            resolution: None,
+        };
+        assert!(can_view(&dispute, "maker"));
+        assert!(can_view(&dispute, "warehouse"));
+        assert!(!can_view(&dispute, "stranger"));
+        assert!(!is_involved("P1", "stranger"));
+    }
+}
@@ -0,0 +1,283 @@
+// 🏭 Emission factor registry.
+//
+// Factors are keyed by transport mode, vehicle class and fuel (an empty class or fuel matches any)
+// and give kg CO₂e per km and, GLEC-style, per tonne-km when the step carries its load. Publishing
+// a factor for an existing key adds a new version; old versions are kept so ESG results, which
+// record the versions they used, can be reproduced later.
+use candid::{candid_method, CandidType};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+use crate::logs;
+use crate::Step;
+
+// Factor used before the registry existed, kept as the catch-all default
+const LEGACY_KG_PER_KM: f64 = 0.162;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EmissionFactorKey {
+    pub transport_mode: String,
+    pub vehicle_class: String,
+    pub fuel: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EmissionFactorInput {
+    pub key: EmissionFactorKey,
+    pub kg_co2e_per_km: f64,
+    pub kg_co2e_per_tonne_km: Option<f64>,
+    pub source: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EmissionFactor {
+    pub key: EmissionFactorKey,
+    pub version: u32,
+    pub kg_co2e_per_km: f64,
+    pub kg_co2e_per_tonne_km: Option<f64>,
+    pub source: String,
+    pub published_by: String,
+    pub published_at: u64,
+}
+
+// Identifies the exact factor version behind a calculated figure.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub struct EmissionFactorRef {
+    pub key: EmissionFactorKey,
+    pub version: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum EmissionMethod {
+    // carbon_footprint_kg supplied with the step
+    Reported,
+    TonneKm,
+    Distance,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct StepEmission {
+    pub kg_co2e: f64,
+    pub method: EmissionMethod,
+    pub factor: Option<EmissionFactorRef>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EmissionFactorRegistry {
+    // Every published version per key, oldest first
+    pub factors: BTreeMap<EmissionFactorKey, Vec<EmissionFactor>>,
+}
+
+fn key(transport_mode: &str, vehicle_class: &str, fuel: &str) -> EmissionFactorKey {
+    EmissionFactorKey {
+        transport_mode: transport_mode.trim().to_lowercase(),
+        vehicle_class: vehicle_class.trim().to_lowercase(),
+        fuel: fuel.trim().to_lowercase(),
+    }
+}
+
+fn seeded(transport_mode: &str, kg_co2e_per_km: f64) -> EmissionFactor {
+    EmissionFactor {
+        key: key(transport_mode, "", ""),
+        version: 1,
+        kg_co2e_per_km,
+        kg_co2e_per_tonne_km: None,
+        source: "BlockTrace default".to_string(),
+        published_by: "system".to_string(),
+        published_at: 0,
+    }
+}
+
+impl Default for EmissionFactorRegistry {
+    // The per-km factors the add-step form has always offered, plus the legacy catch-all
+    fn default() -> Self {
+        EmissionFactorRegistry {
+            factors: [
+                seeded("", LEGACY_KG_PER_KM),
+                seeded("truck", 0.162),
+                seeded("ship", 0.017),
+                seeded("plane", 0.255),
+                seeded("train", 0.041),
+            ]
+            .into_iter()
+            .map(|factor| (factor.key.clone(), vec![factor]))
+            .collect(),
+        }
+    }
+}
+
+thread_local! {
+    static REGISTRY: RefCell<EmissionFactorRegistry> = RefCell::new(EmissionFactorRegistry::default());
+}
+
+pub(crate) fn snapshot() -> EmissionFactorRegistry {
+    REGISTRY.with(|r| r.borrow().clone())
+}
+
+pub(crate) fn restore(registry: EmissionFactorRegistry) {
+    REGISTRY.with(|r| *r.borrow_mut() = registry);
+}
+
+/// Latest factor for the most specific key that matches, falling back to any class, any fuel and
+/// finally the catch-all default.
+pub fn factor_for(transport_mode: &str, vehicle_class: &str, fuel: &str) -> EmissionFactor {
+    let candidates = [
+        key(transport_mode, vehicle_class, fuel),
+        key(transport_mode, vehicle_class, ""),
+        key(transport_mode, "", ""),
+        key("", "", ""),
+    ];
+    REGISTRY.with(|r| {
+        let registry = r.borrow();
+        candidates
+            .iter()
+            .find_map(|k| registry.factors.get(k).and_then(|versions| versions.last()).cloned())
+            .unwrap_or_else(|| seeded("", LEGACY_KG_PER_KM))
+    })
+}
+
+/// Distance-based estimate for callers that only know the transport mode.
+pub fn distance_emission_kg(transport_mode: &str, distance_km: f64) -> f64 {
+    distance_km * factor_for(transport_mode, "", "").kg_co2e_per_km
+}
+
+/// Emissions of a single step: the reported figure when present, otherwise distance (and load,
+/// when both the step and the factor support tonne-km) times the matching factor.
+pub fn step_emission(step: &Step) -> Option<StepEmission> {
+    if let Some(reported) = step.carbon_footprint_kg.filter(|kg| *kg > 0.0) {
+        return Some(StepEmission { kg_co2e: reported, method: EmissionMethod::Reported, factor: None });
+    }
+    let distance = step.distance_km.filter(|km| *km > 0.0)?;
+    let factor = factor_for(
+        step.transport_mode.as_deref().unwrap_or_default(),
+        step.vehicle_class.as_deref().unwrap_or_default(),
+        step.fuel_type.as_deref().unwrap_or_default(),
+    );
+    let factor_ref = Some(EmissionFactorRef { key: factor.key.clone(), version: factor.version });
+    match (step.load_tonnes.filter(|t| *t > 0.0), factor.kg_co2e_per_tonne_km) {
+        (Some(tonnes), Some(per_tonne_km)) => {
+            Some(StepEmission { kg_co2e: distance * tonnes * per_tonne_km, method: EmissionMethod::TonneKm, factor: factor_ref })
+        }
+        _ => Some(StepEmission { kg_co2e: distance * factor.kg_co2e_per_km, method: EmissionMethod::Distance, factor: factor_ref }),
+    }
+}
+
+fn check_factor(field: &str, value: f64) -> ApiResult<()> {
+    if !value.is_finite() || value < 0.0 {
+        return Err(BlockTraceError::invalid(field, "must be a non-negative number"));
+    }
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+fn publish_emission_factor(input: EmissionFactorInput) -> ApiResult<EmissionFactor> {
+    let caller = format!("{}", ic_cdk::caller());
+    if !is_admin(&caller) {
+        return Err(BlockTraceError::unauthorized("Only an admin can publish emission factors"));
+    }
+    check_factor("kg_co2e_per_km", input.kg_co2e_per_km)?;
+    if let Some(per_tonne_km) = input.kg_co2e_per_tonne_km {
+        check_factor("kg_co2e_per_tonne_km", per_tonne_km)?;
+    }
+    if input.source.trim().is_empty() {
+        return Err(BlockTraceError::invalid("source", "cite where the factor comes from"));
+    }
+    let key = key(&input.key.transport_mode, &input.key.vehicle_class, &input.key.fuel);
+    let factor = REGISTRY.with(|r| {
+        let mut registry = r.borrow_mut();
+        let versions = registry.factors.entry(key.clone()).or_default();
+        let factor = EmissionFactor {
+            key,
+            version: versions.last().map(|f| f.version + 1).unwrap_or(1),
+            kg_co2e_per_km: input.kg_co2e_per_km,
+            kg_co2e_per_tonne_km: input.kg_co2e_per_tonne_km,
+            source: input.source.trim().to_string(),
+            published_by: caller.clone(),
+            published_at: time(),
+        };
+        versions.push(factor.clone());
+        factor
+    });
+    logs::info(
+        "emissions",
+        "Emission factor published",
+        vec![
+            ("transport_mode", factor.key.transport_mode.clone()),
+            ("vehicle_class", factor.key.vehicle_class.clone()),
+            ("fuel", factor.key.fuel.clone()),
+            ("version", factor.version.to_string()),
+        ],
+    );
+    Ok(factor)
+}
+
+// Latest version per key, or every version when `include_history` is set.
+#[query]
+#[candid_method(query)]
+fn list_emission_factors(include_history: bool) -> Vec<EmissionFactor> {
+    REGISTRY.with(|r| {
+        r.borrow()
+            .factors
+            .values()
+            .flat_map(|versions| if include_history { versions.as_slice() } else { &versions[versions.len().saturating_sub(1)..] })
+            .cloned()
+            .collect()
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_emission_factor(factor: EmissionFactorRef) -> Option<EmissionFactor> {
+    REGISTRY.with(|r| {
+        r.borrow()
+            .factors
+            .get(&factor.key)
+            .and_then(|versions| versions.iter().find(|f| f.version == factor.version))
+            .cloned()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_step;
+
+    #[test]
+    fn picks_most_specific_factor_and_tonne_km() {
+        let hgv = EmissionFactor {
+            key: key("Truck", "HGV 40t", "Diesel"),
+            version: 2,
+            kg_co2e_per_km: 0.9,
+            kg_co2e_per_tonne_km: Some(0.06),
+            source: "GLEC v3".to_string(),
+            published_by: "admin".to_string(),
+            published_at: 1,
+        };
+        REGISTRY.with(|r| r.borrow_mut().factors.insert(hgv.key.clone(), vec![hgv]));
+
+        let mut step = test_step("P1", "carrier", 1);
+        step.transport_mode = Some("TRUCK".to_string());
+        step.distance_km = Some(100.0);
+        let generic = step_emission(&step).unwrap();
+        assert_eq!(generic.method, EmissionMethod::Distance);
+        assert!((generic.kg_co2e - 16.2).abs() < 1e-9);
+
+        step.vehicle_class = Some("hgv 40t".to_string());
+        step.fuel_type = Some("diesel".to_string());
+        step.load_tonnes = Some(20.0);
+        let specific = step_emission(&step).unwrap();
+        assert_eq!(specific.method, EmissionMethod::TonneKm);
+        assert!((specific.kg_co2e - 120.0).abs() < 1e-9);
+        assert_eq!(specific.factor.unwrap().version, 2);
+
+        step.carbon_footprint_kg = Some(42.0);
+        assert_eq!(step_emission(&step).unwrap().method, EmissionMethod::Reported);
+        assert_eq!(factor_for("hovercraft", "", "").key, key("", "", ""));
+    }
+}
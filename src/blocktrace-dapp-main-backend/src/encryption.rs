@@ -0,0 +1,359 @@
+// 🔒 Optional field-level encryption of sensitive step fields with per-organization vetKeys.
+//
+// Clients encrypt selected fields before calling add_step; the canister only stores ciphertext
+// and blanks the plaintext fields. Decryption keys are derived by the vetKD system API for the
+// organization (and key version) and are handed out, encrypted under a caller-supplied
+// transport key, only to members of that organization or principals the organization granted.
+use candid::{candid_method, CandidType, Principal};
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::logs;
+use crate::organizations::organization_of;
+use crate::{quotas, Step, PRODUCT_HISTORY};
+
+const VETKD_KEY_NAME: &str = "key_1"; // Use "test_key_1" on test subnets, "dfx_test_key" locally
+const VETKD_CONTEXT: &[u8] = b"blocktrace-step-fields";
+const VETKD_DERIVE_KEY_CYCLES: u128 = 26_153_846_153;
+const MAX_CIPHERTEXT_BYTES: usize = 4096;
+pub const ENCRYPTED_PLACEHOLDER: &str = "[encrypted]";
+pub const ENCRYPTABLE_FIELDS: [&str; 3] = ["notes", "cost_usd", "actor_name"];
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct EncryptedField {
+    // One of ENCRYPTABLE_FIELDS
+    pub field: String,
+    pub ciphertext: Vec<u8>,
+    pub nonce: Vec<u8>,
+    // Symmetric scheme used with the derived key, e.g. "AES-256-GCM"
+    pub algorithm: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct StepEncryption {
+    pub organization_id: String,
+    pub key_version: u32,
+    pub fields: Vec<EncryptedField>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct KeyDerivationMetadata {
+    pub key_name: String,
+    pub context: Vec<u8>,
+    pub derivation_input: Vec<u8>,
+    pub organization_id: String,
+    pub key_version: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EncryptedStepFields {
+    pub product_id: String,
+    pub sequence: Option<u64>,
+    pub timestamp: u64,
+    pub encryption: StepEncryption,
+    pub key: KeyDerivationMetadata,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct EncryptionState {
+    pub key_versions: HashMap<String, u32>,
+    pub decryption_grants: HashMap<String, Vec<String>>,
+}
+
+// vetKD management canister interface
+#[derive(CandidType, Deserialize)]
+enum VetKDCurve {
+    #[serde(rename = "bls12_381_g2")]
+    Bls12381G2,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKDKeyId {
+    curve: VetKDCurve,
+    name: String,
+}
+
+#[derive(CandidType)]
+struct VetKDPublicKeyArgs {
+    canister_id: Option<Principal>,
+    context: Vec<u8>,
+    key_id: VetKDKeyId,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKDPublicKeyReply {
+    public_key: Vec<u8>,
+}
+
+#[derive(CandidType)]
+struct VetKDDeriveKeyArgs {
+    input: Vec<u8>,
+    context: Vec<u8>,
+    transport_public_key: Vec<u8>,
+    key_id: VetKDKeyId,
+}
+
+#[derive(CandidType, Deserialize)]
+struct VetKDDeriveKeyReply {
+    encrypted_key: Vec<u8>,
+}
+
+thread_local! {
+    static KEY_VERSIONS: RefCell<HashMap<String, u32>> = RefCell::new(HashMap::new());
+    static DECRYPTION_GRANTS: RefCell<HashMap<String, Vec<String>>> = RefCell::new(HashMap::new());
+}
+
+fn key_id() -> VetKDKeyId {
+    VetKDKeyId { curve: VetKDCurve::Bls12381G2, name: VETKD_KEY_NAME.to_string() }
+}
+
+fn current_key_version(organization_id: &str) -> u32 {
+    KEY_VERSIONS.with(|v| v.borrow().get(organization_id).copied().unwrap_or(1))
+}
+
+fn derivation_input(organization_id: &str, key_version: u32) -> Vec<u8> {
+    format!("{}:v{}", organization_id, key_version).into_bytes()
+}
+
+fn key_metadata(organization_id: &str, key_version: u32) -> KeyDerivationMetadata {
+    KeyDerivationMetadata {
+        key_name: VETKD_KEY_NAME.to_string(),
+        context: VETKD_CONTEXT.to_vec(),
+        derivation_input: derivation_input(organization_id, key_version),
+        organization_id: organization_id.to_string(),
+        key_version,
+    }
+}
+
+fn can_decrypt(principal: &str, organization_id: &str) -> bool {
+    organization_of(principal) == organization_id
+        || DECRYPTION_GRANTS.with(|g| g.borrow().get(organization_id).is_some_and(|p| p.iter().any(|p| p == principal)))
+}
+
+/// Validate the ciphertext attached to a step and blank the plaintext of the encrypted fields.
+/// Data must be encrypted under a key of the writer's own organization.
+pub fn seal_step(step: &mut Step) -> ApiResult<()> {
+    let Some(encryption) = step.encryption.as_ref() else {
+        return Ok(());
+    };
+    let organization_id = organization_of(&step.user_id);
+    if encryption.organization_id != organization_id {
+        return Err(BlockTraceError::invalid("encryption.organization_id", format!("must be {}", organization_id)));
+    }
+    if encryption.key_version == 0 || encryption.key_version > current_key_version(&organization_id) {
+        return Err(BlockTraceError::invalid("encryption.key_version", format!("unknown version {} for organization {}", encryption.key_version, organization_id)));
+    }
+    if encryption.fields.is_empty() {
+        return Err(BlockTraceError::invalid("encryption.fields", "cannot be empty"));
+    }
+    let mut seen: Vec<&str> = Vec::new();
+    for field in &encryption.fields {
+        if !ENCRYPTABLE_FIELDS.contains(&field.field.as_str()) {
+            return Err(BlockTraceError::invalid("encryption.fields", format!("'{}' cannot be encrypted; supported: {}", field.field, ENCRYPTABLE_FIELDS.join(", "))));
+        }
+        if seen.contains(&field.field.as_str()) {
+            return Err(BlockTraceError::invalid("encryption.fields", format!("'{}' is encrypted more than once", field.field)));
+        }
+        if field.ciphertext.is_empty() || field.ciphertext.len() > MAX_CIPHERTEXT_BYTES {
+            return Err(BlockTraceError::invalid("encryption.fields", format!("ciphertext for '{}' must be between 1 and {} bytes", field.field, MAX_CIPHERTEXT_BYTES)));
+        }
+        seen.push(&field.field);
+    }
+
+    let fields: Vec<String> = seen.iter().map(|f| f.to_string()).collect();
+    for field in fields {
+        match field.as_str() {
+            "notes" => step.notes = None,
+            "cost_usd" => step.cost_usd = None,
+            "actor_name" => step.actor_name = ENCRYPTED_PLACEHOLDER.to_string(),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn snapshot() -> EncryptionState {
+    EncryptionState {
+        key_versions: KEY_VERSIONS.with(|v| v.borrow().clone()),
+        decryption_grants: DECRYPTION_GRANTS.with(|g| g.borrow().clone()),
+    }
+}
+
+pub(crate) fn restore(state: EncryptionState) {
+    KEY_VERSIONS.with(|v| *v.borrow_mut() = state.key_versions);
+    DECRYPTION_GRANTS.with(|g| *g.borrow_mut() = state.decryption_grants);
+}
+
+// Metadata a client needs to encrypt new steps for its organization.
+#[query]
+#[candid_method(query)]
+fn get_encryption_key_metadata(organization_id: String) -> KeyDerivationMetadata {
+    key_metadata(&organization_id, current_key_version(&organization_id))
+}
+
+// Public key clients use to verify derived keys before trusting them.
+#[update]
+#[candid_method(update)]
+async fn get_vetkd_public_key() -> ApiResult<Vec<u8>> {
+    let args = VetKDPublicKeyArgs { canister_id: None, context: VETKD_CONTEXT.to_vec(), key_id: key_id() };
+    let (reply,): (VetKDPublicKeyReply,) = ic_cdk::call(Principal::management_canister(), "vetkd_public_key", (args,))
+        .await
+        .map_err(|(code, msg)| BlockTraceError::external("vetkd_public_key", format!("{:?} {}", code, msg)))?;
+    Ok(reply.public_key)
+}
+
+// The returned key is encrypted under `transport_public_key`, so only the caller can use it.
+#[update]
+#[candid_method(update)]
+async fn get_encrypted_decryption_key(organization_id: String, key_version: u32, transport_public_key: Vec<u8>) -> ApiResult<Vec<u8>> {
+    let caller = format!("{}", ic_cdk::caller());
+    if !can_decrypt(&caller, &organization_id) {
+        return Err(BlockTraceError::unauthorized(format!("{} is not authorized to decrypt data of organization {}", caller, organization_id)));
+    }
+    if key_version == 0 || key_version > current_key_version(&organization_id) {
+        return Err(BlockTraceError::invalid("key_version", format!("unknown version {} for organization {}", key_version, organization_id)));
+    }
+    quotas::reserve_signature(&caller)?;
+
+    let args = VetKDDeriveKeyArgs {
+        input: derivation_input(&organization_id, key_version),
+        context: VETKD_CONTEXT.to_vec(),
+        transport_public_key,
+        key_id: key_id(),
+    };
+    let (reply,): (VetKDDeriveKeyReply,) = ic_cdk::api::call::call_with_payment128(
+        Principal::management_canister(),
+        "vetkd_derive_key",
+        (args,),
+        VETKD_DERIVE_KEY_CYCLES,
+    )
+    .await
+    .map_err(|(code, msg)| BlockTraceError::external("vetkd_derive_key", format!("{:?} {}", code, msg)))?;
+    logs::info(
+        "encryption",
+        "Decryption key issued",
+        vec![("key_version", key_version.to_string()), ("organization_id", organization_id.clone()), ("caller", caller.clone())],
+    );
+    Ok(reply.encrypted_key)
+}
+
+// Start encrypting new steps under a fresh key. Older versions stay derivable for existing data.
+#[update]
+#[candid_method(update)]
+fn rotate_organization_key() -> KeyDerivationMetadata {
+    let organization_id = organization_of(&format!("{}", ic_cdk::caller()));
+    let version = KEY_VERSIONS.with(|v| {
+        let mut versions = v.borrow_mut();
+        let version = versions.entry(organization_id.clone()).or_insert(1);
+        *version += 1;
+        *version
+    });
+    key_metadata(&organization_id, version)
+}
+
+// Members of an organization can let outside principals (auditors, partners) decrypt its data.
+#[update]
+#[candid_method(update)]
+fn grant_decryption_access(principal: String) -> ApiResult<String> {
+    let organization_id = organization_of(&format!("{}", ic_cdk::caller()));
+    if principal.trim().is_empty() {
+        return Err(BlockTraceError::invalid("principal", "cannot be empty"));
+    }
+    DECRYPTION_GRANTS.with(|g| {
+        let mut grants = g.borrow_mut();
+        let list = grants.entry(organization_id.clone()).or_default();
+        if !list.contains(&principal) {
+            list.push(principal.clone());
+        }
+    });
+    Ok(format!("{} can now decrypt data of {}", principal, organization_id))
+}
+
+#[update]
+#[candid_method(update)]
+fn revoke_decryption_access(principal: String) -> ApiResult<String> {
+    let organization_id = organization_of(&format!("{}", ic_cdk::caller()));
+    let removed = DECRYPTION_GRANTS.with(|g| {
+        let mut grants = g.borrow_mut();
+        let list = grants.entry(organization_id.clone()).or_default();
+        let before = list.len();
+        list.retain(|p| *p != principal);
+        before != list.len()
+    });
+    if removed {
+        Ok(format!("{} can no longer obtain keys of {}", principal, organization_id))
+    } else {
+        Err(BlockTraceError::not_found("Decryption grant", principal))
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn list_decryption_grants() -> Vec<String> {
+    let organization_id = organization_of(&format!("{}", ic_cdk::caller()));
+    DECRYPTION_GRANTS.with(|g| g.borrow().get(&organization_id).cloned().unwrap_or_default())
+}
+
+// Ciphertext is safe to hand out; only the derived key is access controlled.
+#[query]
+#[candid_method(query)]
+fn get_encrypted_step_fields(product_id: String) -> Vec<EncryptedStepFields> {
+    PRODUCT_HISTORY.with(|store| {
+        store
+            .borrow()
+            .get(&product_id)
+            .map(|steps| {
+                steps
+                    .iter()
+                    .filter_map(|step| {
+                        let encryption = step.encryption.clone()?;
+                        let key = key_metadata(&encryption.organization_id, encryption.key_version);
+                        Some(EncryptedStepFields {
+                            product_id: step.product_id.clone(),
+                            sequence: step.sequence,
+                            timestamp: step.timestamp,
+                            encryption,
+                            key,
+                        })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_step;
+
+    fn field(name: &str) -> EncryptedField {
+        EncryptedField { field: name.to_string(), ciphertext: vec![1, 2, 3], nonce: vec![0; 12], algorithm: "AES-256-GCM".to_string() }
+    }
+
+    #[test]
+    fn sealing_blanks_plaintext_and_rejects_foreign_keys() {
+        let mut step = test_step("P1", "org_member", 1);
+        step.notes = Some("secret supplier terms".to_string());
+        step.cost_usd = Some(12.5);
+        step.encryption = Some(StepEncryption {
+            organization_id: "org_member".to_string(),
+            key_version: 1,
+            fields: vec![field("notes"), field("cost_usd")],
+        });
+        seal_step(&mut step).unwrap();
+        assert_eq!(step.notes, None);
+        assert_eq!(step.cost_usd, None);
+
+        let mut foreign = step.clone();
+        foreign.encryption.as_mut().unwrap().organization_id = "competitor".to_string();
+        assert!(seal_step(&mut foreign).is_err());
+
+        let mut unsupported = step.clone();
+        unsupported.encryption.as_mut().unwrap().fields = vec![field("location")];
+        assert!(seal_step(&mut unsupported).is_err());
+    }
+}
@@ -0,0 +1,85 @@
+// ❗ Typed errors returned by every fallible endpoint, so clients can branch on the kind of
+// failure instead of parsing messages.
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct FieldViolation {
+    pub field: String,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum BlockTraceError {
+    NotFound { resource: String, id: String },
+    Unauthorized { reason: String },
+    InvalidInput { field: String, reason: String },
+    // Every field check that failed, so the client can fix them all in one round trip
+    ValidationFailed { violations: Vec<FieldViolation> },
+    QuotaExceeded { reason: String, retry_after_seconds: Option<u64> },
+    ExternalServiceFailed { service: String, reason: String },
+    // The request is valid but clashes with the current state (duplicates, stale proposals, ...)
+    Conflict { reason: String },
+    // Optimistic concurrency check failed; carries the current head so the client can refresh
+    StaleHistory { product_id: String, last_sequence: Option<u64>, last_step_hash: Option<String> },
+    // Destructive admin operation that must go through propose/approve/execute
+    ApprovalRequired { method: String, threshold: u8, admin_count: u32 },
+}
+
+pub type ApiResult<T> = Result<T, BlockTraceError>;
+
+impl BlockTraceError {
+    pub fn not_found(resource: &str, id: impl Into<String>) -> Self {
+        BlockTraceError::NotFound { resource: resource.to_string(), id: id.into() }
+    }
+
+    pub fn unauthorized(reason: impl Into<String>) -> Self {
+        BlockTraceError::Unauthorized { reason: reason.into() }
+    }
+
+    pub fn invalid(field: &str, reason: impl Into<String>) -> Self {
+        BlockTraceError::InvalidInput { field: field.to_string(), reason: reason.into() }
+    }
+
+    pub fn quota(reason: impl Into<String>, retry_after_seconds: Option<u64>) -> Self {
+        BlockTraceError::QuotaExceeded { reason: reason.into(), retry_after_seconds }
+    }
+
+    pub fn external(service: &str, reason: impl Into<String>) -> Self {
+        BlockTraceError::ExternalServiceFailed { service: service.to_string(), reason: reason.into() }
+    }
+
+    pub fn conflict(reason: impl Into<String>) -> Self {
+        BlockTraceError::Conflict { reason: reason.into() }
+    }
+}
+
+impl fmt::Display for BlockTraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockTraceError::NotFound { resource, id } => write!(f, "{} {} not found", resource, id),
+            BlockTraceError::Unauthorized { reason } => write!(f, "Unauthorized: {}", reason),
+            BlockTraceError::InvalidInput { field, reason } => write!(f, "Invalid {}: {}", field, reason),
+            BlockTraceError::ValidationFailed { violations } => {
+                let details: Vec<String> = violations.iter().map(|v| format!("{} {}", v.field, v.reason)).collect();
+                write!(f, "Validation failed: {}", details.join("; "))
+            }
+            BlockTraceError::QuotaExceeded { reason, retry_after_seconds: Some(retry) } => {
+                write!(f, "Quota exceeded: {} (retry in {}s)", reason, retry)
+            }
+            BlockTraceError::QuotaExceeded { reason, retry_after_seconds: None } => write!(f, "Quota exceeded: {}", reason),
+            BlockTraceError::ExternalServiceFailed { service, reason } => write!(f, "{} failed: {}", service, reason),
+            BlockTraceError::Conflict { reason } => write!(f, "{}", reason),
+            BlockTraceError::StaleHistory { product_id, last_sequence, .. } => match last_sequence {
+                Some(sequence) => write!(f, "Product {} changed (last step is now #{}); refresh and retry", product_id, sequence),
+                None => write!(f, "Product {} has no steps; refresh and retry", product_id),
+            },
+            BlockTraceError::ApprovalRequired { method, threshold, admin_count } => write!(
+                f,
+                "{} requires {}-of-{} admin approval; submit it with propose_admin_action",
+                method, threshold, admin_count
+            ),
+        }
+    }
+}
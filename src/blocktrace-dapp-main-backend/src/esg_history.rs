@@ -0,0 +1,289 @@
+// 📈 ESG score time series per product.
+//
+// Recalculations (scheduled, global monitoring or on request) append a point whenever the score
+// or footprint changed, and otherwise at most once a day, so customers can chart how a product's
+// score and footprint moved over time. Once a series grows past its cap, points older than the
+// last 30 days are thinned to one per day, then per two days and so on, rather than the oldest
+// being dropped. Range queries return the points together with the delta between the first and
+// last point and a least-squares trend.
+use candid::{candid_method, CandidType};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::benchmarks;
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+use crate::history::is_involved;
+use crate::{calculate_esg_score, resolve_caller, ESGScore};
+
+const MAX_POINTS_PER_PRODUCT: usize = 5_000;
+const NANOS_PER_DAY: f64 = 86_400_000_000_000.0;
+// Unchanged scores are recorded at most this often
+const UNCHANGED_RECORD_INTERVAL_NANOS: u64 = 86_400_000_000_000;
+// Points this recent are never downsampled
+const FULL_RESOLUTION_NANOS: u64 = 30 * 86_400_000_000_000;
+// Score slope (points per day) below which a series counts as stable
+const STABLE_SLOPE_PER_DAY: f64 = 0.01;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EsgScorePoint {
+    pub timestamp: u64,
+    pub sustainability_score: u8,
+    pub carbon_footprint_kg: f64,
+    pub total_distance_km: f64,
+    pub methodology_version: u32,
+    // What triggered the recalculation, e.g. "scheduled" or "manual"
+    pub trigger: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum EsgTrendDirection {
+    Improving,
+    Stable,
+    Declining,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EsgTrend {
+    pub direction: EsgTrendDirection,
+    pub score_change_per_day: f64,
+    pub carbon_change_kg_per_day: f64,
+}
+
+// Change from the first to the last point of the range.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EsgDelta {
+    pub score_change: i16,
+    pub carbon_change_kg: f64,
+    pub carbon_change_percent: Option<f64>,
+    pub days: f64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EsgScoreSeries {
+    pub product_id: String,
+    pub points: Vec<EsgScorePoint>,
+    pub delta: Option<EsgDelta>,
+    pub trend: Option<EsgTrend>,
+}
+
+thread_local! {
+    static SERIES: RefCell<BTreeMap<String, Vec<EsgScorePoint>>> = const { RefCell::new(BTreeMap::new()) };
+}
+
+pub(crate) fn snapshot() -> BTreeMap<String, Vec<EsgScorePoint>> {
+    SERIES.with(|s| s.borrow().clone())
+}
+
+pub(crate) fn restore(series: BTreeMap<String, Vec<EsgScorePoint>>) {
+    SERIES.with(|s| *s.borrow_mut() = series);
+}
+
+pub(crate) fn clear() {
+    SERIES.with(|s| s.borrow_mut().clear());
+}
+
+pub(crate) fn record(score: &ESGScore, trigger: &str, timestamp: u64) {
+    let point = EsgScorePoint {
+        timestamp,
+        sustainability_score: score.sustainability_score,
+        carbon_footprint_kg: score.carbon_footprint_kg,
+        total_distance_km: score.total_distance_km,
+        methodology_version: score.methodology_version,
+        trigger: trigger.to_string(),
+    };
+    let recorded = SERIES.with(|s| {
+        let mut series = s.borrow_mut();
+        let points = series.entry(score.product_id.clone()).or_default();
+        if !should_record(points.last(), &point) {
+            return false;
+        }
+        points.push(point);
+        if points.len() > MAX_POINTS_PER_PRODUCT {
+            downsample(points);
+        }
+        true
+    });
+    if recorded {
+        benchmarks::refresh(&score.product_id);
+    }
+}
+
+fn should_record(last: Option<&EsgScorePoint>, point: &EsgScorePoint) -> bool {
+    let Some(last) = last else {
+        return true;
+    };
+    let unchanged = last.sustainability_score == point.sustainability_score
+        && last.carbon_footprint_kg == point.carbon_footprint_kg
+        && last.total_distance_km == point.total_distance_km
+        && last.methodology_version == point.methodology_version;
+    !unchanged || point.timestamp.saturating_sub(last.timestamp) >= UNCHANGED_RECORD_INTERVAL_NANOS
+}
+
+// Keeps the latest point of each `bucket`-wide time slot among points older than `cutoff`.
+fn thin(points: &mut Vec<EsgScorePoint>, cutoff: u64, bucket: u64) {
+    let mut kept: Vec<EsgScorePoint> = Vec::with_capacity(points.len());
+    for point in points.drain(..) {
+        let replaces_previous = kept.last().is_some_and(|last| {
+            last.timestamp < cutoff && point.timestamp < cutoff && last.timestamp / bucket == point.timestamp / bucket
+        });
+        if replaces_previous {
+            kept.pop();
+        }
+        kept.push(point);
+    }
+    *points = kept;
+}
+
+// Thins ever older points at ever coarser resolution until the series fits its cap again.
+fn downsample(points: &mut Vec<EsgScorePoint>) {
+    let newest = points.last().map(|p| p.timestamp).unwrap_or_default();
+    let (mut bucket, mut recent) = (UNCHANGED_RECORD_INTERVAL_NANOS, FULL_RESOLUTION_NANOS);
+    while points.len() > MAX_POINTS_PER_PRODUCT {
+        thin(points, newest.saturating_sub(recent), bucket);
+        if bucket == u64::MAX {
+            break;
+        }
+        bucket = bucket.saturating_mul(2);
+        recent /= 2;
+    }
+}
+
+/// Least-squares slope of `value` over time, per day.
+fn slope_per_day(points: &[EsgScorePoint], value: impl Fn(&EsgScorePoint) -> f64) -> f64 {
+    let origin = points[0].timestamp;
+    let xs: Vec<f64> = points.iter().map(|p| (p.timestamp - origin) as f64 / NANOS_PER_DAY).collect();
+    let n = points.len() as f64;
+    let (mean_x, mean_y) = (xs.iter().sum::<f64>() / n, points.iter().map(&value).sum::<f64>() / n);
+    let covariance: f64 = xs.iter().zip(points).map(|(x, p)| (x - mean_x) * (value(p) - mean_y)).sum();
+    let variance: f64 = xs.iter().map(|x| (x - mean_x).powi(2)).sum();
+    if variance == 0.0 {
+        0.0
+    } else {
+        covariance / variance
+    }
+}
+
+fn series(product_id: String, points: Vec<EsgScorePoint>) -> EsgScoreSeries {
+    let (delta, trend) = match (points.first(), points.last()) {
+        (Some(first), Some(last)) if points.len() > 1 => {
+            let score_change_per_day = slope_per_day(&points, |p| p.sustainability_score as f64);
+            let direction = if score_change_per_day > STABLE_SLOPE_PER_DAY {
+                EsgTrendDirection::Improving
+            } else if score_change_per_day < -STABLE_SLOPE_PER_DAY {
+                EsgTrendDirection::Declining
+            } else {
+                EsgTrendDirection::Stable
+            };
+            let carbon_change_kg = last.carbon_footprint_kg - first.carbon_footprint_kg;
+            (
+                Some(EsgDelta {
+                    score_change: last.sustainability_score as i16 - first.sustainability_score as i16,
+                    carbon_change_kg,
+                    carbon_change_percent: (first.carbon_footprint_kg > 0.0).then(|| carbon_change_kg / first.carbon_footprint_kg * 100.0),
+                    days: (last.timestamp - first.timestamp) as f64 / NANOS_PER_DAY,
+                }),
+                Some(EsgTrend {
+                    direction,
+                    score_change_per_day,
+                    carbon_change_kg_per_day: slope_per_day(&points, |p| p.carbon_footprint_kg),
+                }),
+            )
+        }
+        _ => (None, None),
+    };
+    EsgScoreSeries { product_id, points, delta, trend }
+}
+
+fn check_access(product_id: &str, caller: &str) -> ApiResult<()> {
+    if !is_involved(product_id, caller) && !is_admin(caller) {
+        return Err(BlockTraceError::unauthorized("Only parties involved in the product can see its ESG history"));
+    }
+    Ok(())
+}
+
+// Computes the current score over the full product history and stores it as a point.
+#[update]
+#[candid_method(update)]
+fn recalculate_esg_score(product_id: String, caller_principal: String) -> ApiResult<ESGScore> {
+    let caller = resolve_caller(caller_principal);
+    check_access(&product_id, &caller)?;
+    let score = calculate_esg_score(product_id.clone(), String::new(), None, None)
+        .ok_or_else(|| BlockTraceError::not_found("Product", product_id))?;
+    record(&score, "manual", time());
+    Ok(score)
+}
+
+// Points within [from, to] (nanoseconds, both optional) with delta and trend over that range.
+#[query]
+#[candid_method(query)]
+fn get_esg_score_history(product_id: String, from: Option<u64>, to: Option<u64>, caller_principal: String) -> ApiResult<EsgScoreSeries> {
+    let caller = resolve_caller(caller_principal);
+    check_access(&product_id, &caller)?;
+    let (from, to) = (from.unwrap_or(0), to.unwrap_or(u64::MAX));
+    if from > to {
+        return Err(BlockTraceError::invalid("from", "must not be after to"));
+    }
+    let points: Vec<EsgScorePoint> = SERIES.with(|s| {
+        s.borrow()
+            .get(&product_id)
+            .map(|points| points.iter().filter(|p| (from..=to).contains(&p.timestamp)).cloned().collect())
+            .unwrap_or_default()
+    });
+    Ok(series(product_id, points))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn point(day: u64, score: u8, carbon: f64) -> EsgScorePoint {
+        EsgScorePoint {
+            timestamp: day * NANOS_PER_DAY as u64,
+            sustainability_score: score,
+            carbon_footprint_kg: carbon,
+            total_distance_km: 0.0,
+            methodology_version: 1,
+            trigger: "scheduled".to_string(),
+        }
+    }
+
+    #[test]
+    fn computes_delta_and_trend() {
+        let result = series("P1".to_string(), vec![point(0, 60, 200.0), point(10, 65, 180.0), point(20, 70, 150.0)]);
+        let delta = result.delta.unwrap();
+        assert_eq!(delta.score_change, 10);
+        assert_eq!(delta.carbon_change_percent, Some(-25.0));
+        assert_eq!(delta.days, 20.0);
+        let trend = result.trend.unwrap();
+        assert_eq!(trend.direction, EsgTrendDirection::Improving);
+        assert!((trend.score_change_per_day - 0.5).abs() < 1e-9);
+
+        assert!(series("P1".to_string(), vec![point(0, 60, 200.0)]).trend.is_none());
+    }
+
+    #[test]
+    fn skips_unchanged_points_and_downsamples_old_ones() {
+        let first = point(0, 60, 200.0);
+        let mut same_hour = point(0, 60, 200.0);
+        same_hour.timestamp += 3_600_000_000_000;
+        assert!(!should_record(Some(&first), &same_hour));
+        same_hour.carbon_footprint_kg = 190.0;
+        assert!(should_record(Some(&first), &same_hour));
+        assert!(should_record(Some(&first), &point(1, 60, 200.0)));
+
+        // Ten points a day for 600 days: the oldest day survives, thinned
+        let hour = NANOS_PER_DAY as u64 / 24;
+        let mut points: Vec<EsgScorePoint> = (0..6_000u64)
+            .map(|i| EsgScorePoint { timestamp: i * 144 * hour / 60, ..point(0, (i % 100) as u8, i as f64) })
+            .collect();
+        downsample(&mut points);
+        assert!(points.len() <= MAX_POINTS_PER_PRODUCT);
+        assert_eq!(points[0].timestamp / NANOS_PER_DAY as u64, 0);
+        assert_eq!(points.last().unwrap().carbon_footprint_kg, 5_999.0);
+        assert!(points.windows(2).all(|w| w[0].timestamp < w[1].timestamp));
+    }
+}
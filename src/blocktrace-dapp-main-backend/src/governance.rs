@@ -0,0 +1,412 @@
+// 🛡️ Admin governance: admins come from init/upgrade arguments and are managed on-chain.
+// With an approval threshold above one, destructive operations go through an M-of-N flow:
+// propose_admin_action -> approve_admin_action (by other admins) -> execute_admin_action.
+use candid::{candid_method, CandidType, Principal};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+use crate::audit;
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::logs;
+
+// Admin compiled into earlier releases. Only used to seed governance when upgrading a canister
+// that predates it and no admins are passed in the upgrade arguments.
+const LEGACY_ADMIN_PRINCIPAL: &str = "4shqr-ynwgp-frjxc-kckbe-cutkz-wpigo-aa4wb-isbt3-lrqwp-x7xe3-jae";
+const PROPOSAL_TTL_NANOS: u64 = 7 * 86_400_000_000_000;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct InitArgs {
+    pub admins: Vec<Principal>,
+    pub approval_threshold: Option<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum AdminAction {
+    ClearAllData,
+    DeleteStepsByOwner { owner: String },
+    DeleteOrphanSteps,
+    AssignOrphanSteps { new_owner: String },
+    ReassignSteps { owner_from: String, owner_to: String },
+    AddAdmin { principal: Principal },
+    RemoveAdmin { principal: Principal },
+    SetApprovalThreshold { threshold: u8 },
+}
+
+impl AdminAction {
+    pub fn method_name(&self) -> &'static str {
+        match self {
+            AdminAction::ClearAllData => "clear_all_data",
+            AdminAction::DeleteStepsByOwner { .. } => "delete_steps_by_owner",
+            AdminAction::DeleteOrphanSteps => "delete_orphan_steps",
+            AdminAction::AssignOrphanSteps { .. } => "assign_orphan_steps",
+            AdminAction::ReassignSteps { .. } => "reassign_steps",
+            AdminAction::AddAdmin { .. } => "add_admin",
+            AdminAction::RemoveAdmin { .. } => "remove_admin",
+            AdminAction::SetApprovalThreshold { .. } => "set_approval_threshold",
+        }
+    }
+
+    pub fn arguments(&self) -> Vec<(&'static str, String)> {
+        match self {
+            AdminAction::ClearAllData | AdminAction::DeleteOrphanSteps => vec![],
+            AdminAction::DeleteStepsByOwner { owner } => vec![("owner", owner.clone())],
+            AdminAction::AssignOrphanSteps { new_owner } => vec![("new_owner", new_owner.clone())],
+            AdminAction::ReassignSteps { owner_from, owner_to } => {
+                vec![("owner_from", owner_from.clone()), ("owner_to", owner_to.clone())]
+            }
+            AdminAction::AddAdmin { principal } | AdminAction::RemoveAdmin { principal } => {
+                vec![("principal", principal.to_text())]
+            }
+            AdminAction::SetApprovalThreshold { threshold } => vec![("threshold", threshold.to_string())],
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum ProposalStatus {
+    Pending,
+    Executed { executed_at: u64, result: String },
+    Cancelled { cancelled_by: String },
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct AdminProposal {
+    pub id: u64,
+    pub action: AdminAction,
+    pub proposer: String,
+    pub approvals: Vec<String>,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub status: ProposalStatus,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct GovernanceInfo {
+    pub admins: Vec<String>,
+    pub approval_threshold: u8,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct GovernanceState {
+    pub admins: Vec<String>,
+    pub approval_threshold: u8,
+    pub proposals: Vec<AdminProposal>,
+}
+
+thread_local! {
+    static ADMINS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    static APPROVAL_THRESHOLD: RefCell<u8> = const { RefCell::new(1) };
+    static PROPOSALS: RefCell<Vec<AdminProposal>> = const { RefCell::new(Vec::new()) };
+}
+
+pub fn is_admin(principal: &str) -> bool {
+    ADMINS.with(|admins| admins.borrow().iter().any(|a| a == principal))
+}
+
+fn threshold() -> u8 {
+    APPROVAL_THRESHOLD.with(|t| *t.borrow())
+}
+
+fn admins() -> Vec<String> {
+    ADMINS.with(|admins| admins.borrow().clone())
+}
+
+fn caller_text() -> String {
+    format!("{}", ic_cdk::caller())
+}
+
+fn approval_required(method: &str) -> BlockTraceError {
+    BlockTraceError::ApprovalRequired {
+        method: method.to_string(),
+        threshold: threshold(),
+        admin_count: admins().len() as u32,
+    }
+}
+
+/// Guard for the direct (single-admin) admin endpoints: the caller must be an admin and the
+/// canister must not be configured for M-of-N approval.
+pub fn require_direct_admin(method: &str) -> ApiResult<()> {
+    if !is_admin(&caller_text()) {
+        return Err(BlockTraceError::unauthorized(format!("{} can only be called by an admin principal", method)));
+    }
+    if threshold() > 1 {
+        return Err(approval_required(method));
+    }
+    Ok(())
+}
+
+fn validate_admins(admins: &[String], threshold: u8) -> ApiResult<()> {
+    if admins.is_empty() {
+        return Err(BlockTraceError::invalid("admins", "at least one admin is required"));
+    }
+    if threshold == 0 || threshold as usize > admins.len() {
+        return Err(BlockTraceError::invalid("approval_threshold", format!("must be between 1 and {}", admins.len())));
+    }
+    Ok(())
+}
+
+fn configure(admins: Vec<String>, threshold: u8) {
+    ADMINS.with(|a| *a.borrow_mut() = admins);
+    APPROVAL_THRESHOLD.with(|t| *t.borrow_mut() = threshold);
+}
+
+fn configure_from_args(args: InitArgs) {
+    let mut list: Vec<String> = Vec::new();
+    for admin in args.admins.iter().map(|p| p.to_text()) {
+        if !list.contains(&admin) {
+            list.push(admin);
+        }
+    }
+    let threshold = args.approval_threshold.unwrap_or(1);
+    if let Err(e) = validate_admins(&list, threshold) {
+        ic_cdk::trap(&format!("Invalid governance arguments: {}", e));
+    }
+    configure(list, threshold);
+}
+
+pub(crate) fn init_governance(args: Option<InitArgs>) {
+    match args {
+        Some(args) if !args.admins.is_empty() => configure_from_args(args),
+        // Without explicit admins the deployer administers the canister
+        _ => configure(vec![caller_text()], 1),
+    }
+}
+
+/// Upgrade arguments replace the admin set, which lets controllers recover from lost admin keys.
+pub(crate) fn upgrade_governance(args: Option<InitArgs>) {
+    match args {
+        Some(args) if !args.admins.is_empty() => configure_from_args(args),
+        _ => {
+            if admins().is_empty() {
+                configure(vec![LEGACY_ADMIN_PRINCIPAL.to_string()], 1);
+            }
+        }
+    }
+}
+
+pub(crate) fn snapshot() -> GovernanceState {
+    GovernanceState {
+        admins: admins(),
+        approval_threshold: threshold(),
+        proposals: PROPOSALS.with(|p| p.borrow().clone()),
+    }
+}
+
+pub(crate) fn restore(state: GovernanceState) {
+    configure(state.admins, state.approval_threshold.max(1));
+    PROPOSALS.with(|p| *p.borrow_mut() = state.proposals);
+}
+
+// Governance changes made either directly or through an executed proposal.
+pub(crate) fn apply_governance_action(action: &AdminAction) -> ApiResult<String> {
+    let mut list = admins();
+    let mut new_threshold = threshold();
+    match action {
+        AdminAction::AddAdmin { principal } => {
+            let text = principal.to_text();
+            if list.contains(&text) {
+                return Err(BlockTraceError::conflict(format!("{} is already an admin", text)));
+            }
+            list.push(text);
+        }
+        AdminAction::RemoveAdmin { principal } => {
+            let text = principal.to_text();
+            if !list.contains(&text) {
+                return Err(BlockTraceError::not_found("Admin", text));
+            }
+            list.retain(|a| *a != text);
+            // Keep the threshold reachable when the admin set shrinks
+            new_threshold = new_threshold.min(list.len() as u8);
+        }
+        AdminAction::SetApprovalThreshold { threshold } => new_threshold = *threshold,
+        _ => return Err(BlockTraceError::invalid("action", "not a governance action")),
+    }
+    validate_admins(&list, new_threshold)?;
+    configure(list.clone(), new_threshold);
+    Ok(format!("Governance updated: {} admins, approval threshold {}", list.len(), new_threshold))
+}
+
+fn direct_governance_change(action: AdminAction) -> ApiResult<String> {
+    let caller = caller_text();
+    if !is_admin(&caller) {
+        return Err(BlockTraceError::unauthorized("Only an admin can manage admins"));
+    }
+    if threshold() > 1 {
+        return Err(approval_required(action.method_name()));
+    }
+    let result = apply_governance_action(&action)?;
+    audit::record(action.method_name(), action.arguments(), 0, 0);
+    Ok(result)
+}
+
+#[update]
+#[candid_method(update)]
+fn add_admin(principal: Principal) -> ApiResult<String> {
+    direct_governance_change(AdminAction::AddAdmin { principal })
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_admin(principal: Principal) -> ApiResult<String> {
+    direct_governance_change(AdminAction::RemoveAdmin { principal })
+}
+
+#[update]
+#[candid_method(update)]
+fn set_approval_threshold(threshold: u8) -> ApiResult<String> {
+    direct_governance_change(AdminAction::SetApprovalThreshold { threshold })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_governance_info() -> GovernanceInfo {
+    GovernanceInfo { admins: admins(), approval_threshold: threshold() }
+}
+
+fn with_pending_proposal<R>(id: u64, f: impl FnOnce(&mut AdminProposal) -> ApiResult<R>) -> ApiResult<R> {
+    PROPOSALS.with(|proposals| {
+        let mut proposals = proposals.borrow_mut();
+        let proposal = proposals.iter_mut().find(|p| p.id == id).ok_or_else(|| BlockTraceError::not_found("Proposal", id.to_string()))?;
+        if proposal.status != ProposalStatus::Pending {
+            return Err(BlockTraceError::conflict(format!("Proposal {} is no longer pending", id)));
+        }
+        if time() > proposal.expires_at {
+            return Err(BlockTraceError::conflict(format!("Proposal {} has expired", id)));
+        }
+        f(proposal)
+    })
+}
+
+#[update]
+#[candid_method(update)]
+fn propose_admin_action(action: AdminAction) -> ApiResult<AdminProposal> {
+    let caller = caller_text();
+    if !is_admin(&caller) {
+        return Err(BlockTraceError::unauthorized("Only an admin can propose admin actions"));
+    }
+    let now = time();
+    let proposal = PROPOSALS.with(|proposals| {
+        let mut proposals = proposals.borrow_mut();
+        let proposal = AdminProposal {
+            id: proposals.len() as u64,
+            action,
+            proposer: caller.clone(),
+            approvals: vec![caller],
+            created_at: now,
+            expires_at: now + PROPOSAL_TTL_NANOS,
+            status: ProposalStatus::Pending,
+        };
+        proposals.push(proposal.clone());
+        proposal
+    });
+    logs::info(
+        "governance",
+        "Admin proposal created",
+        vec![("id", proposal.id.to_string()), ("action", proposal.action.method_name().to_string()), ("proposer", proposal.proposer.clone())],
+    );
+    Ok(proposal)
+}
+
+#[update]
+#[candid_method(update)]
+fn approve_admin_action(proposal_id: u64) -> ApiResult<AdminProposal> {
+    let caller = caller_text();
+    if !is_admin(&caller) {
+        return Err(BlockTraceError::unauthorized("Only an admin can approve admin actions"));
+    }
+    with_pending_proposal(proposal_id, |proposal| {
+        if proposal.approvals.contains(&caller) {
+            return Err(BlockTraceError::conflict("You have already approved this proposal"));
+        }
+        proposal.approvals.push(caller.clone());
+        Ok(proposal.clone())
+    })
+}
+
+#[update]
+#[candid_method(update)]
+fn cancel_admin_action(proposal_id: u64) -> ApiResult<AdminProposal> {
+    let caller = caller_text();
+    if !is_admin(&caller) {
+        return Err(BlockTraceError::unauthorized("Only an admin can cancel admin actions"));
+    }
+    with_pending_proposal(proposal_id, |proposal| {
+        proposal.status = ProposalStatus::Cancelled { cancelled_by: caller.clone() };
+        Ok(proposal.clone())
+    })
+}
+
+// Executes once approvals from current admins reach the threshold. Approvals from principals
+// removed from the admin set in the meantime no longer count.
+#[update]
+#[candid_method(update)]
+fn execute_admin_action(proposal_id: u64) -> ApiResult<String> {
+    let caller = caller_text();
+    if !is_admin(&caller) {
+        return Err(BlockTraceError::unauthorized("Only an admin can execute admin actions"));
+    }
+    let proposal = with_pending_proposal(proposal_id, |proposal| Ok(proposal.clone()))?;
+    let valid_approvals: Vec<String> = proposal.approvals.iter().filter(|a| is_admin(a)).cloned().collect();
+    if valid_approvals.len() < threshold() as usize {
+        return Err(BlockTraceError::conflict(format!("Proposal {} has {} of {} required approvals", proposal_id, valid_approvals.len(), threshold())));
+    }
+
+    let extra = vec![
+        ("proposal_id", proposal_id.to_string()),
+        ("approvals", valid_approvals.join(",")),
+    ];
+    let result = match &proposal.action {
+        AdminAction::AddAdmin { .. } | AdminAction::RemoveAdmin { .. } | AdminAction::SetApprovalThreshold { .. } => {
+            let result = apply_governance_action(&proposal.action)?;
+            let mut arguments = proposal.action.arguments();
+            arguments.extend(extra);
+            audit::record(proposal.action.method_name(), arguments, 0, 0);
+            result
+        }
+        action => crate::run_admin_action(action, extra)?,
+    };
+
+    PROPOSALS.with(|proposals| {
+        if let Some(p) = proposals.borrow_mut().iter_mut().find(|p| p.id == proposal_id) {
+            p.status = ProposalStatus::Executed { executed_at: time(), result: result.clone() };
+        }
+    });
+    Ok(result)
+}
+
+#[query]
+#[candid_method(query)]
+fn list_admin_proposals(include_closed: bool) -> Vec<AdminProposal> {
+    let now = time();
+    PROPOSALS.with(|proposals| {
+        proposals
+            .borrow()
+            .iter()
+            .filter(|p| include_closed || (p.status == ProposalStatus::Pending && now <= p.expires_at))
+            .cloned()
+            .collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn governance_changes_keep_threshold_reachable() {
+        let a = Principal::from_slice(&[1]);
+        let b = Principal::from_slice(&[2]);
+        configure(vec![a.to_text()], 1);
+
+        apply_governance_action(&AdminAction::AddAdmin { principal: b }).unwrap();
+        apply_governance_action(&AdminAction::SetApprovalThreshold { threshold: 2 }).unwrap();
+        assert!(apply_governance_action(&AdminAction::SetApprovalThreshold { threshold: 3 }).is_err());
+
+        apply_governance_action(&AdminAction::RemoveAdmin { principal: b }).unwrap();
+        assert_eq!(threshold(), 1);
+        assert!(is_admin(&a.to_text()) && !is_admin(&b.to_text()));
+        assert!(apply_governance_action(&AdminAction::RemoveAdmin { principal: a }).is_err());
+    }
+}
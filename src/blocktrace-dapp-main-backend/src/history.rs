@@ -0,0 +1,461 @@
+// 🕰️ Point-in-time views of product histories.
+//
+// Steps are never edited in place by their owners: a correction is appended with its own
+// timestamp, so any query can be answered "as of" a moment, ignoring everything that was
+// recorded (or corrected) afterwards.
+use candid::{candid_method, CandidType};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::custody;
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::logs;
+use crate::retention;
+use crate::{authenticated_caller, Step, PRODUCT_HISTORY};
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct StepCorrection {
+    pub product_id: String,
+    pub sequence: u64,
+    pub corrected_step: Step,
+    pub reason: String,
+    pub corrected_by: String,
+    pub corrected_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum LifecycleStage {
+    Produced,
+    Stored,
+    InTransit,
+    Received,
+    Delivered,
+    Sold,
+    Recalled,
+    Recorded,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ProductState {
+    pub product_id: String,
+    pub as_of: Option<u64>,
+    pub custodian: String,
+    pub custodian_name: String,
+    pub location: String,
+    pub last_action: String,
+    pub lifecycle_stage: LifecycleStage,
+    pub step_count: u32,
+    pub first_recorded: u64,
+    pub last_updated: u64,
+    pub open_disputes: u32,
+}
+
+// Latest stored step of a product, used by writers for optimistic concurrency checks.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct ProductHead {
+    pub product_id: String,
+    pub last_sequence: u64,
+    pub last_step_hash: String,
+    pub last_updated: u64,
+}
+
+thread_local! {
+    static STEP_CORRECTIONS: RefCell<HashMap<String, Vec<StepCorrection>>> = RefCell::new(HashMap::new());
+    // Creator of each product, pinned when its first step is written so that ownership does not
+    // pass to the next author once that step is archived or tombstoned
+    static PRODUCT_OWNERS: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+/// Next sequence number for a product. Sequences identify a step within its product history.
+pub fn next_sequence(steps: &[Step]) -> u64 {
+    steps.iter().filter_map(|s| s.sequence).max().map(|s| s + 1).unwrap_or(0)
+}
+
+/// SHA-256 (hex) of the step's candid encoding as stored, so sealed fields stay sealed.
+pub fn step_hash(step: &Step) -> String {
+    hex::encode(Sha256::digest(candid::encode_one(step).unwrap_or_default()))
+}
+
+fn head_of<'a>(product_id: &str, steps: impl IntoIterator<Item = &'a Step>) -> Option<ProductHead> {
+    steps.into_iter().max_by_key(|s| (s.sequence, s.timestamp)).map(|last| ProductHead {
+        product_id: product_id.to_string(),
+        last_sequence: last.sequence.unwrap_or_default(),
+        last_step_hash: step_hash(last),
+        last_updated: last.timestamp,
+    })
+}
+
+/// Latest step a product's ledger has ever held: live steps and retired (archived or
+/// tombstoned) ones, whose sequences are never reused.
+pub fn ledger_head(product_id: &str, live: &[Step], retired: &[Step]) -> Option<ProductHead> {
+    head_of(product_id, live.iter().chain(retired))
+}
+
+/// Rejects a write made against a stale view of the product: the caller's expected last
+/// sequence and/or step hash must still describe the latest step of the ledger, retired steps
+/// included. An empty expected hash (with no expected sequence) states that the product has no
+/// steps yet, which is the only expectation a product without any step satisfies.
+pub fn check_expected_head(
+    product_id: &str,
+    live: &[Step],
+    retired: &[Step],
+    expected_sequence: Option<u64>,
+    expected_hash: Option<&str>,
+) -> ApiResult<()> {
+    if expected_sequence.is_none() && expected_hash.is_none() {
+        return Ok(());
+    }
+    let head = ledger_head(product_id, live, retired);
+    let expects_no_head = expected_hash.is_some_and(|e| e.trim().is_empty());
+    let matches = match &head {
+        None => expects_no_head && expected_sequence.is_none(),
+        Some(_) if expects_no_head => false,
+        Some(h) => {
+            expected_sequence.is_none_or(|s| h.last_sequence == s)
+                && expected_hash.is_none_or(|e| h.last_step_hash.eq_ignore_ascii_case(e.trim()))
+        }
+    };
+    if matches {
+        return Ok(());
+    }
+    Err(BlockTraceError::StaleHistory {
+        product_id: product_id.to_string(),
+        last_sequence: head.as_ref().map(|h| h.last_sequence),
+        last_step_hash: head.map(|h| h.last_step_hash),
+    })
+}
+
+/// Assign sequence numbers to steps restored from versions that did not record them.
+pub fn backfill_sequences(history: &mut HashMap<String, Vec<Step>>) {
+    for steps in history.values_mut() {
+        if steps.iter().all(|s| s.sequence.is_some()) {
+            continue;
+        }
+        let next = next_sequence(steps);
+        let mut missing: Vec<&mut Step> = steps.iter_mut().filter(|s| s.sequence.is_none()).collect();
+        missing.sort_by_key(|s| s.timestamp);
+        for (offset, step) in missing.into_iter().enumerate() {
+            step.sequence = Some(next + offset as u64);
+        }
+    }
+}
+
+/// Apply the corrections visible at `as_of` to the steps recorded up to `as_of`.
+pub fn apply_as_of(steps: Vec<Step>, corrections: &[StepCorrection], as_of: Option<u64>) -> Vec<Step> {
+    let cutoff = as_of.unwrap_or(u64::MAX);
+    let mut history: Vec<Step> = steps
+        .into_iter()
+        .filter(|step| step.timestamp <= cutoff)
+        .map(|step| {
+            corrections
+                .iter()
+                .filter(|c| Some(c.sequence) == step.sequence && c.corrected_at <= cutoff)
+                .max_by_key(|c| c.corrected_at)
+                .map(|c| c.corrected_step.clone())
+                .unwrap_or(step)
+        })
+        .collect();
+    history.sort_by_key(|step| step.timestamp);
+    history
+}
+
+/// Every step of a product as the ledger showed it at `as_of` (or now), across all owners,
+/// including steps since archived or deleted.
+pub fn history_as_of(product_id: &str, as_of: Option<u64>) -> Vec<Step> {
+    let mut steps = PRODUCT_HISTORY.with(|store| store.borrow().get(product_id).cloned().unwrap_or_default());
+    steps.extend(retention::retired_steps(product_id, as_of));
+    STEP_CORRECTIONS.with(|store| {
+        let corrections = store.borrow();
+        apply_as_of(steps, corrections.get(product_id).map(|v| v.as_slice()).unwrap_or(&[]), as_of)
+    })
+}
+
+/// Whether `principal` is a party to the product: its owner, or a custodian through an accepted
+/// handover. Anyone can append a step to a product, so authoring other steps does not count.
+pub fn is_involved(product_id: &str, principal: &str) -> bool {
+    if principal.trim().is_empty() {
+        return false;
+    }
+    product_owner(product_id).as_deref() == Some(principal)
+        || history_as_of(product_id, None).iter().any(|s| s.user_id == principal && custody::is_handover_step(s))
+}
+
+/// Principal that recorded the first step of a product.
+pub fn product_owner(product_id: &str) -> Option<String> {
+    PRODUCT_OWNERS.with(|owners| owners.borrow().get(product_id).cloned()).or_else(|| first_author(product_id))
+}
+
+// Author of the earliest step on the ledger, archived and tombstoned steps included. Covers
+// products recorded before owners were pinned.
+fn first_author(product_id: &str) -> Option<String> {
+    let mut steps = PRODUCT_HISTORY.with(|store| store.borrow().get(product_id).cloned().unwrap_or_default());
+    steps.extend(retention::all_retired_steps(product_id));
+    steps.into_iter().min_by_key(|s| (s.sequence, s.timestamp)).map(|s| s.user_id)
+}
+
+/// Pins the owner of a product that has none recorded yet. Called after each step is appended.
+pub(crate) fn record_owner(product_id: &str) {
+    if PRODUCT_OWNERS.with(|owners| owners.borrow().contains_key(product_id)) {
+        return;
+    }
+    if let Some(owner) = first_author(product_id) {
+        PRODUCT_OWNERS.with(|owners| owners.borrow_mut().insert(product_id.to_string(), owner));
+    }
+}
+
+/// Pins the owners of restored products that predate stored owners.
+pub(crate) fn backfill_owners() {
+    let products: Vec<String> = PRODUCT_HISTORY.with(|store| store.borrow().keys().cloned().collect());
+    for product_id in products {
+        record_owner(&product_id);
+    }
+}
+
+/// Moves product ownership along with an admin reassignment of steps.
+pub(crate) fn reassign_owner(owner_from: &str, owner_to: &str) {
+    PRODUCT_OWNERS.with(|owners| {
+        for owner in owners.borrow_mut().values_mut().filter(|owner| owner.as_str() == owner_from) {
+            *owner = owner_to.to_string();
+        }
+    });
+}
+
+pub fn lifecycle_stage(step: &Step) -> LifecycleStage {
+    let text = format!("{} {}", step.action, step.status.clone().unwrap_or_default()).to_lowercase();
+    let matches = |keywords: &[&str]| keywords.iter().any(|k| text.contains(k));
+    if matches(&["recall"]) {
+        LifecycleStage::Recalled
+    } else if matches(&["sold", "sale", "purchase"]) {
+        LifecycleStage::Sold
+    } else if matches(&["deliver"]) {
+        LifecycleStage::Delivered
+    } else if matches(&["receiv", "arriv"]) {
+        LifecycleStage::Received
+    } else if matches(&["ship", "transit", "transport", "dispatch"]) {
+        LifecycleStage::InTransit
+    } else if matches(&["stor", "warehouse"]) {
+        LifecycleStage::Stored
+    } else if matches(&["produc", "manufactur", "assembl", "harvest", "process", "packag"]) {
+        LifecycleStage::Produced
+    } else {
+        LifecycleStage::Recorded
+    }
+}
+
+/// Step that made its author the custodian: the latest accepted custody handover, or the first
+/// step while the product has never changed hands.
+pub fn custody_step(history: &[Step]) -> Option<&Step> {
+    history.iter().rev().find(|s| custody::is_handover_step(s)).or(history.first())
+}
+
+pub fn product_state(product_id: &str, as_of: Option<u64>) -> Option<ProductState> {
+    let history = history_as_of(product_id, as_of);
+    let first = history.first()?;
+    let last = history.last()?;
+    let custody = custody_step(&history)?;
+    Some(ProductState {
+        product_id: product_id.to_string(),
+        as_of,
+        custodian: custody.user_id.clone(),
+        custodian_name: custody.actor_name.clone(),
+        location: last.location.clone(),
+        last_action: last.action.clone(),
+        lifecycle_stage: lifecycle_stage(last),
+        step_count: history.len() as u32,
+        first_recorded: first.timestamp,
+        last_updated: last.timestamp,
+        open_disputes: crate::disputes::open_dispute_count(product_id, as_of),
+    })
+}
+
+pub(crate) fn snapshot() -> HashMap<String, Vec<StepCorrection>> {
+    STEP_CORRECTIONS.with(|store| store.borrow().clone())
+}
+
+pub(crate) fn restore(corrections: HashMap<String, Vec<StepCorrection>>) {
+    STEP_CORRECTIONS.with(|store| *store.borrow_mut() = corrections);
+}
+
+pub(crate) fn owners_snapshot() -> HashMap<String, String> {
+    PRODUCT_OWNERS.with(|owners| owners.borrow().clone())
+}
+
+pub(crate) fn restore_owners(owners: HashMap<String, String>) {
+    PRODUCT_OWNERS.with(|store| *store.borrow_mut() = owners);
+}
+
+pub(crate) fn clear() {
+    STEP_CORRECTIONS.with(|store| store.borrow_mut().clear());
+    PRODUCT_OWNERS.with(|owners| owners.borrow_mut().clear());
+}
+
+// Record a correction to one of the caller's own steps. The original step stays untouched so
+// that earlier "as of" answers remain reproducible.
+#[update]
+#[candid_method(update)]
+fn correct_step(product_id: String, sequence: u64, corrected: Step, reason: String, caller_principal: String) -> ApiResult<StepCorrection> {
+    let actual_caller = authenticated_caller(&caller_principal)?;
+    if reason.trim().is_empty() {
+        return Err(BlockTraceError::invalid("reason", "a correction reason is required"));
+    }
+
+    let original = PRODUCT_HISTORY.with(|store| {
+        store
+            .borrow()
+            .get(&product_id)
+            .and_then(|steps| steps.iter().find(|s| s.sequence == Some(sequence)).cloned())
+    })
+    .ok_or_else(|| BlockTraceError::not_found("Step", format!("{}/{}", product_id, sequence)))?;
+
+    if original.user_id != actual_caller {
+        return Err(BlockTraceError::unauthorized("Only the owner of a step can correct it"));
+    }
+    if custody::is_handover_step(&corrected) != custody::is_handover_step(&original) {
+        return Err(BlockTraceError::invalid("status", "corrections cannot add or remove a custody handover"));
+    }
+
+    let mut corrected_step = Step {
+        user_id: original.user_id,
+        product_id: original.product_id,
+        timestamp: original.timestamp,
+        sequence: original.sequence,
+        ..corrected
+    };
+    crate::validation::validate_step(&corrected_step, corrected_step.timestamp)?;
+    crate::encryption::seal_step(&mut corrected_step)?;
+
+    let correction = StepCorrection {
+        product_id: product_id.clone(),
+        sequence,
+        corrected_step,
+        reason,
+        corrected_by: actual_caller,
+        corrected_at: time(),
+    };
+    STEP_CORRECTIONS.with(|store| {
+        store.borrow_mut().entry(product_id.clone()).or_default().push(correction.clone());
+    });
+
+    logs::info(
+        "history",
+        "Step corrected",
+        vec![("product_id", product_id), ("sequence", sequence.to_string()), ("corrected_by", correction.corrected_by.clone())],
+    );
+    Ok(correction)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_step_corrections(product_id: String, caller_principal: String) -> Vec<StepCorrection> {
+    STEP_CORRECTIONS.with(|store| {
+        store
+            .borrow()
+            .get(&product_id)
+            .map(|list| list.iter().filter(|c| c.corrected_step.user_id == caller_principal).cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+// Current custodian and lifecycle stage, optionally as the ledger showed them at `as_of`.
+// Only parties to the product can see its state.
+#[query]
+#[candid_method(query)]
+fn get_product_state(product_id: String, caller_principal: String, as_of: Option<u64>) -> Option<ProductState> {
+    let caller = authenticated_caller(&caller_principal).ok()?;
+    if !is_involved(&product_id, &caller) {
+        return None;
+    }
+    product_state(&product_id, as_of)
+}
+
+// Lets a writer learn what to pass as `expected_last_sequence` / `expected_last_step_hash`.
+#[query]
+#[candid_method(query)]
+fn get_product_head(product_id: String, caller_principal: String) -> Option<ProductHead> {
+    let caller = authenticated_caller(&caller_principal).ok()?;
+    if !is_involved(&product_id, &caller) {
+        return None;
+    }
+    let live = PRODUCT_HISTORY.with(|store| store.borrow().get(&product_id).cloned().unwrap_or_default());
+    ledger_head(&product_id, &live, &retention::all_retired_steps(&product_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_step;
+
+    #[test]
+    fn as_of_ignores_later_steps_and_later_corrections() {
+        let mut steps = vec![test_step("P1", "alice", 100), test_step("P1", "alice", 200)];
+        let mut history = HashMap::from([("P1".to_string(), steps.clone())]);
+        backfill_sequences(&mut history);
+        steps = history.remove("P1").unwrap();
+
+        let mut corrected = steps[0].clone();
+        corrected.location = "Factory B".to_string();
+        let corrections = vec![StepCorrection {
+            product_id: "P1".to_string(),
+            sequence: 0,
+            corrected_step: corrected,
+            reason: "wrong site".to_string(),
+            corrected_by: "alice".to_string(),
+            corrected_at: 300,
+        }];
+
+        let before = apply_as_of(steps.clone(), &corrections, Some(150));
+        assert_eq!(before.len(), 1);
+        assert_eq!(before[0].location, "Factory A");
+
+        let now = apply_as_of(steps, &corrections, None);
+        assert_eq!(now.len(), 2);
+        assert_eq!(now[0].location, "Factory B");
+        assert_eq!(now[1].sequence, Some(1));
+    }
+
+    #[test]
+    fn custody_moves_only_through_confirmed_handovers() {
+        let mut steps = vec![test_step("P1", "maker", 1), test_step("P1", "intruder", 2)];
+        assert_eq!(custody_step(&steps).map(|s| s.user_id.as_str()), Some("maker"));
+        let mut handover = test_step("P1", "warehouse", 3);
+        handover.status = Some(custody::HANDOVER_STATUS.to_string());
+        steps.extend([handover, test_step("P1", "intruder", 4)]);
+        assert_eq!(custody_step(&steps).map(|s| s.user_id.as_str()), Some("warehouse"));
+    }
+
+    #[test]
+    fn stale_expectations_are_rejected_with_the_current_head() {
+        let mut steps = vec![test_step("P1", "alice", 100), test_step("P1", "bob", 200)];
+        steps[0].sequence = Some(0);
+        steps[1].sequence = Some(1);
+        let hash = step_hash(&steps[1]);
+
+        assert!(check_expected_head("P1", &steps, &[], None, None).is_ok());
+        assert!(check_expected_head("P1", &steps, &[], Some(1), Some(&hash.to_uppercase())).is_ok());
+        match check_expected_head("P1", &steps, &[], Some(0), None) {
+            Err(BlockTraceError::StaleHistory { last_sequence, last_step_hash, .. }) => {
+                assert_eq!(last_sequence, Some(1));
+                assert_eq!(last_step_hash, Some(hash.clone()));
+            }
+            other => panic!("expected StaleHistory, got {:?}", other),
+        }
+        assert!(check_expected_head("P1", &steps, &[], None, Some("")).is_err());
+
+        // A product without steps only accepts the "no steps yet" expectation
+        assert!(check_expected_head("P2", &[], &[], None, Some("")).is_ok());
+        assert!(check_expected_head("P2", &[], &[], Some(0), None).is_err());
+        assert!(check_expected_head("P2", &[], &[], Some(0), Some("")).is_err());
+        assert!(check_expected_head("P2", &[], &[], None, Some(&hash)).is_err());
+
+        // Retired steps still count once the live history no longer holds the head
+        assert!(check_expected_head("P1", &[], &steps, None, Some("")).is_err());
+        assert!(check_expected_head("P1", &[], &steps, Some(0), None).is_err());
+        assert!(check_expected_head("P1", &[], &steps, Some(1), Some(&hash)).is_ok());
+        assert!(check_expected_head("P1", &steps[..1], &steps[1..], Some(0), None).is_err());
+        assert!(check_expected_head("P1", &steps[..1], &steps[1..], Some(1), None).is_ok());
+    }
+}
@@ -0,0 +1,101 @@
+// 🔁 Idempotency keys for step submission.
+//
+// Scanners on flaky connections retry `add_step` with the same client-generated key; within the
+// window the original result is returned instead of appending the step again. Keys are scoped
+// to the submitting principal and only successful submissions are remembered, so a rejected
+// request can be retried with the same key.
+use candid::CandidType;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::errors::{ApiResult, BlockTraceError};
+
+pub const IDEMPOTENCY_WINDOW_NANOS: u64 = 24 * 3_600 * 1_000_000_000;
+const MAX_KEY_LENGTH: usize = 128;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct IdempotencyRecord {
+    pub product_id: String,
+    pub sequence: Option<u64>,
+    pub result: String,
+    pub recorded_at: u64,
+}
+
+thread_local! {
+    static RECORDS: RefCell<HashMap<String, IdempotencyRecord>> = RefCell::new(HashMap::new());
+}
+
+fn scoped_key(principal: &str, key: &str) -> String {
+    format!("{}/{}", principal, key)
+}
+
+pub fn check_key(key: &str) -> ApiResult<()> {
+    if key.trim().is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(BlockTraceError::invalid(
+            "idempotency_key",
+            format!("must be 1 to {} bytes", MAX_KEY_LENGTH),
+        ));
+    }
+    Ok(())
+}
+
+/// The result recorded for `key` within the window, if any. Reusing a key for another product
+/// is a client bug and is rejected rather than replayed.
+pub fn replay(principal: &str, key: &str, product_id: &str, now: u64) -> ApiResult<Option<String>> {
+    let record = RECORDS.with(|r| r.borrow().get(&scoped_key(principal, key)).cloned());
+    match record {
+        Some(record) if now.saturating_sub(record.recorded_at) < IDEMPOTENCY_WINDOW_NANOS => {
+            if record.product_id != product_id {
+                return Err(BlockTraceError::conflict(format!(
+                    "Idempotency key {} was already used for product {}",
+                    key, record.product_id
+                )));
+            }
+            Ok(Some(record.result))
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn remember(principal: &str, key: &str, record: IdempotencyRecord) {
+    RECORDS.with(|r| {
+        let mut records = r.borrow_mut();
+        let now = record.recorded_at;
+        records.retain(|_, existing| now.saturating_sub(existing.recorded_at) < IDEMPOTENCY_WINDOW_NANOS);
+        records.insert(scoped_key(principal, key), record);
+    });
+}
+
+pub(crate) fn snapshot() -> HashMap<String, IdempotencyRecord> {
+    RECORDS.with(|r| r.borrow().clone())
+}
+
+pub(crate) fn restore(records: HashMap<String, IdempotencyRecord>) {
+    RECORDS.with(|r| *r.borrow_mut() = records);
+}
+
+pub(crate) fn clear() {
+    RECORDS.with(|r| r.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_within_window_per_principal() {
+        let record = IdempotencyRecord {
+            product_id: "P1".to_string(),
+            sequence: Some(3),
+            result: "added".to_string(),
+            recorded_at: 1_000,
+        };
+        remember("alice", "scan-42", record);
+        assert_eq!(replay("alice", "scan-42", "P1", 2_000).unwrap(), Some("added".to_string()));
+        assert_eq!(replay("bob", "scan-42", "P1", 2_000).unwrap(), None);
+        assert!(replay("alice", "scan-42", "P2", 2_000).is_err());
+        assert_eq!(replay("alice", "scan-42", "P1", 1_000 + IDEMPOTENCY_WINDOW_NANOS).unwrap(), None);
+        assert!(check_key("").is_err());
+    }
+}
@@ -1,14 +1,66 @@
 use ic_cdk::api::{time, management_canister::http_request::{HttpResponse, TransformArgs, http_request, CanisterHttpRequestArgument, HttpMethod, TransformContext, HttpHeader}};
 use ic_cdk_macros::{query, update, init, pre_upgrade, post_upgrade};
 use ic_cdk_timers::{set_timer_interval, TimerId};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::cell::RefCell;
 use std::time::Duration;
-use candid::{CandidType, candid_method};
+use candid::{CandidType, Principal, candid_method};
 use serde::{Deserialize, Serialize};
 use sha2::{Sha256, Digest};
 use hex;
 
+mod anomalies;
+mod audit;
+mod benchmarks;
+mod certificates;
+mod custody;
+mod disputes;
+mod emissions;
+mod encryption;
+mod errors;
+mod esg_history;
+mod governance;
+mod history;
+mod idempotency;
+mod logs;
+mod memory;
+mod methodology;
+mod metrics;
+mod organizations;
+mod passport;
+mod public_view;
+mod quotas;
+mod retention;
+mod scopes;
+mod serials;
+mod validation;
+mod webhooks;
+
+use anomalies::{Anomaly, AnomalySeverity, AnomalyState};
+use audit::{AuditEntry, AuditLogPage};
+use benchmarks::{EsgBenchmark, PeerEntry, PeerGroup};
+use certificates::{Certificate, CertificateInput, CertificateStatus, CertificateSubject, StepCertificateFlag, SupplierCertificateCheck};
+use custody::{CustodyState, HandoverAcceptance, HandoverProposal, HandoverSubject};
+use disputes::{Dispute, DisputeCategory, DisputeOutcome, DisputeState};
+use emissions::{EmissionFactor, EmissionFactorInput, EmissionFactorRef, EmissionFactorRegistry};
+use encryption::{EncryptedStepFields, EncryptionState, KeyDerivationMetadata, StepEncryption};
+use errors::{ApiResult, BlockTraceError};
+use esg_history::{EsgScorePoint, EsgScoreSeries};
+use governance::{AdminAction, AdminProposal, GovernanceInfo, GovernanceState, InitArgs};
+use history::{ProductHead, ProductState, StepCorrection};
+use idempotency::IdempotencyRecord;
+use logs::{LogEntry, LogFilter, LogLevel};
+use methodology::{ComponentScore, EsgMethodology, EsgMethodologyInput, MethodologyState};
+use metrics::{CanisterStats, Counter, MetricCounters};
+use passport::{HttpGatewayResponse, HttpRequest};
+use public_view::{DisclosureSettings, PublicProductView};
+use quotas::{QuotaConfig, QuotaOperation, QuotaState, QuotaUsageReport};
+use retention::{ArchivedStep, RetentionPolicy, RetentionRunReport, RetentionState, Tombstone};
+use scopes::{EmissionsBreakdown, OrganizationEmissions, ScopeTotals};
+use serials::{SerialRecord, SerialState, SerialStatus, SerialVerification};
+use validation::ValidationLimits;
+use webhooks::{WebhookAlert, WebhookDeliveryRecord, WebhookFilter, WebhookState, WebhookSubscription};
+
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 pub struct Step {
     pub user_id: String,
@@ -22,6 +74,12 @@ pub struct Step {
     pub status: Option<String>,
     // Enhanced blockchain supply chain fields
     pub transport_mode: Option<String>,
+    pub vehicle_class: Option<String>,
+    pub fuel_type: Option<String>,
+    // Cargo weight, used for tonne-km emission factors
+    pub load_tonnes: Option<f64>,
+    // Whether the recording organization operates the vehicle; inferred from the role when unset
+    pub transport_owned: Option<bool>,
     pub temperature_celsius: Option<f64>,
     pub humidity_percent: Option<f64>,
     pub gps_latitude: Option<f64>,
@@ -35,6 +93,12 @@ pub struct Step {
     pub distance_km: Option<f64>,
     pub cost_usd: Option<f64>,
     pub blockchain_hash: Option<String>,
+    // Unit serials issued for the step's batch that this step applies to
+    pub serials: Option<Vec<String>>,
+    // Position of the step within its product history, assigned by the canister
+    pub sequence: Option<u64>,
+    // Ciphertext of fields encrypted client-side with the organization's key
+    pub encryption: Option<StepEncryption>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
@@ -46,12 +110,29 @@ pub struct ESGScore {
     pub total_steps: u32,
     pub impact_message: String,
     pub co2_saved_vs_traditional: f64,
+    // Registry factor versions behind the carbon figure
+    pub emission_factors_used: Vec<EmissionFactorRef>,
+    // Step emissions by scope, relative to the product owner's organization
+    pub scope_breakdown: ScopeTotals,
+    pub methodology_version: u32,
+    // Points each methodology component contributed to sustainability_score
+    pub components: Vec<ComponentScore>,
 }
 
 #[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
 pub enum AddStepResult {
     Ok(String),
-    Err(String),
+    Err(BlockTraceError),
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+pub struct AddStepOptions {
+    // Client-generated; a retry with the same key returns the original result
+    pub idempotency_key: Option<String>,
+    // Optimistic concurrency: reject the write unless the product's latest step still matches.
+    // An empty expected_last_step_hash means "the product has no steps yet" (create only).
+    pub expected_last_sequence: Option<u64>,
+    pub expected_last_step_hash: Option<String>,
 }
 
 // Advanced ICP Features Structs
@@ -95,102 +176,239 @@ thread_local! {
     static PRODUCT_HISTORY: RefCell<HashMap<String, Vec<Step>>> = RefCell::new(HashMap::new());
     static SUPPLIER_VERIFICATIONS: RefCell<HashMap<String, SupplierVerification>> = RefCell::new(HashMap::new());
     static CROSS_CHAIN_PROOFS: RefCell<HashMap<String, CrossChainProof>> = RefCell::new(HashMap::new());
-    static ESG_TIMERS: RefCell<HashMap<String, TimerId>> = RefCell::new(HashMap::new());
+    // Per-product recalculation timers and the principal that scheduled each of them
+    static ESG_TIMERS: RefCell<HashMap<String, (TimerId, String)>> = RefCell::new(HashMap::new());
+    static GLOBAL_ESG_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
     static HTTP_OUTCALL_CACHE: RefCell<HashMap<String, (HttpOutcallResponse, u64)>> = RefCell::new(HashMap::new());
     static AUTOMATED_ESG_UPDATES: RefCell<Vec<AutomatedESGUpdate>> = RefCell::new(Vec::new());
     static ECDSA_PUBLIC_KEY: RefCell<Option<Vec<u8>>> = RefCell::new(None);
 }
 
-#[update]
-#[candid_method(update)]
-fn add_step(mut step: Step, caller_principal: String) -> AddStepResult {
-    // If the frontend didn't supply the caller principal, fall back to the actual caller.
-    let actual_caller = if caller_principal.trim().is_empty() {
+// Principal named by the frontend, falling back to the actual caller when none was supplied.
+pub(crate) fn resolve_caller(caller_principal: String) -> String {
+    if caller_principal.trim().is_empty() {
         format!("{}", ic_cdk::caller())
     } else {
-        caller_principal.clone()
-    };
-    step.user_id = actual_caller;
-    if step.product_id.trim().is_empty() {
-        return AddStepResult::Err("Product ID cannot be empty".to_string());
-    }
-    if step.actor_name.trim().is_empty() {
-        return AddStepResult::Err("Actor name cannot be empty".to_string());
-    }
-    if step.role.trim().is_empty() {
-        return AddStepResult::Err("Role cannot be empty".to_string());
+        caller_principal
     }
-    if step.action.trim().is_empty() {
-        return AddStepResult::Err("Action cannot be empty".to_string());
-    }
-    if step.location.trim().is_empty() {
-        return AddStepResult::Err("Location cannot be empty".to_string());
+}
+
+// The actual caller, for authorization decisions. `caller_principal` is kept in signatures for
+// frontend compatibility but may only name the caller itself.
+pub(crate) fn authenticated_caller(caller_principal: &str) -> ApiResult<String> {
+    let caller = format!("{}", ic_cdk::caller());
+    if !caller_principal.trim().is_empty() && caller_principal.trim() != caller {
+        return Err(BlockTraceError::unauthorized("caller_principal does not match the calling principal"));
     }
+    Ok(caller)
+}
+
+// Validates, seals and appends a step on behalf of `step.user_id`, then notifies subscribers.
+// Shared by add_step and protocol flows (such as custody handovers) that record steps.
+pub(crate) fn append_step(step: Step, now: u64, options: &AddStepOptions) -> ApiResult<Step> {
+    commit_step(prepare_step(step, now)?, options)
+}
+
+// Everything that can reject a step short of the head expectations: validation, serial checks and
+// sealing. Flows that record several steps prepare all of them before committing any.
+pub(crate) fn prepare_step(mut step: Step, now: u64) -> ApiResult<Step> {
+    validation::validate_step(&step, now)?;
+    serials::check_step_serials(&step)?;
+    encryption::seal_step(&mut step)?;
     if step.status.is_none() || step.status.as_ref().unwrap().trim().is_empty() {
         step.status = Some("verified".to_string());
     }
-    step.timestamp = time();
+    step.timestamp = now;
     if let Some(ref notes) = step.notes {
         if notes.trim().is_empty() {
             step.notes = None;
         }
     }
-    ic_cdk::println!("Adding enhanced step: {:?}", step);
-    PRODUCT_HISTORY.with(|store| {
-        store
-            .borrow_mut()
-            .entry(step.product_id.clone())
-            .or_default()
-            .push(step.clone());
-    });
-    ic_cdk::println!("Enhanced step added successfully for product: {}", step.product_id);
-    AddStepResult::Ok(format!("Enhanced step added successfully for product {}", step.product_id))
+    Ok(step)
 }
 
-// Admin principal allowed to run destructive migrations on-chain. Change if you want a different admin.
-const ADMIN_PRINCIPAL: &str = "4shqr-ynwgp-frjxc-kckbe-cutkz-wpigo-aa4wb-isbt3-lrqwp-x7xe3-jae";
+// Stores a step returned by prepare_step. Without head expectations in `options` this cannot fail.
+pub(crate) fn commit_step(mut step: Step, options: &AddStepOptions) -> ApiResult<Step> {
+    let retired = retention::all_retired_steps(&step.product_id);
+    let mut findings = PRODUCT_HISTORY.with(|store| -> ApiResult<Vec<anomalies::Finding>> {
+        let mut map = store.borrow_mut();
+        history::check_expected_head(
+            &step.product_id,
+            map.get(&step.product_id).map(|s| s.as_slice()).unwrap_or_default(),
+            &retired,
+            options.expected_last_sequence,
+            options.expected_last_step_hash.as_deref(),
+        )?;
+        let steps = map.entry(step.product_id.clone()).or_default();
+        step.sequence = Some(history::next_sequence(steps).max(retention::sequence_floor(&step.product_id)));
+        let findings = anomalies::detect(steps, &step);
+        steps.push(step.clone());
+        Ok(findings)
+    })?;
+    history::record_owner(&step.product_id);
+    logs::info(
+        "steps",
+        "Step added",
+        vec![
+            ("product_id", step.product_id.clone()),
+            ("sequence", step.sequence.unwrap_or_default().to_string()),
+            ("user_id", step.user_id.clone()),
+            ("action", step.action.clone()),
+        ],
+    );
+
+    webhooks::notify_step_added(&step);
+    findings.extend(serials::record_sightings(&step));
+    anomalies::record(&step, findings);
+    if let Some(hash) = step.certification_hash.as_ref().filter(|h| !h.trim().is_empty()) {
+        let status = certificates::certificate_status(hash, step.timestamp);
+        if status != CertificateStatus::Valid {
+            webhooks::notify_alert(WebhookAlert {
+                alert_type: "certificate_flag".to_string(),
+                product_id: step.product_id.clone(),
+                batch_number: step.batch_number.clone(),
+                message: format!("Step recorded under certificate {} with status {:?}", hash, status),
+                timestamp: step.timestamp,
+            });
+        }
+    }
+    Ok(step)
+}
 
 #[update]
-fn assign_orphan_steps(new_owner: String) -> String {
-    let caller = format!("{}", ic_cdk::caller());
-    if caller != ADMIN_PRINCIPAL {
-        ic_cdk::trap("assign_orphan_steps can only be called by the admin principal");
+#[candid_method(update)]
+fn add_step(mut step: Step, caller_principal: String, options: Option<AddStepOptions>) -> AddStepResult {
+    let options = options.unwrap_or_default();
+    let actual_caller = match authenticated_caller(&caller_principal) {
+        Ok(caller) => caller,
+        Err(e) => return AddStepResult::Err(e),
+    };
+    let now = time();
+    // Replays are answered before rate limiting so that retries don't use up the caller's budget
+    if let Some(key) = options.idempotency_key.as_deref() {
+        let replayed = idempotency::check_key(key).and_then(|_| idempotency::replay(&actual_caller, key, &step.product_id, now));
+        match replayed {
+            Ok(Some(result)) => return AddStepResult::Ok(result),
+            Ok(None) => {}
+            Err(e) => return AddStepResult::Err(e),
+        }
+    }
+    if let Err(e) = quotas::check_rate_limit(QuotaOperation::AddStep, &actual_caller) {
+        return AddStepResult::Err(e);
     }
+    if custody::is_handover_step(&step) {
+        return AddStepResult::Err(BlockTraceError::invalid("status", "handover steps are recorded by accepting a custody handover"));
+    }
+    step.user_id = actual_caller;
+    let step = match append_step(step, now, &options) {
+        Ok(step) => step,
+        Err(e) => return AddStepResult::Err(e),
+    };
+    let result = format!("Enhanced step added successfully for product {}", step.product_id);
+    if let Some(key) = options.idempotency_key.as_deref() {
+        idempotency::remember(
+            &step.user_id,
+            key,
+            IdempotencyRecord {
+                product_id: step.product_id.clone(),
+                sequence: step.sequence,
+                result: result.clone(),
+                recorded_at: now,
+            },
+        );
+    }
+    AddStepResult::Ok(result)
+}
+
+// Result of a destructive admin operation, used for the audit log entry.
+struct AdminOutcome {
+    message: String,
+    affected_products: usize,
+    affected_steps: usize,
+}
+
+// Runs a destructive admin operation once governance has authorized it (directly or through an
+// executed proposal) and records it in the audit log together with `extra_arguments`.
+pub(crate) fn run_admin_action(action: &AdminAction, extra_arguments: Vec<(&str, String)>) -> ApiResult<String> {
+    let outcome = match action {
+        AdminAction::ClearAllData => clear_all_data_internal(),
+        AdminAction::DeleteStepsByOwner { owner } => delete_steps_by_owner_internal(owner),
+        AdminAction::DeleteOrphanSteps => delete_orphan_steps_internal(),
+        AdminAction::AssignOrphanSteps { new_owner } => assign_orphan_steps_internal(new_owner),
+        AdminAction::ReassignSteps { owner_from, owner_to } => reassign_steps_internal(owner_from, owner_to),
+        AdminAction::AddAdmin { .. } | AdminAction::RemoveAdmin { .. } | AdminAction::SetApprovalThreshold { .. } => {
+            return Err(BlockTraceError::invalid("action", "governance actions are applied by the governance module"));
+        }
+    };
+    let mut arguments = action.arguments();
+    arguments.extend(extra_arguments);
+    audit::record(action.method_name(), arguments, outcome.affected_products as u64, outcome.affected_steps as u64);
+    logs::warn(
+        "admin",
+        outcome.message.clone(),
+        vec![
+            ("method", action.method_name().to_string()),
+            ("affected_products", outcome.affected_products.to_string()),
+            ("affected_steps", outcome.affected_steps.to_string()),
+        ],
+    );
+    Ok(outcome.message)
+}
+
+#[update]
+#[candid_method(update)]
+fn assign_orphan_steps(new_owner: String) -> ApiResult<String> {
+    governance::require_direct_admin("assign_orphan_steps")?;
+    run_admin_action(&AdminAction::AssignOrphanSteps { new_owner }, vec![])
+}
 
+fn assign_orphan_steps_internal(new_owner: &str) -> AdminOutcome {
     let mut moved_total = 0usize;
+    let mut products_touched = 0usize;
     PRODUCT_HISTORY.with(|store| {
         let mut map = store.borrow_mut();
         for (_product_id, steps) in map.iter_mut() {
+            let before = moved_total;
             for step in steps.iter_mut() {
                 if step.user_id.trim().is_empty() {
-                    step.user_id = new_owner.clone();
+                    step.user_id = new_owner.to_string();
                     moved_total += 1;
                 }
             }
+            if moved_total > before {
+                products_touched += 1;
+            }
         }
     });
+    history::reassign_owner("", new_owner);
 
-    let msg = format!("Assigned {} orphan steps to {}", moved_total, new_owner);
-    ic_cdk::println!("{}", msg);
-    msg
+    AdminOutcome {
+        message: format!("Assigned {} orphan steps to {}", moved_total, new_owner),
+        affected_products: products_touched,
+        affected_steps: moved_total,
+    }
 }
 
 #[update]
-fn delete_orphan_steps() -> String {
-    let caller = format!("{}", ic_cdk::caller());
-    if caller != ADMIN_PRINCIPAL {
-        ic_cdk::trap("delete_orphan_steps can only be called by the admin principal");
-    }
+#[candid_method(update)]
+fn delete_orphan_steps() -> ApiResult<String> {
+    governance::require_direct_admin("delete_orphan_steps")?;
+    run_admin_action(&AdminAction::DeleteOrphanSteps, vec![])
+}
 
-    let mut removed_total = 0usize;
+fn delete_orphan_steps_internal() -> AdminOutcome {
+    let mut removed: Vec<Step> = Vec::new();
+    let mut products_touched = 0usize;
     PRODUCT_HISTORY.with(|store| {
         let mut map = store.borrow_mut();
         // Collect keys to update so we can remove empty steps safely
         for (_product_id, steps) in map.iter_mut() {
-            let before = steps.len();
-            steps.retain(|s| !s.user_id.trim().is_empty());
-            let after = steps.len();
-            removed_total += before.saturating_sub(after);
+            let (orphans, kept): (Vec<Step>, Vec<Step>) = steps.drain(..).partition(|s| s.user_id.trim().is_empty());
+            *steps = kept;
+            if !orphans.is_empty() {
+                products_touched += 1;
+                removed.extend(orphans);
+            }
         }
         // Optionally remove products that now have zero steps
         let empty_keys: Vec<String> = map.iter().filter_map(|(k, v)| if v.is_empty() { Some(k.clone()) } else { None }).collect();
@@ -199,27 +417,35 @@ fn delete_orphan_steps() -> String {
         }
     });
 
-    let msg = format!("Removed {} orphan steps (and cleaned up empty products)", removed_total);
-    ic_cdk::println!("{}", msg);
-    msg
+    let removed_total = removed.len();
+    retention::tombstone(removed, "delete_orphan_steps");
+    AdminOutcome {
+        message: format!("Moved {} orphan steps to tombstones (and cleaned up empty products)", removed_total),
+        affected_products: products_touched,
+        affected_steps: removed_total,
+    }
 }
 
 // Admin utility: delete all steps that belong to a specific owner string (e.g., malformed owner like "1")
 #[update]
-fn delete_steps_by_owner(owner: String) -> String {
-    let caller = format!("{}", ic_cdk::caller());
-    if caller != ADMIN_PRINCIPAL {
-        ic_cdk::trap("delete_steps_by_owner can only be called by the admin principal");
-    }
+#[candid_method(update)]
+fn delete_steps_by_owner(owner: String) -> ApiResult<String> {
+    governance::require_direct_admin("delete_steps_by_owner")?;
+    run_admin_action(&AdminAction::DeleteStepsByOwner { owner }, vec![])
+}
 
-    let mut removed_total = 0usize;
+fn delete_steps_by_owner_internal(owner: &str) -> AdminOutcome {
+    let mut removed: Vec<Step> = Vec::new();
+    let mut products_touched = 0usize;
     PRODUCT_HISTORY.with(|store| {
         let mut map = store.borrow_mut();
         for (_product_id, steps) in map.iter_mut() {
-            let before = steps.len();
-            steps.retain(|s| s.user_id != owner);
-            let after = steps.len();
-            removed_total += before.saturating_sub(after);
+            let (owned, kept): (Vec<Step>, Vec<Step>) = steps.drain(..).partition(|s| s.user_id == owner);
+            *steps = kept;
+            if !owned.is_empty() {
+                products_touched += 1;
+                removed.extend(owned);
+            }
         }
         let empty_keys: Vec<String> = map.iter().filter_map(|(k, v)| if v.is_empty() { Some(k.clone()) } else { None }).collect();
         for k in empty_keys {
@@ -227,29 +453,23 @@ fn delete_steps_by_owner(owner: String) -> String {
         }
     });
 
-    let msg = format!("Removed {} steps owned by '{}'", removed_total, owner);
-    ic_cdk::println!("{}", msg);
-    msg
+    let removed_total = removed.len();
+    retention::tombstone(removed, "delete_steps_by_owner");
+    AdminOutcome {
+        message: format!("Moved {} steps owned by '{}' to tombstones", removed_total, owner),
+        affected_products: products_touched,
+        affected_steps: removed_total,
+    }
 }
 
 #[query]
 #[candid_method(query)]
-fn get_product_history(product_id: String, caller_principal: String) -> Vec<Step> {
-    PRODUCT_HISTORY.with(|store| {
-        let mut history = store
-            .borrow()
-            .get(&product_id)
-            .cloned()
-            .unwrap_or_default()
-            .into_iter()
-            .filter(|step| step.user_id == caller_principal)
-            .collect::<Vec<Step>>();
-
-        history.sort_by(|a, b| a.timestamp.cmp(&b.timestamp));
-
-        ic_cdk::println!("Retrieved {} enhanced steps for product: {} (user: {})", history.len(), product_id, caller_principal);
-        history
-    })
+fn get_product_history(product_id: String, caller_principal: String, as_of: Option<u64>) -> Vec<Step> {
+    // history_as_of returns steps sorted by timestamp with corrections applied
+    history::history_as_of(&product_id, as_of)
+        .into_iter()
+        .filter(|step| step.user_id == caller_principal)
+        .collect()
 }
 
 #[query]
@@ -264,9 +484,7 @@ fn get_user_products(caller_principal: String) -> Vec<String> {
                 }
             }
         }
-        let products: Vec<String> = user_products.into_iter().collect();
-        ic_cdk::println!("Retrieved {} products for user: {}", products.len(), caller_principal);
-        products
+        user_products.into_iter().collect()
     })
 }
 
@@ -274,9 +492,7 @@ fn get_user_products(caller_principal: String) -> Vec<String> {
 #[candid_method(query)]
 fn get_total_steps_count() -> u64 {
     PRODUCT_HISTORY.with(|store| {
-        let count = store.borrow().values().map(|steps| steps.len()).sum::<usize>() as u64;
-        ic_cdk::println!("Total enhanced steps count: {}", count);
-        count
+        store.borrow().values().map(|steps| steps.len()).sum::<usize>() as u64
     })
 }
 
@@ -311,104 +527,130 @@ fn list_all_owners() -> Vec<(String, u64)> {
 
 // Admin update: reassign all steps owned by `owner_from` to `owner_to`.
 #[update]
-fn reassign_steps(owner_from: String, owner_to: String) -> String {
-    let caller = format!("{}", ic_cdk::caller());
-    if caller != ADMIN_PRINCIPAL {
-        ic_cdk::trap("reassign_steps can only be called by the admin principal");
-    }
+#[candid_method(update)]
+fn reassign_steps(owner_from: String, owner_to: String) -> ApiResult<String> {
+    governance::require_direct_admin("reassign_steps")?;
+    run_admin_action(&AdminAction::ReassignSteps { owner_from, owner_to }, vec![])
+}
 
+fn reassign_steps_internal(owner_from: &str, owner_to: &str) -> AdminOutcome {
     if owner_from == owner_to {
-        return format!("No-op: owner_from == owner_to ({})", owner_from);
+        return AdminOutcome {
+            message: format!("No-op: owner_from == owner_to ({})", owner_from),
+            affected_products: 0,
+            affected_steps: 0,
+        };
     }
 
     let mut moved_total = 0usize;
+    let mut products_touched = 0usize;
     PRODUCT_HISTORY.with(|store| {
         for (_product_id, steps) in store.borrow_mut().iter_mut() {
+            let before = moved_total;
             for s in steps.iter_mut() {
                 if s.user_id == owner_from {
-                    s.user_id = owner_to.clone();
+                    s.user_id = owner_to.to_string();
                     moved_total += 1;
                 }
             }
+            if moved_total > before {
+                products_touched += 1;
+            }
         }
     });
+    history::reassign_owner(owner_from, owner_to);
 
-    let msg = format!("Reassigned {} steps from '{}' to '{}'", moved_total, owner_from, owner_to);
-    ic_cdk::println!("{}", msg);
-    msg
+    AdminOutcome {
+        message: format!("Reassigned {} steps from '{}' to '{}'", moved_total, owner_from, owner_to),
+        affected_products: products_touched,
+        affected_steps: moved_total,
+    }
 }
 
 #[query]
 #[candid_method(query)]
-fn calculate_esg_score(product_id: String, caller_principal: String) -> Option<ESGScore> {
-    PRODUCT_HISTORY.with(|store| {
-        let history = if caller_principal.is_empty() {
-            // For internal use (timers), get all steps for the product
-            store.borrow().get(&product_id).cloned().unwrap_or_default()
-        } else {
-            // For user queries, filter by user_id
-            store.borrow().get(&product_id).cloned().unwrap_or_default()
-                .into_iter()
-                .filter(|step| step.user_id == caller_principal)
-                .collect::<Vec<Step>>()
-        };
-        
-        if history.is_empty() {
-            return None;
-        }
+pub(crate) fn calculate_esg_score(product_id: String, caller_principal: String, as_of: Option<u64>, methodology_version: Option<u32>) -> Option<ESGScore> {
+    // None for an unknown version; the latest published methodology when unspecified
+    let methodology = methodology::methodology(methodology_version)?;
+    let history = if caller_principal.is_empty() {
+        // For internal use (timers), get all steps for the product
+        history::history_as_of(&product_id, as_of)
+    } else {
+        // For user queries, filter by user_id
+        history::history_as_of(&product_id, as_of)
+            .into_iter()
+            .filter(|step| step.user_id == caller_principal)
+            .collect::<Vec<Step>>()
+    };
+    
+    if history.is_empty() {
+        return None;
+    }
 
-        let total_steps = history.len() as u32;
-        
-        // Use actual distance data if available, otherwise estimate
-        let total_distance_km = history.iter()
-            .filter_map(|step| step.distance_km)
-            .sum::<f64>()
-            .max(0.0);
-        
-        let estimated_distance = if total_distance_km > 0.0 {
-            total_distance_km
-        } else {
-            let unique_locations: std::collections::HashSet<String> = 
-                history.iter().map(|step| step.location.clone()).collect();
-            (unique_locations.len() as f64 - 1.0) * 500.0
-        };
-        
-        // Use actual carbon footprint if available, otherwise calculate
-        let carbon_footprint = history.iter()
-            .filter_map(|step| step.carbon_footprint_kg)
-            .sum::<f64>()
-            .max(0.0);
-        
-        let estimated_carbon = if carbon_footprint > 0.0 {
-            carbon_footprint
-        } else {
-            estimated_distance * 0.162
-        };
-        
-        let base_score = 100.0;
-        let distance_penalty = (estimated_distance / 100.0).min(30.0);
-        let steps_bonus = (total_steps as f64 * 2.0).min(20.0);
-        
-        let sustainability_score = ((base_score - distance_penalty + steps_bonus).max(0.0).min(100.0)) as u8;
-        
-        let traditional_co2 = estimated_carbon * 1.3;
-        let co2_saved = traditional_co2 - estimated_carbon;
-        
-        let impact_message = format!(
-            "Enhanced Impact Score: {}/100 🌿 — saved {:.1}kg CO₂ vs traditional supply chains",
-            sustainability_score,
-            co2_saved
-        );
+    let total_steps = history.len() as u32;
+    
+    // Use actual distance data if available, otherwise estimate
+    let total_distance_km = history.iter()
+        .filter_map(|step| step.distance_km)
+        .sum::<f64>()
+        .max(0.0);
+    
+    let estimated_distance = if total_distance_km > 0.0 {
+        total_distance_km
+    } else {
+        let unique_locations: std::collections::HashSet<String> = 
+            history.iter().map(|step| step.location.clone()).collect();
+        (unique_locations.len() as f64 - 1.0) * 500.0
+    };
+    
+    // Use actual carbon footprint if available, otherwise calculate from the emission factor registry
+    let step_emissions: Vec<emissions::StepEmission> = history.iter().filter_map(emissions::step_emission).collect();
+    let mut emission_factors_used: Vec<EmissionFactorRef> = step_emissions.iter().filter_map(|e| e.factor.clone()).collect();
+    let carbon_footprint = step_emissions.iter().map(|e| e.kg_co2e).sum::<f64>().max(0.0);
+    
+    let estimated_carbon = if carbon_footprint > 0.0 {
+        carbon_footprint
+    } else {
+        let factor = emissions::factor_for("", "", "");
+        if estimated_distance > 0.0 {
+            emission_factors_used.push(EmissionFactorRef { key: factor.key.clone(), version: factor.version });
+        }
+        estimated_distance * factor.kg_co2e_per_km
+    };
+    emission_factors_used.sort();
+    emission_factors_used.dedup();
+    
+    let quality_scores: Vec<f64> = history.iter().filter_map(|step| step.quality_score.map(f64::from)).collect();
+    let inputs = methodology::ScoreInputs {
+        total_distance_km: estimated_distance,
+        carbon_footprint_kg: estimated_carbon,
+        step_count: total_steps,
+        average_quality_score: (!quality_scores.is_empty()).then(|| quality_scores.iter().sum::<f64>() / quality_scores.len() as f64),
+        certified_step_share: history.iter().filter(|step| step.certification_hash.as_ref().is_some_and(|h| !h.trim().is_empty())).count() as f64 / total_steps as f64,
+    };
+    let (sustainability_score, components) = methodology::score(&methodology, &inputs);
+    
+    let traditional_co2 = estimated_carbon * methodology.conventional_carbon_multiplier;
+    let co2_saved = traditional_co2 - estimated_carbon;
+    
+    let impact_message = format!(
+        "Enhanced Impact Score: {}/100 🌿 — saved {:.1}kg CO₂ vs traditional supply chains",
+        sustainability_score,
+        co2_saved
+    );
 
-        Some(ESGScore {
-            product_id: product_id.clone(),
-            sustainability_score,
-            carbon_footprint_kg: estimated_carbon,
-            total_distance_km: estimated_distance,
-            total_steps,
-            impact_message,
-            co2_saved_vs_traditional: co2_saved,
-        })
+    Some(ESGScore {
+        product_id: product_id.clone(),
+        sustainability_score,
+        carbon_footprint_kg: estimated_carbon,
+        total_distance_km: estimated_distance,
+        total_steps,
+        impact_message,
+        co2_saved_vs_traditional: co2_saved,
+        emission_factors_used,
+        scope_breakdown: scopes::scope_totals(&product_id, &history),
+        methodology_version: methodology.version,
+        components,
     })
 }
 
@@ -418,7 +660,7 @@ fn get_user_esg_scores(caller_principal: String) -> Vec<ESGScore> {
     let user_products = get_user_products(caller_principal.clone());
     let mut scores = Vec::new();
     for product_id in user_products {
-        if let Some(score) = calculate_esg_score(product_id, caller_principal.clone()) {
+        if let Some(score) = calculate_esg_score(product_id, caller_principal.clone(), None, None) {
             scores.push(score);
         }
     }
@@ -428,7 +670,9 @@ fn get_user_esg_scores(caller_principal: String) -> Vec<ESGScore> {
 // 🌐 ADVANCED ICP FEATURE 1: HTTP OUTCALLS FOR REAL SUPPLY CHAIN APIS
 #[update]
 #[candid_method(update)]
-async fn verify_supplier_with_api(supplier_id: String, api_endpoint: Option<String>) -> Result<SupplierVerification, String> {
+async fn verify_supplier_with_api(supplier_id: String, api_endpoint: Option<String>) -> ApiResult<SupplierVerification> {
+    let caller = format!("{}", ic_cdk::caller());
+    quotas::check_rate_limit(QuotaOperation::VerifySupplier, &caller)?;
     let cache_key = format!("supplier_{}", supplier_id);
     
     // Check cache first (5 minute TTL)
@@ -443,8 +687,11 @@ async fn verify_supplier_with_api(supplier_id: String, api_endpoint: Option<Stri
     });
     
     let api_response = if let Some(cached) = cached_result {
+        metrics::increment(Counter::CacheHit);
         cached
     } else {
+        metrics::increment(Counter::CacheMiss);
+        quotas::reserve_outcall(&caller)?;
         // Real HTTP outcall to external supplier verification API
         let url = api_endpoint.unwrap_or_else(|| 
             format!("https://api.supplierverify.com/v1/verify/{}", supplier_id)
@@ -467,6 +714,7 @@ async fn verify_supplier_with_api(supplier_id: String, api_endpoint: Option<Stri
         
         match http_request(request, 25_000_000_000).await {
             Ok((response,)) => {
+                metrics::increment(Counter::OutcallSucceeded);
                 let http_response = HttpOutcallResponse {
                     status: response.status.0.try_into().unwrap_or(500),
                     body: String::from_utf8(response.body).unwrap_or_default(),
@@ -481,7 +729,12 @@ async fn verify_supplier_with_api(supplier_id: String, api_endpoint: Option<Stri
                 http_response
             },
             Err(e) => {
-                ic_cdk::println!("HTTP outcall failed: {:?}", e);
+                metrics::increment(Counter::OutcallFailed);
+                logs::warn(
+                    "outcalls",
+                    "Supplier verification outcall failed, using fallback data",
+                    vec![("supplier_id", supplier_id.clone()), ("url", url), ("error", format!("{:?}", e))],
+                );
                 // Fallback to mock data
                 HttpOutcallResponse {
                     status: 200,
@@ -530,8 +783,15 @@ async fn verify_supplier_with_api(supplier_id: String, api_endpoint: Option<Stri
         store.borrow_mut().insert(supplier_id.clone(), verification.clone());
     });
     
-    ic_cdk::println!("🌐 HTTP Outcall: Verified supplier {} with score {} via {}", 
-        supplier_id, verification.compliance_score, verification.api_source);
+    logs::info(
+        "outcalls",
+        "Supplier verified",
+        vec![
+            ("supplier_id", supplier_id),
+            ("score", verification.compliance_score.to_string()),
+            ("source", verification.api_source.clone()),
+        ],
+    );
     Ok(verification)
 }
 
@@ -555,7 +815,12 @@ fn get_supplier_verification(supplier_id: String) -> Option<SupplierVerification
 
 #[update]
 #[candid_method(update)]
-async fn fetch_real_time_carbon_data(transport_mode: String, distance_km: f64) -> Result<f64, String> {
+async fn fetch_real_time_carbon_data(transport_mode: String, distance_km: f64) -> ApiResult<f64> {
+    quotas::reserve_outcall(&format!("{}", ic_cdk::caller()))?;
+    request_carbon_data(transport_mode, distance_km).await
+}
+
+async fn request_carbon_data(transport_mode: String, distance_km: f64) -> ApiResult<f64> {
     let url = format!("https://api.carbonfootprint.com/v1/calculate?mode={}&distance={}", transport_mode, distance_km);
     
     let request = CanisterHttpRequestArgument {
@@ -575,18 +840,33 @@ async fn fetch_real_time_carbon_data(transport_mode: String, distance_km: f64) -
     
     match http_request(request, 25_000_000_000).await {
         Ok((response,)) => {
+            metrics::increment(Counter::OutcallSucceeded);
             let body = String::from_utf8(response.body).unwrap_or_default();
             let parsed: Result<serde_json::Value, _> = serde_json::from_str(&body);
             match parsed {
                 Ok(json) => {
-                    let carbon_kg = json["carbon_kg"].as_f64().unwrap_or(distance_km * 0.162);
-                    ic_cdk::println!("🌐 Real-time carbon data: {} kg CO₂ for {} km via {}", carbon_kg, distance_km, transport_mode);
+                    let carbon_kg = json["carbon_kg"].as_f64().unwrap_or_else(|| emissions::distance_emission_kg(&transport_mode, distance_km));
+                    logs::log(
+                        LogLevel::Debug,
+                        "outcalls",
+                        "Real-time carbon data received",
+                        vec![("transport_mode", transport_mode), ("distance_km", distance_km.to_string()), ("carbon_kg", carbon_kg.to_string())],
+                    );
                     Ok(carbon_kg)
                 },
-                Err(_) => Ok(distance_km * 0.162) // Fallback calculation
+                Err(_) => Ok(emissions::distance_emission_kg(&transport_mode, distance_km)) // Fallback calculation
             }
         },
-        Err(_) => Ok(distance_km * 0.162) // Fallback calculation
+        Err((code, message)) => {
+            metrics::increment(Counter::OutcallFailed);
+            let fallback_kg = emissions::distance_emission_kg(&transport_mode, distance_km);
+            logs::warn(
+                "outcalls",
+                "Carbon data outcall failed, using fallback factor",
+                vec![("transport_mode", transport_mode), ("error", format!("{:?}: {}", code, message))],
+            );
+            Ok(fallback_kg) // Fallback calculation
+        }
     }
 }
 
@@ -603,14 +883,22 @@ fn transform_carbon_response(args: TransformArgs) -> HttpResponse {
 // ⏰ ADVANCED ICP FEATURE 2: SOPHISTICATED TIMERS FOR AUTOMATED ESG RECALCULATION
 #[update]
 #[candid_method(update)]
-fn schedule_esg_recalculation(product_id: String, interval_seconds: u64) -> Result<String, String> {
+fn schedule_esg_recalculation(product_id: String, interval_seconds: u64) -> ApiResult<String> {
+    // Outcalls made by the timer are charged to whoever scheduled it
+    let scheduler = format!("{}", ic_cdk::caller());
+    quotas::check_timer_interval(interval_seconds)?;
+    check_esg_timer_owner(&product_id, &scheduler)?;
+    quotas::check_rate_limit(QuotaOperation::ScheduleEsgRecalculation, &scheduler)?;
     let product_id_clone = product_id.clone();
+    let timer_scheduler = scheduler.clone();
     
     let timer_id = set_timer_interval(Duration::from_secs(interval_seconds), move || {
+        metrics::increment(Counter::TimerRun);
         let product_id_inner = product_id_clone.clone();
+        let scheduler = timer_scheduler.clone();
         ic_cdk::spawn(async move {
             // Get current ESG score (using empty principal for internal calculations)
-            let old_score = calculate_esg_score(product_id_inner.clone(), "".to_string())
+            let old_score = calculate_esg_score(product_id_inner.clone(), "".to_string(), None, None)
                 .map(|s| s.sustainability_score)
                 .unwrap_or(0);
             
@@ -624,17 +912,23 @@ fn schedule_esg_recalculation(product_id: String, interval_seconds: u64) -> Resu
                 let mut _updated_carbon = 0.0;
                 for step in &history {
                     if let (Some(transport), Some(distance)) = (&step.transport_mode, step.distance_km) {
-                        match fetch_real_time_carbon_data(transport.clone(), distance).await {
+                        if quotas::reserve_outcall(&scheduler).is_err() {
+                            _updated_carbon += emissions::distance_emission_kg(transport, distance);
+                            continue;
+                        }
+                        match request_carbon_data(transport.clone(), distance).await {
                             Ok(carbon) => _updated_carbon += carbon,
-                            Err(_) => _updated_carbon += distance * 0.162,
+                            Err(_) => _updated_carbon += emissions::distance_emission_kg(transport, distance),
                         }
                     }
                 }
                 
                 // Recalculate ESG score with updated data (using empty principal for internal calculations)
-                let new_score = calculate_esg_score(product_id_inner.clone(), "".to_string())
-                    .map(|s| s.sustainability_score)
-                    .unwrap_or(0);
+                let recalculated = calculate_esg_score(product_id_inner.clone(), "".to_string(), None, None);
+                if let Some(score) = recalculated.as_ref() {
+                    esg_history::record(score, "scheduled", time());
+                }
+                let new_score = recalculated.map(|s| s.sustainability_score).unwrap_or(0);
                 
                 // Log significant changes
                 if (new_score as i16 - old_score as i16).abs() >= 5 {
@@ -649,27 +943,53 @@ fn schedule_esg_recalculation(product_id: String, interval_seconds: u64) -> Resu
                     AUTOMATED_ESG_UPDATES.with(|updates| {
                         updates.borrow_mut().push(update);
                     });
+
+                    webhooks::notify_alert(WebhookAlert {
+                        alert_type: "esg_score_change".to_string(),
+                        product_id: product_id_inner.clone(),
+                        batch_number: None,
+                        message: format!("ESG score changed from {} to {}", old_score, new_score),
+                        timestamp: time(),
+                    });
                     
-                    ic_cdk::println!("⏰ Timer: ESG score changed for {}: {} -> {} ({}% change)", 
-                        product_id_inner, old_score, new_score, 
-                        ((new_score as f64 - old_score as f64) / old_score as f64 * 100.0) as i32);
+                    logs::info(
+                        "timers",
+                        "ESG score changed",
+                        vec![
+                            ("product_id", product_id_inner.clone()),
+                            ("old_score", old_score.to_string()),
+                            ("new_score", new_score.to_string()),
+                        ],
+                    );
                 }
             }
         });
     });
     
-    ESG_TIMERS.with(|store| {
-        store.borrow_mut().insert(product_id.clone(), timer_id);
-    });
+    // Rescheduling replaces the product's running timer rather than stacking another one
+    if let Some((previous, _)) = ESG_TIMERS.with(|store| store.borrow_mut().insert(product_id.clone(), (timer_id, scheduler))) {
+        ic_cdk_timers::clear_timer(previous);
+    }
     
-    ic_cdk::println!("⏰ Timer: Scheduled automated ESG recalculation for {} every {} seconds", product_id, interval_seconds);
+    logs::info(
+        "timers",
+        "ESG recalculation scheduled",
+        vec![("product_id", product_id.clone()), ("interval_seconds", interval_seconds.to_string())],
+    );
     Ok(format!("Automated ESG monitoring activated for product {}", product_id))
 }
 
 #[update]
 #[candid_method(update)]
-fn schedule_global_esg_monitoring(interval_minutes: u64) -> Result<String, String> {
-    let _timer_id = set_timer_interval(Duration::from_secs(interval_minutes * 60), move || {
+fn schedule_global_esg_monitoring(interval_minutes: u64) -> ApiResult<String> {
+    let caller = format!("{}", ic_cdk::caller());
+    if !governance::is_admin(&caller) {
+        return Err(BlockTraceError::unauthorized("Only an admin can schedule global ESG monitoring"));
+    }
+    quotas::check_timer_interval(interval_minutes.saturating_mul(60))?;
+    quotas::check_rate_limit(QuotaOperation::ScheduleEsgRecalculation, &caller)?;
+    let timer_id = set_timer_interval(Duration::from_secs(interval_minutes * 60), move || {
+        metrics::increment(Counter::TimerRun);
         ic_cdk::spawn(async move {
             let all_products = PRODUCT_HISTORY.with(|store| {
                 store.borrow().keys().cloned().collect::<Vec<String>>()
@@ -678,7 +998,8 @@ fn schedule_global_esg_monitoring(interval_minutes: u64) -> Result<String, Strin
             let mut updates_count = 0;
             let total_products = all_products.len();
             for product_id in &all_products {
-                if let Some(_current_score) = calculate_esg_score(product_id.clone(), "".to_string()) {
+                if let Some(current_score) = calculate_esg_score(product_id.clone(), "".to_string(), None, None) {
+                    esg_history::record(&current_score, "global_monitoring", time());
                     // Check for supply chain disruptions or improvements
                     let history = PRODUCT_HISTORY.with(|store| {
                         store.borrow().get(product_id).cloned().unwrap_or_default()
@@ -694,24 +1015,58 @@ fn schedule_global_esg_monitoring(interval_minutes: u64) -> Result<String, Strin
                 }
             }
             
-            ic_cdk::println!("⏰ Global Monitor: Processed {} products, {} had recent activity", 
-                total_products, updates_count);
+            logs::info(
+                "timers",
+                "Global ESG monitor run",
+                vec![("products", total_products.to_string()), ("recently_active", updates_count.to_string())],
+            );
         });
     });
+    // Rescheduling replaces the running monitor rather than stacking another one
+    if let Some(previous) = GLOBAL_ESG_TIMER.with(|timer| timer.borrow_mut().replace(timer_id)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
     
-    ic_cdk::println!("⏰ Global ESG monitoring started with {} minute intervals", interval_minutes);
+    logs::info("timers", "Global ESG monitoring scheduled", vec![("interval_minutes", interval_minutes.to_string())]);
     Ok(format!("Global ESG monitoring activated with {} minute intervals", interval_minutes))
 }
 
 #[update]
 #[candid_method(update)]
-fn cancel_esg_timer(product_id: String) -> Result<String, String> {
+fn cancel_global_esg_monitoring() -> ApiResult<String> {
+    if !governance::is_admin(&format!("{}", ic_cdk::caller())) {
+        return Err(BlockTraceError::unauthorized("Only an admin can cancel global ESG monitoring"));
+    }
+    let timer_id = GLOBAL_ESG_TIMER
+        .with(|timer| timer.borrow_mut().take())
+        .ok_or_else(|| BlockTraceError::not_found("Global ESG monitoring", "timer"))?;
+    ic_cdk_timers::clear_timer(timer_id);
+    logs::info("timers", "Global ESG monitoring cancelled", vec![]);
+    Ok("Global ESG monitoring cancelled".to_string())
+}
+
+// A product's timer may only be replaced or cancelled by whoever scheduled it, or by an admin.
+fn check_esg_timer_owner(product_id: &str, caller: &str) -> ApiResult<()> {
+    let scheduler = ESG_TIMERS.with(|store| store.borrow().get(product_id).map(|(_, scheduler)| scheduler.clone()));
+    match scheduler {
+        Some(scheduler) if scheduler != caller && !governance::is_admin(caller) => {
+            Err(BlockTraceError::unauthorized("Only the principal that scheduled the ESG timer or an admin can change it"))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[update]
+#[candid_method(update)]
+fn cancel_esg_timer(product_id: String) -> ApiResult<String> {
+    check_esg_timer_owner(&product_id, &format!("{}", ic_cdk::caller()))?;
     ESG_TIMERS.with(|store| {
-        if let Some(timer_id) = store.borrow_mut().remove(&product_id) {
+        if let Some((timer_id, _)) = store.borrow_mut().remove(&product_id) {
             ic_cdk_timers::clear_timer(timer_id);
+            logs::info("timers", "ESG recalculation cancelled", vec![("product_id", product_id.clone())]);
             Ok(format!("ESG monitoring cancelled for product {}", product_id))
         } else {
-            Err("No active ESG monitoring found for this product".to_string())
+            Err(BlockTraceError::not_found("ESG timer for product", product_id))
         }
     })
 }
@@ -737,7 +1092,10 @@ fn get_active_timers() -> Vec<String> {
 // 🔐 ADVANCED ICP FEATURE 3: t-ECDSA FOR REAL CROSS-CHAIN VERIFICATION
 #[update]
 #[candid_method(update)]
-async fn generate_cross_chain_proof(product_id: String, chain_id: String) -> Result<CrossChainProof, String> {
+async fn generate_cross_chain_proof(product_id: String, chain_id: String) -> ApiResult<CrossChainProof> {
+    let caller = format!("{}", ic_cdk::caller());
+    quotas::check_rate_limit(QuotaOperation::CrossChainProof, &caller)?;
+
     // Get or generate ECDSA public key
     let public_key = get_or_create_ecdsa_key().await?;
     
@@ -747,7 +1105,7 @@ async fn generate_cross_chain_proof(product_id: String, chain_id: String) -> Res
     });
     
     if product_history.is_empty() {
-        return Err("Product not found".to_string());
+        return Err(BlockTraceError::not_found("Product", product_id));
     }
     
     // Create deterministic hash of all product data
@@ -769,6 +1127,7 @@ async fn generate_cross_chain_proof(product_id: String, chain_id: String) -> Res
     let proof_hash_hex = hex::encode(&proof_hash);
     
     // Generate t-ECDSA signature
+    quotas::reserve_signature(&caller)?;
     let signature = sign_with_ecdsa(proof_hash.to_vec()).await?;
     
     let proof = CrossChainProof {
@@ -784,12 +1143,19 @@ async fn generate_cross_chain_proof(product_id: String, chain_id: String) -> Res
         store.borrow_mut().insert(product_id.clone(), proof.clone());
     });
     
-    ic_cdk::println!("🔐 t-ECDSA: Generated cross-chain proof for {} on chain {} with {} byte signature", 
-        product_id, chain_id, proof.ecdsa_signature.len());
+    logs::info(
+        "cross_chain",
+        "Cross-chain proof generated",
+        vec![
+            ("product_id", product_id),
+            ("chain_id", chain_id),
+            ("signature_bytes", proof.ecdsa_signature.len().to_string()),
+        ],
+    );
     Ok(proof)
 }
 
-async fn get_or_create_ecdsa_key() -> Result<Vec<u8>, String> {
+async fn get_or_create_ecdsa_key() -> ApiResult<Vec<u8>> {
     // Check if we already have a public key cached
     let cached_key = ECDSA_PUBLIC_KEY.with(|key| key.borrow().clone());
     if let Some(key) = cached_key {
@@ -815,13 +1181,13 @@ async fn get_or_create_ecdsa_key() -> Result<Vec<u8>, String> {
             Ok(public_key)
         },
         Err(e) => {
-            ic_cdk::println!("Failed to get ECDSA public key: {:?}", e);
-            Err(format!("ECDSA key generation failed: {:?}", e))
+            logs::error("ecdsa", "Failed to get ECDSA public key", vec![("error", format!("{:?}", e))]);
+            Err(BlockTraceError::external("ecdsa_public_key", format!("{:?}", e)))
         }
     }
 }
 
-async fn sign_with_ecdsa(message_hash: Vec<u8>) -> Result<Vec<u8>, String> {
+async fn sign_with_ecdsa(message_hash: Vec<u8>) -> ApiResult<Vec<u8>> {
     let request = ic_cdk::api::management_canister::ecdsa::SignWithEcdsaArgument {
         message_hash,
         derivation_path: vec![b"blocktrace".to_vec()],
@@ -832,44 +1198,49 @@ async fn sign_with_ecdsa(message_hash: Vec<u8>) -> Result<Vec<u8>, String> {
     };
     
     match ic_cdk::api::management_canister::ecdsa::sign_with_ecdsa(request).await {
-        Ok((response,)) => Ok(response.signature),
+        Ok((response,)) => {
+            metrics::increment(Counter::SignatureIssued);
+            Ok(response.signature)
+        }
         Err(e) => {
-            ic_cdk::println!("Failed to sign with ECDSA: {:?}", e);
-            Err(format!("ECDSA signing failed: {:?}", e))
+            logs::error("ecdsa", "Failed to sign with ECDSA", vec![("error", format!("{:?}", e))]);
+            Err(BlockTraceError::external("sign_with_ecdsa", format!("{:?}", e)))
         }
     }
 }
 
 #[update]
 #[candid_method(update)]
-async fn verify_cross_chain_proof_on_ethereum(product_id: String) -> Result<String, String> {
+async fn verify_cross_chain_proof_on_ethereum(product_id: String) -> ApiResult<String> {
     let proof = CROSS_CHAIN_PROOFS.with(|store| {
         store.borrow().get(&product_id).cloned()
-    }).ok_or("No cross-chain proof found for this product")?;
+    }).ok_or_else(|| BlockTraceError::not_found("Cross-chain proof for product", product_id.clone()))?;
     
     // In a real implementation, this would call an Ethereum smart contract
     // to verify the signature and store the proof on-chain
     let ethereum_tx_hash = format!("0x{}", hex::encode(&proof.ecdsa_signature[..32]));
     
-    ic_cdk::println!("🔐 Cross-chain: Verified proof for {} on Ethereum with tx {}", 
-        product_id, ethereum_tx_hash);
+    logs::info(
+        "cross_chain",
+        "Proof verified on Ethereum",
+        vec![("product_id", product_id), ("tx_hash", ethereum_tx_hash.clone())],
+    );
     
     Ok(ethereum_tx_hash)
 }
 
 #[update]
 #[candid_method(update)]
-async fn create_bitcoin_anchor(product_id: String) -> Result<String, String> {
+async fn create_bitcoin_anchor(product_id: String) -> ApiResult<String> {
     let proof = CROSS_CHAIN_PROOFS.with(|store| {
         store.borrow().get(&product_id).cloned()
-    }).ok_or("No cross-chain proof found for this product")?;
+    }).ok_or_else(|| BlockTraceError::not_found("Cross-chain proof for product", product_id.clone()))?;
     
     // Create Bitcoin transaction with OP_RETURN containing proof hash
     let _op_return_data = format!("BLOCKTRACE:{}", &proof.proof_hash[..32]);
     let bitcoin_tx_id = format!("btc_{}", hex::encode(&proof.proof_hash[..16]));
     
-    ic_cdk::println!("🔐 Bitcoin: Anchored proof for {} with tx {}", 
-        product_id, bitcoin_tx_id);
+    logs::info("cross_chain", "Proof anchored on Bitcoin", vec![("product_id", product_id), ("tx_id", bitcoin_tx_id.clone())]);
     
     Ok(bitcoin_tx_id)
 }
@@ -930,12 +1301,12 @@ fn get_canister_info() -> String {
 // Debug function to clear all data (for testing multi-tenant isolation)
 #[update]
 #[candid_method(update)]
-fn clear_all_data() -> String {
-    let caller = format!("{}", ic_cdk::caller());
-    if caller != ADMIN_PRINCIPAL {
-        ic_cdk::trap("clear_all_data can only be called by the admin principal");
-    }
+fn clear_all_data() -> ApiResult<String> {
+    governance::require_direct_admin("clear_all_data")?;
+    run_admin_action(&AdminAction::ClearAllData, vec![])
+}
 
+fn clear_all_data_internal() -> AdminOutcome {
     let mut product_count = 0;
     let mut step_count = 0;
     
@@ -955,7 +1326,9 @@ fn clear_all_data() -> String {
     });
 
     ESG_TIMERS.with(|store| {
-        store.borrow_mut().clear();
+        for (_, (timer_id, _)) in store.borrow_mut().drain() {
+            ic_cdk_timers::clear_timer(timer_id);
+        }
     });
 
     HTTP_OUTCALL_CACHE.with(|cache| {
@@ -966,9 +1339,25 @@ fn clear_all_data() -> String {
         updates.borrow_mut().clear();
     });
 
-    let msg = format!("Cleared all data: {} products, {} steps", product_count, step_count);
-    ic_cdk::println!("{}", msg);
-    msg
+    certificates::clear();
+    history::clear();
+    webhooks::clear();
+    retention::clear();
+    public_view::clear();
+    idempotency::clear();
+    custody::clear();
+    disputes::clear();
+    anomalies::clear();
+    serials::clear();
+    esg_history::clear();
+    benchmarks::clear();
+
+    // The audit log and governance configuration are deliberately kept
+    AdminOutcome {
+        message: format!("Cleared all data: {} products, {} steps", product_count, step_count),
+        affected_products: product_count,
+        affected_steps: step_count,
+    }
 }
 
 // Debug function to check user-specific data
@@ -980,7 +1369,7 @@ fn debug_user_data(user_principal: String) -> String {
     let mut user_products_with_steps = Vec::new();
     
     for product_id in &user_products {
-        let steps = get_product_history(product_id.clone(), user_principal.clone());
+        let steps = get_product_history(product_id.clone(), user_principal.clone(), None);
         total_user_steps += steps.len();
         user_products_with_steps.push((product_id.clone(), steps.len()));
     }
@@ -1011,30 +1400,149 @@ fn get_advanced_features_status() -> Vec<(String, String)> {
     ]
 }
 
+// Admins come from the init arguments; without them the deploying principal becomes the only admin.
 #[init]
-fn init() {
-    ic_cdk::println!("Enhanced BlockTrace backend initialized - Starting with empty database");
+#[candid_method(init)]
+fn init(args: Option<InitArgs>) {
+    governance::init_governance(args);
+    retention::start_retention_timer();
+    logs::info("lifecycle", "Canister initialized", vec![]);
+}
+
+// State persisted next to PRODUCT_HISTORY across upgrades. Every field is optional so that
+// snapshots written by older versions (or without a given subsystem) still decode.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct StableState {
+    certificates: Option<HashMap<String, Certificate>>,
+    certificate_issuers: Option<Vec<String>>,
+    step_corrections: Option<HashMap<String, Vec<StepCorrection>>>,
+    product_owners: Option<HashMap<String, String>>,
+    webhooks: Option<WebhookState>,
+    organizations: Option<HashMap<String, String>>,
+    quotas: Option<QuotaState>,
+    audit_log: Option<Vec<AuditEntry>>,
+    governance: Option<GovernanceState>,
+    retention: Option<RetentionState>,
+    encryption: Option<EncryptionState>,
+    disclosure_settings: Option<HashMap<String, DisclosureSettings>>,
+    metrics: Option<MetricCounters>,
+    logs: Option<Vec<LogEntry>>,
+    validation_limits: Option<ValidationLimits>,
+    passport_base_url: Option<String>,
+    idempotency_keys: Option<HashMap<String, IdempotencyRecord>>,
+    custody: Option<CustodyState>,
+    disputes: Option<DisputeState>,
+    anomalies: Option<AnomalyState>,
+    serials: Option<SerialState>,
+    emission_factors: Option<EmissionFactorRegistry>,
+    esg_methodologies: Option<MethodologyState>,
+    esg_history: Option<BTreeMap<String, Vec<EsgScorePoint>>>,
+    product_categories: Option<HashMap<String, String>>,
+    benchmark_peers: Option<HashMap<String, PeerEntry>>,
+}
+
+fn restore_stable_state(state: StableState) {
+    certificates::restore(state.certificates.unwrap_or_default());
+    certificates::restore_issuers(state.certificate_issuers.unwrap_or_default());
+    history::restore(state.step_corrections.unwrap_or_default());
+    history::restore_owners(state.product_owners.unwrap_or_default());
+    webhooks::restore(state.webhooks.unwrap_or_default());
+    organizations::restore(state.organizations.unwrap_or_default());
+    quotas::restore(state.quotas.unwrap_or_default());
+    audit::migrate(state.audit_log.unwrap_or_default());
+    retention::restore(state.retention.unwrap_or_default());
+    encryption::restore(state.encryption.unwrap_or_default());
+    public_view::restore(state.disclosure_settings.unwrap_or_default());
+    metrics::restore(state.metrics.unwrap_or_default());
+    logs::restore(state.logs.unwrap_or_default());
+    validation::restore(state.validation_limits.unwrap_or_default());
+    passport::restore(state.passport_base_url);
+    idempotency::restore(state.idempotency_keys.unwrap_or_default());
+    custody::restore(state.custody.unwrap_or_default());
+    disputes::restore(state.disputes.unwrap_or_default());
+    anomalies::restore(state.anomalies.unwrap_or_default());
+    serials::restore(state.serials.unwrap_or_default());
+    emissions::restore(state.emission_factors.unwrap_or_default());
+    methodology::restore(state.esg_methodologies.unwrap_or_default());
+    esg_history::restore(state.esg_history.unwrap_or_default());
+    benchmarks::restore(state.product_categories.unwrap_or_default(), state.benchmark_peers.unwrap_or_default());
+    if let Some(governance_state) = state.governance {
+        governance::restore(governance_state);
+    }
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
     let data = PRODUCT_HISTORY.with(|store| store.borrow().clone());
-    ic_cdk::storage::stable_save((data,)).expect("Failed to save enhanced data before upgrade");
+    let state = StableState {
+        certificates: Some(certificates::snapshot()),
+        certificate_issuers: Some(certificates::issuers_snapshot()),
+        step_corrections: Some(history::snapshot()),
+        product_owners: Some(history::owners_snapshot()),
+        webhooks: Some(webhooks::snapshot()),
+        organizations: Some(organizations::snapshot()),
+        quotas: Some(quotas::snapshot()),
+        // Lives in stable memory; only snapshots from older versions carry it
+        audit_log: None,
+        governance: Some(governance::snapshot()),
+        retention: Some(retention::snapshot()),
+        encryption: Some(encryption::snapshot()),
+        disclosure_settings: Some(public_view::snapshot()),
+        metrics: Some(metrics::snapshot()),
+        logs: Some(logs::snapshot()),
+        validation_limits: Some(validation::snapshot()),
+        passport_base_url: passport::snapshot(),
+        idempotency_keys: Some(idempotency::snapshot()),
+        custody: Some(custody::snapshot()),
+        disputes: Some(disputes::snapshot()),
+        anomalies: Some(anomalies::snapshot()),
+        serials: Some(serials::snapshot()),
+        emission_factors: Some(emissions::snapshot()),
+        esg_methodologies: Some(methodology::snapshot()),
+        esg_history: Some(esg_history::snapshot()),
+        product_categories: Some(benchmarks::snapshot()),
+        benchmark_peers: Some(benchmarks::peers_snapshot()),
+    };
+    let snapshot = candid::encode_args((data, state)).expect("Failed to encode enhanced data before upgrade");
+    memory::save_upgrade_snapshot(&snapshot);
 }
 
+// Upgrade arguments with a non-empty admin list replace the persisted admin set.
 #[post_upgrade]
-fn post_upgrade() {
+fn post_upgrade(args: Option<InitArgs>) {
+    restore_upgraded_data();
+    governance::upgrade_governance(args);
+    retention::start_retention_timer();
+}
+
+// Layout written by pre_upgrade: product histories plus everything else.
+type UpgradeData = (HashMap<String, Vec<Step>>, Option<StableState>);
+
+fn restore_upgraded_data() {
+    // Releases before the memory manager saved the snapshot with stable_save at offset 0. Check
+    // before anything touches the memory manager, which formats stable memory on first use.
+    let legacy_layout = memory::is_legacy_layout();
+
     // Try restoring using the current Step type first. If that fails (older data without `user_id`),
     // attempt to restore using a legacy Step struct and convert.
-    let restored: Result<(HashMap<String, Vec<Step>>,), _> = ic_cdk::storage::stable_restore();
+    let restored: Result<UpgradeData, String> = if legacy_layout {
+        ic_cdk::storage::stable_restore()
+    } else {
+        memory::load_upgrade_snapshot()
+            .ok_or_else(|| "No upgrade snapshot".to_string())
+            .and_then(|bytes| candid::decode_args(&bytes).map_err(|e| e.to_string()))
+    };
 
-    if let Ok((data,)) = restored {
+    if let Ok((mut data, state)) = restored {
+        history::backfill_sequences(&mut data);
         PRODUCT_HISTORY.with(|store| {
             *store.borrow_mut() = data;
         });
+        restore_stable_state(state.unwrap_or_default());
+        history::backfill_owners();
 
         let product_count = PRODUCT_HISTORY.with(|store| store.borrow().len());
-        ic_cdk::println!("Enhanced BlockTrace backend upgraded - Restored {} products", product_count);
+        logs::info("lifecycle", "Canister upgraded", vec![("products", product_count.to_string())]);
         return;
     }
 
@@ -1066,7 +1574,8 @@ fn post_upgrade() {
         pub blockchain_hash: Option<String>,
     }
 
-    let legacy_restore: Result<(HashMap<String, Vec<StepLegacy>>,), _> = ic_cdk::storage::stable_restore();
+    let legacy_restore: Result<(HashMap<String, Vec<StepLegacy>>,), _> =
+        if legacy_layout { ic_cdk::storage::stable_restore() } else { Err("Not a legacy snapshot".to_string()) };
     if let Ok((legacy_data,)) = legacy_restore {
         let mut migrated: HashMap<String, Vec<Step>> = HashMap::new();
         for (k, v) in legacy_data.into_iter() {
@@ -1083,6 +1592,10 @@ fn post_upgrade() {
                     timestamp: s.timestamp,
                     status: s.status,
                     transport_mode: s.transport_mode,
+                    vehicle_class: None,
+                    fuel_type: None,
+                    load_tonnes: None,
+                    transport_owned: None,
                     temperature_celsius: s.temperature_celsius,
                     humidity_percent: s.humidity_percent,
                     gps_latitude: s.gps_latitude,
@@ -1096,21 +1609,30 @@ fn post_upgrade() {
                     distance_km: s.distance_km,
                     cost_usd: s.cost_usd,
                     blockchain_hash: s.blockchain_hash,
+                    serials: None,
+                    sequence: None,
+                    encryption: None,
                 });
             }
             migrated.insert(k, vec_new);
         }
+        history::backfill_sequences(&mut migrated);
 
         PRODUCT_HISTORY.with(|store| {
             *store.borrow_mut() = migrated;
         });
+        history::backfill_owners();
 
         let product_count = PRODUCT_HISTORY.with(|store| store.borrow().len());
-        ic_cdk::println!("Enhanced BlockTrace backend upgraded - Restored {} products (migrated legacy data)", product_count);
+        logs::info(
+            "lifecycle",
+            "Canister upgraded from legacy data",
+            vec![("products", product_count.to_string())],
+        );
         return;
     }
 
-    ic_cdk::println!("Enhanced BlockTrace backend upgraded - No previous data restored (fresh start)");
+    logs::warn("lifecycle", "Canister upgraded without restorable data", vec![]);
 }
 
 candid::export_service!();
@@ -1126,6 +1648,41 @@ mod tests {
     use super::*;
     use std::fs::write;
 
+    pub(crate) fn test_step(product_id: &str, user_id: &str, timestamp: u64) -> Step {
+        Step {
+            user_id: user_id.to_string(),
+            product_id: product_id.to_string(),
+            actor_name: "Test Actor".to_string(),
+            role: "Manufacturer".to_string(),
+            action: "Production Complete".to_string(),
+            location: "Factory A".to_string(),
+            notes: None,
+            timestamp,
+            status: Some("verified".to_string()),
+            transport_mode: None,
+            vehicle_class: None,
+            fuel_type: None,
+            load_tonnes: None,
+            transport_owned: None,
+            temperature_celsius: None,
+            humidity_percent: None,
+            gps_latitude: None,
+            gps_longitude: None,
+            batch_number: None,
+            certification_hash: None,
+            estimated_arrival: None,
+            actual_arrival: None,
+            quality_score: None,
+            carbon_footprint_kg: None,
+            distance_km: None,
+            cost_usd: None,
+            blockchain_hash: None,
+            serials: None,
+            sequence: None,
+            encryption: None,
+        }
+    }
+
     #[test]
     fn generate_did() {
         let did = export_candid();
@@ -0,0 +1,157 @@
+// 🪵 Structured event log kept in a bounded ring buffer.
+//
+// Replaces ad-hoc println debugging for the events operators care about (steps added, outcall
+// failures, timer activity). The buffer is saved with the upgrade snapshot; when it is full the
+// oldest entries are dropped.
+use candid::{candid_method, CandidType};
+use ic_cdk_macros::query;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+
+const MAX_LOG_ENTRIES: usize = 2_000;
+const DEFAULT_QUERY_LIMIT: u32 = 100;
+const MAX_QUERY_LIMIT: u32 = 500;
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct LogEntry {
+    pub id: u64,
+    pub timestamp: u64,
+    pub level: LogLevel,
+    pub component: String,
+    pub message: String,
+    pub fields: Vec<(String, String)>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct LogFilter {
+    // Entries at this level or above
+    pub min_level: Option<LogLevel>,
+    pub component: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<u32>,
+}
+
+thread_local! {
+    static LOG: RefCell<VecDeque<LogEntry>> = const { RefCell::new(VecDeque::new()) };
+    static NEXT_LOG_ID: RefCell<u64> = const { RefCell::new(0) };
+}
+
+fn push(timestamp: u64, level: LogLevel, component: &str, message: String, fields: Vec<(&str, String)>) {
+    let id = NEXT_LOG_ID.with(|n| {
+        let mut n = n.borrow_mut();
+        *n += 1;
+        *n
+    });
+    LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        if log.len() >= MAX_LOG_ENTRIES {
+            log.pop_front();
+        }
+        log.push_back(LogEntry {
+            id,
+            timestamp,
+            level,
+            component: component.to_string(),
+            message,
+            fields: fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+        });
+    });
+}
+
+// Entries written by native unit tests, which have no system clock, are stamped 0.
+fn now() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        ic_cdk::api::time()
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+pub fn log(level: LogLevel, component: &str, message: impl Into<String>, fields: Vec<(&str, String)>) {
+    push(now(), level, component, message.into(), fields);
+}
+
+pub fn info(component: &str, message: impl Into<String>, fields: Vec<(&str, String)>) {
+    log(LogLevel::Info, component, message, fields);
+}
+
+pub fn warn(component: &str, message: impl Into<String>, fields: Vec<(&str, String)>) {
+    log(LogLevel::Warn, component, message, fields);
+}
+
+pub fn error(component: &str, message: impl Into<String>, fields: Vec<(&str, String)>) {
+    log(LogLevel::Error, component, message, fields);
+}
+
+fn matches(filter: &LogFilter, entry: &LogEntry) -> bool {
+    filter.min_level.is_none_or(|level| entry.level >= level)
+        && filter.component.as_ref().is_none_or(|c| entry.component.eq_ignore_ascii_case(c))
+        && filter.since.is_none_or(|since| entry.timestamp >= since)
+        && filter.until.is_none_or(|until| entry.timestamp <= until)
+}
+
+fn query_log(filter: &LogFilter) -> Vec<LogEntry> {
+    let limit = filter.limit.unwrap_or(DEFAULT_QUERY_LIMIT).min(MAX_QUERY_LIMIT) as usize;
+    LOG.with(|log| log.borrow().iter().rev().filter(|e| matches(filter, e)).take(limit).cloned().collect())
+}
+
+pub(crate) fn snapshot() -> Vec<LogEntry> {
+    LOG.with(|log| log.borrow().iter().cloned().collect())
+}
+
+pub(crate) fn restore(entries: Vec<LogEntry>) {
+    let next_id = entries.last().map(|e| e.id).unwrap_or(0);
+    NEXT_LOG_ID.with(|n| *n.borrow_mut() = next_id);
+    LOG.with(|log| *log.borrow_mut() = entries.into_iter().collect());
+}
+
+// Newest first. Entries carry principals and product IDs, so only admins may read them.
+#[query]
+#[candid_method(query)]
+fn get_logs(filter: LogFilter) -> ApiResult<Vec<LogEntry>> {
+    if !is_admin(&format!("{}", ic_cdk::caller())) {
+        return Err(BlockTraceError::unauthorized("Only admins can read the event log"));
+    }
+    Ok(query_log(&filter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_buffer_drops_oldest_and_filters() {
+        for i in 0..(MAX_LOG_ENTRIES as u64 + 5) {
+            let level = if i % 2 == 0 { LogLevel::Info } else { LogLevel::Error };
+            push(i, level, if i % 3 == 0 { "timers" } else { "steps" }, format!("event {}", i), vec![("i", i.to_string())]);
+        }
+        assert_eq!(snapshot().len(), MAX_LOG_ENTRIES);
+        assert_eq!(snapshot()[0].timestamp, 5);
+
+        let filter = LogFilter {
+            min_level: Some(LogLevel::Warn),
+            component: Some("timers".to_string()),
+            since: Some(1_000),
+            until: Some(1_010),
+            limit: None,
+        };
+        let timestamps: Vec<u64> = query_log(&filter).iter().map(|e| e.timestamp).collect();
+        assert_eq!(timestamps, vec![1_005]);
+    }
+}
@@ -0,0 +1,80 @@
+// 💾 Stable memory layout.
+//
+// A MemoryManager splits stable memory into virtual memories. One holds the candid snapshot that
+// pre_upgrade writes for heap state; the others back structures that live in stable memory
+// directly and survive upgrades without being copied through the heap. Canisters installed before
+// this layout wrote the snapshot with `stable_save` at offset 0, which `is_legacy_layout` detects
+// so post_upgrade can read it before the memory manager claims stable memory.
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, Memory as _};
+use std::cell::RefCell;
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+pub const UPGRADES: MemoryId = MemoryId::new(0);
+pub const AUDIT_LOG_INDEX: MemoryId = MemoryId::new(1);
+pub const AUDIT_LOG_DATA: MemoryId = MemoryId::new(2);
+pub const RETENTION_ARCHIVE: MemoryId = MemoryId::new(3);
+
+const WASM_PAGE_SIZE: u64 = 65_536;
+const MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+/// True when stable memory holds a `stable_save` snapshot from before the memory manager existed.
+/// Must be called before anything touches a virtual memory, which would format stable memory.
+pub fn is_legacy_layout() -> bool {
+    let raw = DefaultMemoryImpl::default();
+    if raw.size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 3];
+    raw.read(0, &mut magic);
+    &magic != MANAGER_MAGIC
+}
+
+/// Writes the upgrade snapshot, length-prefixed, to the start of the UPGRADES memory.
+pub fn save_upgrade_snapshot(bytes: &[u8]) {
+    let memory = get(UPGRADES);
+    let pages = (8 + bytes.len() as u64).div_ceil(WASM_PAGE_SIZE);
+    if memory.size() < pages && memory.grow(pages - memory.size()) < 0 {
+        ic_cdk::trap("Out of stable memory for the upgrade snapshot");
+    }
+    memory.write(0, &(bytes.len() as u64).to_le_bytes());
+    memory.write(8, bytes);
+}
+
+pub fn load_upgrade_snapshot() -> Option<Vec<u8>> {
+    let memory = get(UPGRADES);
+    if memory.size() == 0 {
+        return None;
+    }
+    let mut length = [0; 8];
+    memory.read(0, &mut length);
+    let mut bytes = vec![0; u64::from_le_bytes(length) as usize];
+    memory.read(8, &mut bytes);
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrade_snapshot_round_trips() {
+        assert!(!is_legacy_layout());
+        assert_eq!(load_upgrade_snapshot(), None);
+        let large = vec![7u8; 3 * WASM_PAGE_SIZE as usize];
+        save_upgrade_snapshot(&large);
+        assert_eq!(load_upgrade_snapshot(), Some(large));
+        save_upgrade_snapshot(b"DIDL");
+        assert_eq!(load_upgrade_snapshot().as_deref(), Some(&b"DIDL"[..]));
+    }
+}
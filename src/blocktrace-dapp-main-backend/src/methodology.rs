@@ -0,0 +1,276 @@
+// 📐 Versioned ESG scoring methodology.
+//
+// A score starts from the methodology's base and each named component adds its weight in points
+// (negative for penalties) scaled by how far its input has moved from `from_value` towards
+// `to_value`. Every score reports the version it was computed with and the points of each
+// component, and published versions are never modified, so any historical score can be recomputed
+// and explained. Version 1 is the formula the canister used before methodologies existed.
+use candid::{candid_method, CandidType};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+use crate::logs;
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum ScoreInput {
+    TotalDistanceKm,
+    CarbonFootprintKg,
+    StepCount,
+    // Mean quality_score of steps that recorded one
+    AverageQualityScore,
+    // Fraction (0-1) of steps recorded under a certificate
+    CertifiedStepShare,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ScoringComponent {
+    pub name: String,
+    pub description: String,
+    pub input: ScoreInput,
+    pub weight: f64,
+    pub from_value: f64,
+    pub to_value: f64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EsgMethodologyInput {
+    pub name: String,
+    pub description: String,
+    pub base_score: f64,
+    pub components: Vec<ScoringComponent>,
+    // Emissions of a conventional supply chain relative to this one, for the CO₂-saved figure
+    pub conventional_carbon_multiplier: f64,
+    pub multiplier_rationale: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EsgMethodology {
+    pub version: u32,
+    pub name: String,
+    pub description: String,
+    pub base_score: f64,
+    pub components: Vec<ScoringComponent>,
+    pub conventional_carbon_multiplier: f64,
+    pub multiplier_rationale: String,
+    pub published_by: String,
+    pub published_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ComponentScore {
+    pub name: String,
+    pub input: ScoreInput,
+    pub input_value: Option<f64>,
+    pub weight: f64,
+    pub points: f64,
+    pub explanation: String,
+}
+
+// Measured values the components read from.
+#[derive(Clone, Debug, Default)]
+pub struct ScoreInputs {
+    pub total_distance_km: f64,
+    pub carbon_footprint_kg: f64,
+    pub step_count: u32,
+    pub average_quality_score: Option<f64>,
+    pub certified_step_share: f64,
+}
+
+impl ScoreInputs {
+    fn value(&self, input: ScoreInput) -> Option<f64> {
+        match input {
+            ScoreInput::TotalDistanceKm => Some(self.total_distance_km),
+            ScoreInput::CarbonFootprintKg => Some(self.carbon_footprint_kg),
+            ScoreInput::StepCount => Some(self.step_count as f64),
+            ScoreInput::AverageQualityScore => self.average_quality_score,
+            ScoreInput::CertifiedStepShare => Some(self.certified_step_share),
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct MethodologyState {
+    pub versions: BTreeMap<u32, EsgMethodology>,
+}
+
+fn legacy() -> EsgMethodology {
+    EsgMethodology {
+        version: 1,
+        name: "BlockTrace legacy".to_string(),
+        description: "Distance penalty and traceability bonus on a 100-point base".to_string(),
+        base_score: 100.0,
+        components: vec![
+            ScoringComponent {
+                name: "Transport distance".to_string(),
+                description: "Up to 30 points off, one per 100 km travelled".to_string(),
+                input: ScoreInput::TotalDistanceKm,
+                weight: -30.0,
+                from_value: 0.0,
+                to_value: 3_000.0,
+            },
+            ScoringComponent {
+                name: "Traceability depth".to_string(),
+                description: "Up to 20 points, two per recorded step".to_string(),
+                input: ScoreInput::StepCount,
+                weight: 20.0,
+                from_value: 0.0,
+                to_value: 10.0,
+            },
+        ],
+        conventional_carbon_multiplier: 1.3,
+        multiplier_rationale: "Assumes conventional, untracked supply chains emit 30% more through unoptimised routing".to_string(),
+        published_by: "system".to_string(),
+        published_at: 0,
+    }
+}
+
+impl Default for MethodologyState {
+    fn default() -> Self {
+        MethodologyState { versions: BTreeMap::from([(1, legacy())]) }
+    }
+}
+
+thread_local! {
+    static METHODOLOGIES: RefCell<MethodologyState> = RefCell::new(MethodologyState::default());
+}
+
+pub(crate) fn snapshot() -> MethodologyState {
+    METHODOLOGIES.with(|m| m.borrow().clone())
+}
+
+pub(crate) fn restore(state: MethodologyState) {
+    METHODOLOGIES.with(|m| *m.borrow_mut() = state);
+}
+
+/// The requested version, or the latest published one.
+pub fn methodology(version: Option<u32>) -> Option<EsgMethodology> {
+    METHODOLOGIES.with(|m| {
+        let state = m.borrow();
+        match version {
+            Some(v) => state.versions.get(&v).cloned(),
+            None => state.versions.values().next_back().cloned(),
+        }
+    })
+}
+
+/// Score (0-100) and per-component points for `inputs` under `methodology`.
+pub fn score(methodology: &EsgMethodology, inputs: &ScoreInputs) -> (u8, Vec<ComponentScore>) {
+    let components: Vec<ComponentScore> = methodology
+        .components
+        .iter()
+        .map(|component| {
+            let value = inputs.value(component.input);
+            let (points, explanation) = match value {
+                Some(v) => {
+                    let progress = ((v - component.from_value) / (component.to_value - component.from_value)).clamp(0.0, 1.0);
+                    let points = component.weight * progress;
+                    (points, format!("{:.2} is {:.0}% of the way from {} to {}: {:+.1} points", v, progress * 100.0, component.from_value, component.to_value, points))
+                }
+                None => (0.0, "No data recorded; component contributes nothing".to_string()),
+            };
+            ComponentScore { name: component.name.clone(), input: component.input, input_value: value, weight: component.weight, points, explanation }
+        })
+        .collect();
+    let total = methodology.base_score + components.iter().map(|c| c.points).sum::<f64>();
+    (total.clamp(0.0, 100.0) as u8, components)
+}
+
+fn check_input(input: &EsgMethodologyInput) -> ApiResult<()> {
+    if input.name.trim().is_empty() {
+        return Err(BlockTraceError::invalid("name", "cannot be empty"));
+    }
+    if !(0.0..=100.0).contains(&input.base_score) {
+        return Err(BlockTraceError::invalid("base_score", "must be between 0 and 100"));
+    }
+    if input.components.is_empty() {
+        return Err(BlockTraceError::invalid("components", "at least one component is required"));
+    }
+    for (i, component) in input.components.iter().enumerate() {
+        if component.name.trim().is_empty() || input.components[..i].iter().any(|c| c.name == component.name) {
+            return Err(BlockTraceError::invalid("components", "names must be non-empty and unique"));
+        }
+        if !component.weight.is_finite() || !component.from_value.is_finite() || !component.to_value.is_finite() {
+            return Err(BlockTraceError::invalid("components", format!("'{}' has a non-finite value", component.name)));
+        }
+        if component.from_value == component.to_value {
+            return Err(BlockTraceError::invalid("components", format!("'{}' needs distinct from_value and to_value", component.name)));
+        }
+    }
+    if !input.conventional_carbon_multiplier.is_finite() || input.conventional_carbon_multiplier < 1.0 {
+        return Err(BlockTraceError::invalid("conventional_carbon_multiplier", "must be at least 1"));
+    }
+    if input.multiplier_rationale.trim().is_empty() {
+        return Err(BlockTraceError::invalid("multiplier_rationale", "explain where the multiplier comes from"));
+    }
+    Ok(())
+}
+
+// Publishes the next version, which becomes the default for new scores.
+#[update]
+#[candid_method(update)]
+fn publish_esg_methodology(input: EsgMethodologyInput) -> ApiResult<EsgMethodology> {
+    let caller = format!("{}", ic_cdk::caller());
+    if !is_admin(&caller) {
+        return Err(BlockTraceError::unauthorized("Only an admin can publish ESG methodologies"));
+    }
+    check_input(&input)?;
+    let published = METHODOLOGIES.with(|m| {
+        let mut state = m.borrow_mut();
+        let version = state.versions.keys().next_back().copied().unwrap_or(0) + 1;
+        let methodology = EsgMethodology {
+            version,
+            name: input.name.trim().to_string(),
+            description: input.description,
+            base_score: input.base_score,
+            components: input.components,
+            conventional_carbon_multiplier: input.conventional_carbon_multiplier,
+            multiplier_rationale: input.multiplier_rationale,
+            published_by: caller.clone(),
+            published_at: time(),
+        };
+        state.versions.insert(version, methodology.clone());
+        methodology
+    });
+    logs::info(
+        "esg",
+        "ESG methodology published",
+        vec![("version", published.version.to_string()), ("name", published.name.clone()), ("published_by", caller)],
+    );
+    Ok(published)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_esg_methodology(version: Option<u32>) -> Option<EsgMethodology> {
+    methodology(version)
+}
+
+#[query]
+#[candid_method(query)]
+fn list_esg_methodologies() -> Vec<EsgMethodology> {
+    METHODOLOGIES.with(|m| m.borrow().versions.values().cloned().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_version_reproduces_original_formula() {
+        let original = |distance: f64, steps: u32| (100.0 - (distance / 100.0).min(30.0) + (steps as f64 * 2.0).min(20.0)).clamp(0.0, 100.0) as u8;
+        for (distance, steps) in [(0.0, 1), (1_250.0, 3), (2_999.0, 12), (8_000.0, 4)] {
+            let inputs = ScoreInputs { total_distance_km: distance, step_count: steps, ..Default::default() };
+            let (score, components) = score(&legacy(), &inputs);
+            assert_eq!(score, original(distance, steps));
+            assert_eq!(components.len(), 2);
+        }
+        let (_, components) = score(&legacy(), &ScoreInputs { total_distance_km: 1_500.0, step_count: 2, ..Default::default() });
+        assert!((components[0].points + 15.0).abs() < 1e-9);
+        assert!((components[1].points - 4.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,190 @@
+// 📈 Operational counters and canister stats, as a candid record (`get_stats`) and in
+// Prometheus text format on `GET /metrics`.
+//
+// Counters are monotonic and persisted across upgrades; gauges are read when the stats are
+// collected.
+use candid::{candid_method, CandidType};
+use ic_cdk::api::time;
+use ic_cdk_macros::query;
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+use crate::{AUTOMATED_ESG_UPDATES, CROSS_CHAIN_PROOFS, ESG_TIMERS, HTTP_OUTCALL_CACHE, PRODUCT_HISTORY, SUPPLIER_VERIFICATIONS};
+
+const WASM_PAGE_BYTES: u64 = 65_536;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Counter {
+    CacheHit,
+    CacheMiss,
+    OutcallSucceeded,
+    OutcallFailed,
+    SignatureIssued,
+    TimerRun,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct MetricCounters {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    pub outcalls_succeeded: u64,
+    pub outcalls_failed: u64,
+    pub signatures_issued: u64,
+    pub timer_runs: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct CanisterStats {
+    pub products: u64,
+    pub steps: u64,
+    pub supplier_verifications: u64,
+    pub cross_chain_proofs: u64,
+    pub automated_esg_updates: u64,
+    pub active_esg_timers: u64,
+    pub cache_entries: u64,
+    // Hits over lookups; 0 before the first lookup
+    pub cache_hit_rate: f64,
+    pub counters: MetricCounters,
+    pub heap_memory_bytes: u64,
+    pub stable_memory_bytes: u64,
+    pub cycle_balance: u128,
+    pub collected_at: u64,
+}
+
+thread_local! {
+    static COUNTERS: RefCell<MetricCounters> = RefCell::new(MetricCounters::default());
+}
+
+pub fn increment(counter: Counter) {
+    COUNTERS.with(|c| {
+        let mut c = c.borrow_mut();
+        let value = match counter {
+            Counter::CacheHit => &mut c.cache_hits,
+            Counter::CacheMiss => &mut c.cache_misses,
+            Counter::OutcallSucceeded => &mut c.outcalls_succeeded,
+            Counter::OutcallFailed => &mut c.outcalls_failed,
+            Counter::SignatureIssued => &mut c.signatures_issued,
+            Counter::TimerRun => &mut c.timer_runs,
+        };
+        *value += 1;
+    });
+}
+
+pub(crate) fn snapshot() -> MetricCounters {
+    COUNTERS.with(|c| c.borrow().clone())
+}
+
+pub(crate) fn restore(counters: MetricCounters) {
+    COUNTERS.with(|c| *c.borrow_mut() = counters);
+}
+
+fn heap_memory_bytes() -> u64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        core::arch::wasm32::memory_size(0) as u64 * WASM_PAGE_BYTES
+    }
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        0
+    }
+}
+
+pub fn collect_stats() -> CanisterStats {
+    let counters = snapshot();
+    let lookups = counters.cache_hits + counters.cache_misses;
+    CanisterStats {
+        products: PRODUCT_HISTORY.with(|s| s.borrow().len() as u64),
+        steps: PRODUCT_HISTORY.with(|s| s.borrow().values().map(|v| v.len() as u64).sum()),
+        supplier_verifications: SUPPLIER_VERIFICATIONS.with(|s| s.borrow().len() as u64),
+        cross_chain_proofs: CROSS_CHAIN_PROOFS.with(|s| s.borrow().len() as u64),
+        automated_esg_updates: AUTOMATED_ESG_UPDATES.with(|u| u.borrow().len() as u64),
+        active_esg_timers: ESG_TIMERS.with(|t| t.borrow().len() as u64),
+        cache_entries: HTTP_OUTCALL_CACHE.with(|c| c.borrow().len() as u64),
+        cache_hit_rate: if lookups == 0 { 0.0 } else { counters.cache_hits as f64 / lookups as f64 },
+        counters,
+        heap_memory_bytes: heap_memory_bytes(),
+        stable_memory_bytes: ic_cdk::api::stable::stable64_size() * WASM_PAGE_BYTES,
+        cycle_balance: ic_cdk::api::canister_balance128(),
+        collected_at: time(),
+    }
+}
+
+fn push_metric(out: &mut String, name: &str, kind: &str, help: &str, samples: &[(&str, String)]) {
+    out.push_str(&format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind));
+    for (labels, value) in samples {
+        if labels.is_empty() {
+            out.push_str(&format!("{} {}\n", name, value));
+        } else {
+            out.push_str(&format!("{}{{{}}} {}\n", name, labels, value));
+        }
+    }
+}
+
+pub fn render_prometheus(stats: &CanisterStats) -> String {
+    let c = &stats.counters;
+    let mut out = String::new();
+    let gauge = |v: u64| v.to_string();
+    push_metric(&mut out, "blocktrace_products", "gauge", "Products with at least one step.", &[("", gauge(stats.products))]);
+    push_metric(&mut out, "blocktrace_steps", "gauge", "Live supply chain steps.", &[("", gauge(stats.steps))]);
+    push_metric(&mut out, "blocktrace_supplier_verifications", "gauge", "Stored supplier verifications.", &[("", gauge(stats.supplier_verifications))]);
+    push_metric(&mut out, "blocktrace_cross_chain_proofs", "gauge", "Stored cross-chain proofs.", &[("", gauge(stats.cross_chain_proofs))]);
+    push_metric(&mut out, "blocktrace_automated_esg_updates", "gauge", "Recorded automated ESG score changes.", &[("", gauge(stats.automated_esg_updates))]);
+    push_metric(&mut out, "blocktrace_active_esg_timers", "gauge", "Scheduled per-product ESG timers.", &[("", gauge(stats.active_esg_timers))]);
+    push_metric(&mut out, "blocktrace_http_cache_entries", "gauge", "Entries in the HTTP outcall cache.", &[("", gauge(stats.cache_entries))]);
+    push_metric(
+        &mut out,
+        "blocktrace_http_cache_lookups_total",
+        "counter",
+        "HTTP outcall cache lookups by result.",
+        &[("result=\"hit\"", c.cache_hits.to_string()), ("result=\"miss\"", c.cache_misses.to_string())],
+    );
+    push_metric(&mut out, "blocktrace_http_cache_hit_ratio", "gauge", "Cache hits over lookups.", &[("", format!("{:.4}", stats.cache_hit_rate))]);
+    push_metric(
+        &mut out,
+        "blocktrace_http_outcalls_total",
+        "counter",
+        "HTTP outcalls by outcome.",
+        &[("outcome=\"success\"", c.outcalls_succeeded.to_string()), ("outcome=\"failure\"", c.outcalls_failed.to_string())],
+    );
+    push_metric(&mut out, "blocktrace_signatures_issued_total", "counter", "Threshold ECDSA signatures issued.", &[("", c.signatures_issued.to_string())]);
+    push_metric(&mut out, "blocktrace_timer_runs_total", "counter", "Timer callbacks executed.", &[("", c.timer_runs.to_string())]);
+    push_metric(&mut out, "blocktrace_heap_memory_bytes", "gauge", "Wasm heap memory in use.", &[("", gauge(stats.heap_memory_bytes))]);
+    push_metric(&mut out, "blocktrace_stable_memory_bytes", "gauge", "Stable memory in use.", &[("", gauge(stats.stable_memory_bytes))]);
+    push_metric(&mut out, "blocktrace_cycle_balance", "gauge", "Canister cycle balance.", &[("", stats.cycle_balance.to_string())]);
+    out
+}
+
+#[query]
+#[candid_method(query)]
+fn get_stats() -> CanisterStats {
+    collect_stats()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prometheus_output_has_labelled_counters() {
+        increment(Counter::OutcallFailed);
+        let stats = CanisterStats {
+            products: 2,
+            steps: 5,
+            supplier_verifications: 0,
+            cross_chain_proofs: 0,
+            automated_esg_updates: 0,
+            active_esg_timers: 1,
+            cache_entries: 0,
+            cache_hit_rate: 0.0,
+            counters: snapshot(),
+            heap_memory_bytes: 0,
+            stable_memory_bytes: 0,
+            cycle_balance: 1_000_000,
+            collected_at: 0,
+        };
+        let text = render_prometheus(&stats);
+        assert!(text.contains("# TYPE blocktrace_steps gauge\nblocktrace_steps 5\n"));
+        assert!(text.contains("blocktrace_http_outcalls_total{outcome=\"failure\"} 1\n"));
+        assert!(text.contains("blocktrace_cycle_balance 1000000\n"));
+    }
+}
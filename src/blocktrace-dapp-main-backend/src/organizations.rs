@@ -0,0 +1,75 @@
+// 🏢 Organization membership. Principals belong to their own single-member organization unless
+// an admin groups them under a shared organization id.
+use candid::candid_method;
+use ic_cdk_macros::{query, update};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+
+thread_local! {
+    static ORGANIZATION_MEMBERS: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+}
+
+pub fn organization_of(principal: &str) -> String {
+    ORGANIZATION_MEMBERS.with(|members| {
+        members.borrow().get(principal).cloned().unwrap_or_else(|| principal.to_string())
+    })
+}
+
+pub(crate) fn snapshot() -> HashMap<String, String> {
+    ORGANIZATION_MEMBERS.with(|members| members.borrow().clone())
+}
+
+pub(crate) fn restore(members: HashMap<String, String>) {
+    ORGANIZATION_MEMBERS.with(|store| *store.borrow_mut() = members);
+}
+
+#[update]
+#[candid_method(update)]
+fn set_organization_membership(principal: String, organization_id: String) -> ApiResult<String> {
+    if !is_admin(&format!("{}", ic_cdk::caller())) {
+        return Err(BlockTraceError::unauthorized("Only an admin can manage organizations"));
+    }
+    if principal.trim().is_empty() || organization_id.trim().is_empty() {
+        return Err(BlockTraceError::invalid("organization_id", "principal and organization id cannot be empty"));
+    }
+    ORGANIZATION_MEMBERS.with(|members| {
+        members.borrow_mut().insert(principal.clone(), organization_id.trim().to_string());
+    });
+    Ok(format!("{} is now a member of {}", principal, organization_id.trim()))
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_organization_membership(principal: String) -> ApiResult<String> {
+    if !is_admin(&format!("{}", ic_cdk::caller())) {
+        return Err(BlockTraceError::unauthorized("Only an admin can manage organizations"));
+    }
+    match ORGANIZATION_MEMBERS.with(|members| members.borrow_mut().remove(&principal)) {
+        Some(organization_id) => Ok(format!("{} removed from {}", principal, organization_id)),
+        None => Err(BlockTraceError::not_found("Organization membership", principal)),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn get_organization_of(principal: String) -> String {
+    organization_of(&principal)
+}
+
+#[query]
+#[candid_method(query)]
+fn list_organization_members(organization_id: String) -> Vec<String> {
+    ORGANIZATION_MEMBERS.with(|members| {
+        let mut list: Vec<String> = members
+            .borrow()
+            .iter()
+            .filter(|(_, org)| **org == organization_id)
+            .map(|(principal, _)| principal.clone())
+            .collect();
+        list.sort();
+        list
+    })
+}
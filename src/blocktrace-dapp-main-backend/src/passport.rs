@@ -0,0 +1,296 @@
+// 🛂 Product passport served straight from the canister over the HTTP gateway.
+//
+//   GET /p/{product_id}          HTML passport (JSON with `?format=json` or `Accept: application/json`)
+//   GET /p/{product_id}/qr.svg   SVG QR code linking to the passport
+//   GET /metrics                 Prometheus text exposition of the canister stats
+//
+// The passport is the public product view, so the owner's disclosure settings apply. Responses
+// are not certified; serve them through the `raw` gateway domain. QR codes link to the base URL
+// an admin configured, or to the canister's raw domain, never to the request's Host header:
+// responses are publicly cacheable, so a forged Host would otherwise end up in cached codes.
+use candid::{candid_method, CandidType};
+use ic_cdk_macros::{query, update};
+use qrcode::render::svg;
+use qrcode::QrCode;
+use serde::Deserialize;
+use std::cell::RefCell;
+
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+use crate::metrics;
+use crate::public_view::{public_product_view, PublicProductView, PublicStep};
+
+thread_local! {
+    static PUBLIC_BASE_URL: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub(crate) fn snapshot() -> Option<String> {
+    PUBLIC_BASE_URL.with(|url| url.borrow().clone())
+}
+
+pub(crate) fn restore(base_url: Option<String>) {
+    PUBLIC_BASE_URL.with(|url| *url.borrow_mut() = base_url);
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpRequest {
+    pub method: String,
+    pub url: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct HttpGatewayResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl HttpGatewayResponse {
+    pub fn new(status_code: u16, content_type: &str, body: Vec<u8>) -> Self {
+        HttpGatewayResponse {
+            status_code,
+            headers: vec![
+                ("Content-Type".to_string(), content_type.to_string()),
+                ("Cache-Control".to_string(), "public, max-age=60".to_string()),
+                ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+            ],
+            body,
+        }
+    }
+
+    fn text(status_code: u16, message: &str) -> Self {
+        Self::new(status_code, "text/plain; charset=utf-8", message.as_bytes().to_vec())
+    }
+}
+
+fn header<'a>(request: &'a HttpRequest, name: &str) -> Option<&'a str> {
+    request.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+}
+
+fn percent_decode(input: &str) -> String {
+    let bytes = input.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            if let Some(byte) = input.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                out.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        out.push(if bytes[i] == b'+' { b' ' } else { bytes[i] });
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn percent_encode(input: &str) -> String {
+    input
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn escape_html(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn passport_url(product_id: &str) -> String {
+    let base_url = snapshot().unwrap_or_else(|| format!("https://{}.raw.icp0.io", ic_cdk::id()));
+    format!("{}/p/{}", base_url, percent_encode(product_id))
+}
+
+fn render_step(step: &PublicStep) -> String {
+    let mut details: Vec<String> = Vec::new();
+    if let Some(location) = &step.location {
+        details.push(escape_html(location));
+    }
+    if let Some(actor) = &step.actor_name {
+        details.push(escape_html(actor));
+    }
+    if let Some(mode) = &step.transport_mode {
+        details.push(format!("via {}", escape_html(mode)));
+    }
+    if let Some(carbon) = step.carbon_footprint_kg {
+        details.push(format!("{:.2} kg CO₂e", carbon));
+    }
+    format!(
+        "<li><strong>{}</strong> <small>({:?})</small><br>{}</li>",
+        escape_html(&step.action),
+        step.stage,
+        details.join(" · ")
+    )
+}
+
+fn render_html(view: &PublicProductView) -> String {
+    let milestones: String = view.milestones.iter().map(render_step).collect();
+    let certifications: String = if view.certifications.is_empty() {
+        "<p>No registered certifications.</p>".to_string()
+    } else {
+        let items: String = view
+            .certifications
+            .iter()
+            .map(|c| format!("<li>{} — {} ({:?})</li>", escape_html(&c.standard), escape_html(&c.issuer), c.status))
+            .collect();
+        format!("<ul>{}</ul>", items)
+    };
+    let esg = view
+        .esg_score
+        .as_ref()
+        .map(|s| {
+            let emissions = match (s.carbon_footprint_kg, s.total_distance_km) {
+                (Some(carbon), Some(distance)) => format!(" · {:.2} kg CO₂e over {:.0} km", carbon, distance),
+                _ => String::new(),
+            };
+            format!("<p>Sustainability score: <strong>{}/100</strong>{}</p>", s.sustainability_score, emissions)
+        })
+        .unwrap_or_default();
+    let id = escape_html(&view.product_id);
+    format!(
+        "<!DOCTYPE html><html lang=\"en\"><head><meta charset=\"utf-8\"><meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\
+<title>Product passport {id}</title></head><body style=\"font-family:sans-serif;max-width:40rem;margin:auto;padding:1rem\">\
+<h1>Product passport</h1><p><code>{id}</code> · current stage: {stage:?}</p>{esg}\
+<h2>Journey</h2><ol>{milestones}</ol><h2>Certifications</h2>{certifications}\
+<p><img src=\"/p/{encoded}/qr.svg\" alt=\"QR code\" width=\"160\" height=\"160\"></p></body></html>",
+        id = id,
+        stage = view.current_stage,
+        esg = esg,
+        milestones = milestones,
+        certifications = certifications,
+        encoded = percent_encode(&view.product_id),
+    )
+}
+
+pub fn qr_svg(content: &str) -> Result<String, String> {
+    let code = QrCode::new(content.as_bytes()).map_err(|e| format!("QR encoding failed: {}", e))?;
+    Ok(code
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .quiet_zone(true)
+        .build())
+}
+
+fn passport_response(request: &HttpRequest, product_id: &str, query: &str) -> HttpGatewayResponse {
+    let Some(view) = public_product_view(product_id) else {
+        return HttpGatewayResponse::text(404, "Product not found");
+    };
+    let wants_json = query.split('&').any(|p| p == "format=json")
+        || (!query.split('&').any(|p| p == "format=html")
+            && header(request, "Accept").is_some_and(|a| a.contains("application/json") && !a.contains("text/html")));
+    if wants_json {
+        match serde_json::to_vec(&view) {
+            Ok(body) => HttpGatewayResponse::new(200, "application/json", body),
+            Err(e) => HttpGatewayResponse::text(500, &format!("Serialization failed: {}", e)),
+        }
+    } else {
+        HttpGatewayResponse::new(200, "text/html; charset=utf-8", render_html(&view).into_bytes())
+    }
+}
+
+pub fn handle(request: &HttpRequest) -> HttpGatewayResponse {
+    let mut response = match request.method.as_str() {
+        "GET" | "HEAD" => route(request),
+        _ => HttpGatewayResponse::text(405, "Method not allowed"),
+    };
+    // HEAD answers with the headers GET would send, without the body
+    if request.method == "HEAD" {
+        response.body.clear();
+    }
+    response
+}
+
+fn route(request: &HttpRequest) -> HttpGatewayResponse {
+    let (path, query) = request.url.split_once('?').unwrap_or((request.url.as_str(), ""));
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    match segments.as_slice() {
+        ["p", product_id] => passport_response(request, &percent_decode(product_id), query),
+        ["p", product_id, "qr.svg"] => {
+            let product_id = percent_decode(product_id);
+            if public_product_view(&product_id).is_none() {
+                return HttpGatewayResponse::text(404, "Product not found");
+            }
+            match qr_svg(&passport_url(&product_id)) {
+                Ok(svg) => HttpGatewayResponse::new(200, "image/svg+xml", svg.into_bytes()),
+                Err(e) => HttpGatewayResponse::text(500, &e),
+            }
+        }
+        ["metrics"] => {
+            let mut response = HttpGatewayResponse::new(
+                200,
+                "text/plain; version=0.0.4; charset=utf-8",
+                metrics::render_prometheus(&metrics::collect_stats()).into_bytes(),
+            );
+            response.headers.retain(|(name, _)| name != "Cache-Control");
+            response.headers.push(("Cache-Control".to_string(), "no-store".to_string()));
+            response
+        }
+        _ => HttpGatewayResponse::text(404, "Not found"),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn http_request(request: HttpRequest) -> HttpGatewayResponse {
+    handle(&request)
+}
+
+// Base URL (scheme and domain, e.g. a custom domain pointing at the canister) that passport QR
+// codes link to. None reverts to the canister's raw domain.
+#[update]
+#[candid_method(update)]
+fn set_passport_base_url(base_url: Option<String>) -> ApiResult<Option<String>> {
+    if !is_admin(&format!("{}", ic_cdk::caller())) {
+        return Err(BlockTraceError::unauthorized("Only an admin can change the passport base URL"));
+    }
+    let base_url = base_url.map(|url| url.trim().trim_end_matches('/').to_string());
+    if let Some(url) = base_url.as_deref() {
+        let host = url.strip_prefix("https://").or_else(|| url.strip_prefix("http://")).unwrap_or_default();
+        if host.is_empty() || host.contains(['/', '?', '#', ' ']) {
+            return Err(BlockTraceError::invalid("base_url", "must be an http(s) origin such as https://passport.example.com"));
+        }
+    }
+    restore(base_url.clone());
+    Ok(base_url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths_and_markup_are_encoded() {
+        assert_eq!(percent_decode("PROD%2D001%20A"), "PROD-001 A");
+        assert_eq!(percent_encode("PROD 001/A"), "PROD%20001%2FA");
+        assert_eq!(escape_html("<b>\"x\"</b>"), "&lt;b&gt;&quot;x&quot;&lt;/b&gt;");
+        assert!(qr_svg("https://example.org/p/PROD-001").unwrap().starts_with("<?xml"));
+    }
+
+    #[test]
+    fn links_ignore_the_host_header_and_head_has_no_body() {
+        restore(Some("https://passport.example.com".to_string()));
+        assert_eq!(passport_url("PROD 1"), "https://passport.example.com/p/PROD%201");
+
+        let request = |method: &str| HttpRequest {
+            method: method.to_string(),
+            url: "/unknown".to_string(),
+            headers: vec![("Host".to_string(), "attacker.example".to_string())],
+            body: vec![],
+        };
+        let get = handle(&request("GET"));
+        let head = handle(&request("HEAD"));
+        assert_eq!((head.status_code, &head.headers), (get.status_code, &get.headers));
+        assert!(!get.body.is_empty());
+        assert!(head.body.is_empty());
+    }
+}
@@ -0,0 +1,281 @@
+// 🌍 Consumer-facing product view, e.g. behind a QR code on the packaging.
+//
+// Only what the product owner discloses about its own steps is returned, and nothing until the
+// owner publishes the product. Costs, notes, encrypted fields and principals are never part of
+// the public view, whatever the disclosure settings say.
+use candid::{candid_method, CandidType};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::certificates::{self, CertificateStatus};
+use crate::encryption::ENCRYPTED_PLACEHOLDER;
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::history::{self, LifecycleStage};
+use crate::{calculate_esg_score, ESGScore, Step};
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub enum DisclosableField {
+    Location,
+    ActorName,
+    Role,
+    Timestamp,
+    TransportMode,
+    Environment,
+    GpsCoordinates,
+    BatchNumber,
+    QualityScore,
+    Emissions,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct DisclosureSettings {
+    // Unpublished products have no public view at all
+    pub published: bool,
+    pub fields: Vec<DisclosableField>,
+    pub show_certifications: bool,
+    pub show_esg_score: bool,
+}
+
+impl Default for DisclosureSettings {
+    fn default() -> Self {
+        DisclosureSettings {
+            published: false,
+            fields: vec![
+                DisclosableField::Location,
+                DisclosableField::Role,
+                DisclosableField::Timestamp,
+                DisclosableField::TransportMode,
+                DisclosableField::Emissions,
+            ],
+            show_certifications: true,
+            show_esg_score: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct PublicStep {
+    pub stage: LifecycleStage,
+    pub action: String,
+    pub location: Option<String>,
+    pub actor_name: Option<String>,
+    pub role: Option<String>,
+    pub timestamp: Option<u64>,
+    pub transport_mode: Option<String>,
+    pub temperature_celsius: Option<f64>,
+    pub humidity_percent: Option<f64>,
+    pub gps_latitude: Option<f64>,
+    pub gps_longitude: Option<f64>,
+    pub batch_number: Option<String>,
+    pub quality_score: Option<u8>,
+    pub carbon_footprint_kg: Option<f64>,
+    pub distance_km: Option<f64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct PublicCertification {
+    pub standard: String,
+    pub issuer: String,
+    pub status: CertificateStatus,
+    pub valid_until: u64,
+}
+
+// ESG score for the public view. Emission figures are only filled in when Emissions is disclosed.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct PublicEsgScore {
+    pub sustainability_score: u8,
+    pub methodology_version: u32,
+    pub total_steps: u32,
+    pub carbon_footprint_kg: Option<f64>,
+    pub total_distance_km: Option<f64>,
+    pub co2_saved_vs_traditional: Option<f64>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct PublicProductView {
+    pub product_id: String,
+    pub origin: Option<PublicStep>,
+    pub milestones: Vec<PublicStep>,
+    pub current_stage: LifecycleStage,
+    pub certifications: Vec<PublicCertification>,
+    pub esg_score: Option<PublicEsgScore>,
+    pub last_updated: u64,
+}
+
+thread_local! {
+    static DISCLOSURE_SETTINGS: RefCell<HashMap<String, DisclosureSettings>> = RefCell::new(HashMap::new());
+}
+
+pub fn disclosure_settings(product_id: &str) -> DisclosureSettings {
+    DISCLOSURE_SETTINGS.with(|s| s.borrow().get(product_id).cloned().unwrap_or_default())
+}
+
+pub(crate) fn snapshot() -> HashMap<String, DisclosureSettings> {
+    DISCLOSURE_SETTINGS.with(|s| s.borrow().clone())
+}
+
+pub(crate) fn restore(settings: HashMap<String, DisclosureSettings>) {
+    DISCLOSURE_SETTINGS.with(|s| *s.borrow_mut() = settings);
+}
+
+pub(crate) fn clear() {
+    DISCLOSURE_SETTINGS.with(|s| s.borrow_mut().clear());
+}
+
+fn redact(step: &Step, settings: &DisclosureSettings) -> PublicStep {
+    let shows = |field: DisclosableField| settings.fields.contains(&field);
+    let environment = shows(DisclosableField::Environment);
+    let gps = shows(DisclosableField::GpsCoordinates);
+    let emissions = shows(DisclosableField::Emissions);
+    PublicStep {
+        stage: history::lifecycle_stage(step),
+        action: step.action.clone(),
+        location: shows(DisclosableField::Location).then(|| step.location.clone()),
+        actor_name: (shows(DisclosableField::ActorName) && step.actor_name != ENCRYPTED_PLACEHOLDER)
+            .then(|| step.actor_name.clone()),
+        role: shows(DisclosableField::Role).then(|| step.role.clone()),
+        timestamp: shows(DisclosableField::Timestamp).then_some(step.timestamp),
+        transport_mode: step.transport_mode.clone().filter(|_| shows(DisclosableField::TransportMode)),
+        temperature_celsius: step.temperature_celsius.filter(|_| environment),
+        humidity_percent: step.humidity_percent.filter(|_| environment),
+        gps_latitude: step.gps_latitude.filter(|_| gps),
+        gps_longitude: step.gps_longitude.filter(|_| gps),
+        batch_number: step.batch_number.clone().filter(|_| shows(DisclosableField::BatchNumber)),
+        quality_score: step.quality_score.filter(|_| shows(DisclosableField::QualityScore)),
+        carbon_footprint_kg: step.carbon_footprint_kg.filter(|_| emissions),
+        distance_km: step.distance_km.filter(|_| emissions),
+    }
+}
+
+fn public_esg_score(score: ESGScore, settings: &DisclosureSettings) -> PublicEsgScore {
+    let emissions = settings.fields.contains(&DisclosableField::Emissions);
+    PublicEsgScore {
+        sustainability_score: score.sustainability_score,
+        methodology_version: score.methodology_version,
+        total_steps: score.total_steps,
+        carbon_footprint_kg: emissions.then_some(score.carbon_footprint_kg),
+        total_distance_km: emissions.then_some(score.total_distance_km),
+        co2_saved_vs_traditional: emissions.then_some(score.co2_saved_vs_traditional),
+    }
+}
+
+// Key milestones: the origin, every change of lifecycle stage and the latest step.
+fn milestone_steps(history: &[Step]) -> Vec<&Step> {
+    let mut milestones: Vec<&Step> = Vec::new();
+    let mut previous_stage: Option<LifecycleStage> = None;
+    for (index, step) in history.iter().enumerate() {
+        let stage = history::lifecycle_stage(step);
+        if previous_stage.as_ref() != Some(&stage) || index + 1 == history.len() {
+            milestones.push(step);
+        }
+        previous_stage = Some(stage);
+    }
+    milestones
+}
+
+pub fn public_product_view(product_id: &str) -> Option<PublicProductView> {
+    let settings = disclosure_settings(product_id);
+    if !settings.published {
+        return None;
+    }
+    let owner = history::product_owner(product_id)?;
+    let history: Vec<Step> = history::history_as_of(product_id, None).into_iter().filter(|s| s.user_id == owner).collect();
+    let last = history.last()?;
+
+    let now = time();
+    let mut certifications: Vec<PublicCertification> = Vec::new();
+    if settings.show_certifications {
+        let mut seen: Vec<String> = Vec::new();
+        for hash in history.iter().filter_map(|s| s.certification_hash.as_ref()) {
+            let key = certificates::normalize_hash(hash);
+            if seen.contains(&key) {
+                continue;
+            }
+            seen.push(key);
+            if let Some(cert) = certificates::lookup(hash) {
+                certifications.push(PublicCertification {
+                    standard: cert.standard.code(),
+                    issuer: cert.issuer.clone(),
+                    status: certificates::status_at(&cert, now),
+                    valid_until: cert.valid_until,
+                });
+            }
+        }
+    }
+
+    Some(PublicProductView {
+        product_id: product_id.to_string(),
+        origin: history.first().map(|s| redact(s, &settings)),
+        milestones: milestone_steps(&history).into_iter().map(|s| redact(s, &settings)).collect(),
+        current_stage: history::lifecycle_stage(last),
+        certifications,
+        esg_score: calculate_esg_score(product_id.to_string(), owner, None, None)
+            .filter(|_| settings.show_esg_score)
+            .map(|score| public_esg_score(score, &settings)),
+        last_updated: last.timestamp,
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_public_product_view(product_id: String) -> Option<PublicProductView> {
+    public_product_view(&product_id)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_disclosure_settings(product_id: String, settings: DisclosureSettings) -> ApiResult<String> {
+    let caller = format!("{}", ic_cdk::caller());
+    if history::product_owner(&product_id).as_deref() != Some(caller.as_str()) {
+        return Err(BlockTraceError::unauthorized("Only the product owner can change its disclosure settings"));
+    }
+    DISCLOSURE_SETTINGS.with(|s| s.borrow_mut().insert(product_id.clone(), settings));
+    Ok(format!("Disclosure settings updated for product {}", product_id))
+}
+
+#[query]
+#[candid_method(query)]
+fn get_disclosure_settings(product_id: String) -> DisclosureSettings {
+    disclosure_settings(&product_id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_step;
+
+    #[test]
+    fn redaction_hides_undisclosed_and_sensitive_fields() {
+        let mut step = test_step("P1", "owner", 5);
+        step.cost_usd = Some(99.0);
+        step.notes = Some("internal".to_string());
+        step.gps_latitude = Some(1.0);
+        step.carbon_footprint_kg = Some(3.0);
+        let public = redact(&step, &DisclosureSettings::default());
+        assert_eq!(public.gps_latitude, None);
+        assert_eq!(public.carbon_footprint_kg, Some(3.0));
+        assert_eq!(public.actor_name, None);
+        assert_eq!(public.location, Some(step.location.clone()));
+        assert!(!DisclosureSettings::default().published);
+    }
+
+    #[test]
+    fn milestones_keep_stage_changes_and_latest_step() {
+        let mut produce = test_step("P1", "owner", 1);
+        produce.action = "Harvested".to_string();
+        let mut pack = test_step("P1", "owner", 2);
+        pack.action = "Packaged".to_string();
+        let mut ship = test_step("P1", "owner", 3);
+        ship.action = "Shipped".to_string();
+        let mut ship_again = test_step("P1", "owner", 4);
+        ship_again.action = "In transit".to_string();
+        let mut ship_last = test_step("P1", "owner", 5);
+        ship_last.action = "Transport to port".to_string();
+        let history = vec![produce, pack, ship, ship_again, ship_last];
+        let timestamps: Vec<u64> = milestone_steps(&history).iter().map(|s| s.timestamp).collect();
+        assert_eq!(timestamps, vec![1, 3, 5]);
+    }
+}
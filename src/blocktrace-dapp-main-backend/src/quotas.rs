@@ -0,0 +1,382 @@
+// 🚦 Per-caller rate limiting and quotas for endpoints that consume storage, timers, outcall
+// cycles or threshold signatures.
+//
+// Every metered call draws one token from two buckets: the caller's own and the one shared by
+// the caller's organization. Outcalls and signatures are additionally capped per organization
+// and canister-wide per UTC day.
+use candid::{candid_method, CandidType};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+use crate::logs;
+use crate::organizations::organization_of;
+
+const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+const NANOS_PER_MINUTE: f64 = 60_000_000_000.0;
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum QuotaOperation {
+    AddStep,
+    ScheduleEsgRecalculation,
+    VerifySupplier,
+    CrossChainProof,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct BucketConfig {
+    pub capacity: u32,
+    pub refill_per_minute: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct OperationLimits {
+    pub per_principal: BucketConfig,
+    pub per_organization: BucketConfig,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct QuotaConfig {
+    pub enabled: bool,
+    pub add_step: OperationLimits,
+    pub schedule_esg_recalculation: OperationLimits,
+    pub verify_supplier: OperationLimits,
+    pub cross_chain_proof: OperationLimits,
+    pub min_timer_interval_seconds: u64,
+    pub daily_outcalls_per_organization: u32,
+    pub daily_signatures_per_organization: u32,
+    pub daily_outcalls_total: u32,
+    pub daily_signatures_total: u32,
+}
+
+impl Default for QuotaConfig {
+    fn default() -> Self {
+        let limits = |principal: (u32, u32), organization: (u32, u32)| OperationLimits {
+            per_principal: BucketConfig { capacity: principal.0, refill_per_minute: principal.1 },
+            per_organization: BucketConfig { capacity: organization.0, refill_per_minute: organization.1 },
+        };
+        QuotaConfig {
+            enabled: true,
+            add_step: limits((60, 30), (300, 150)),
+            schedule_esg_recalculation: limits((10, 2), (50, 10)),
+            verify_supplier: limits((20, 5), (100, 20)),
+            cross_chain_proof: limits((5, 1), (20, 5)),
+            min_timer_interval_seconds: 60,
+            daily_outcalls_per_organization: 1_000,
+            daily_signatures_per_organization: 100,
+            daily_outcalls_total: 10_000,
+            daily_signatures_total: 1_000,
+        }
+    }
+}
+
+impl QuotaConfig {
+    fn limits(&self, operation: QuotaOperation) -> &OperationLimits {
+        match operation {
+            QuotaOperation::AddStep => &self.add_step,
+            QuotaOperation::ScheduleEsgRecalculation => &self.schedule_esg_recalculation,
+            QuotaOperation::VerifySupplier => &self.verify_supplier,
+            QuotaOperation::CrossChainProof => &self.cross_chain_proof,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct TokenBucket {
+    pub tokens: f64,
+    pub last_refill: u64,
+}
+
+impl TokenBucket {
+    fn full(config: &BucketConfig, now: u64) -> Self {
+        TokenBucket { tokens: config.capacity as f64, last_refill: now }
+    }
+
+    fn refill(&mut self, config: &BucketConfig, now: u64) {
+        let elapsed = now.saturating_sub(self.last_refill) as f64;
+        self.tokens = (self.tokens + elapsed / NANOS_PER_MINUTE * config.refill_per_minute as f64).min(config.capacity as f64);
+        self.last_refill = now;
+    }
+
+    // Seconds until one token is available again
+    fn retry_after(&self, config: &BucketConfig) -> u64 {
+        if config.refill_per_minute == 0 {
+            return u64::MAX;
+        }
+        ((1.0 - self.tokens).max(0.0) * 60.0 / config.refill_per_minute as f64).ceil() as u64
+    }
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+pub struct DailyUsage {
+    pub day: u64,
+    pub outcalls_by_organization: HashMap<String, u32>,
+    pub signatures_by_organization: HashMap<String, u32>,
+    pub outcalls_total: u32,
+    pub signatures_total: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct BucketUsage {
+    pub scope: String,
+    pub key: String,
+    pub operation: QuotaOperation,
+    pub tokens_remaining: f64,
+    pub capacity: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct OrganizationDailyUsage {
+    pub organization_id: String,
+    pub outcalls: u32,
+    pub signatures: u32,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct QuotaUsageReport {
+    pub day_start: u64,
+    pub config: QuotaConfig,
+    pub buckets: Vec<BucketUsage>,
+    pub organizations: Vec<OrganizationDailyUsage>,
+    pub outcalls_total: u32,
+    pub signatures_total: u32,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct QuotaState {
+    pub config: Option<QuotaConfig>,
+    pub daily_usage: DailyUsage,
+}
+
+thread_local! {
+    static QUOTA_CONFIG: RefCell<QuotaConfig> = RefCell::new(QuotaConfig::default());
+    // Keyed by (scope, principal or organization id, operation)
+    static BUCKETS: RefCell<HashMap<(String, String, QuotaOperation), TokenBucket>> = RefCell::new(HashMap::new());
+    static DAILY_USAGE: RefCell<DailyUsage> = RefCell::new(DailyUsage::default());
+}
+
+fn config() -> QuotaConfig {
+    QUOTA_CONFIG.with(|c| c.borrow().clone())
+}
+
+fn take_token(scope: &str, key: &str, operation: QuotaOperation, bucket_config: &BucketConfig, now: u64) -> Result<(), u64> {
+    BUCKETS.with(|buckets| {
+        let mut buckets = buckets.borrow_mut();
+        let bucket = buckets
+            .entry((scope.to_string(), key.to_string(), operation))
+            .or_insert_with(|| TokenBucket::full(bucket_config, now));
+        bucket.refill(bucket_config, now);
+        if bucket.tokens < 1.0 {
+            Err(bucket.retry_after(bucket_config))
+        } else {
+            Ok(())
+        }
+    })
+}
+
+fn consume_token(scope: &str, key: &str, operation: QuotaOperation) {
+    BUCKETS.with(|buckets| {
+        if let Some(bucket) = buckets.borrow_mut().get_mut(&(scope.to_string(), key.to_string(), operation)) {
+            bucket.tokens -= 1.0;
+        }
+    });
+}
+
+/// Draw one token for `operation` from the caller's and the caller's organization's buckets.
+pub fn check_rate_limit(operation: QuotaOperation, principal: &str) -> ApiResult<()> {
+    let config = config();
+    if !config.enabled {
+        return Ok(());
+    }
+    let limits = config.limits(operation);
+    let organization = organization_of(principal);
+    let now = time();
+
+    take_token("principal", principal, operation, &limits.per_principal, now)
+        .map_err(|retry| BlockTraceError::quota(format!("{:?} rate limit for {}", operation, principal), Some(retry)))?;
+    take_token("organization", &organization, operation, &limits.per_organization, now)
+        .map_err(|retry| BlockTraceError::quota(format!("{:?} rate limit for organization {}", operation, organization), Some(retry)))?;
+
+    consume_token("principal", principal, operation);
+    consume_token("organization", &organization, operation);
+    Ok(())
+}
+
+pub fn check_timer_interval(interval_seconds: u64) -> ApiResult<()> {
+    let min = config().min_timer_interval_seconds;
+    if interval_seconds < min {
+        return Err(BlockTraceError::invalid("interval", format!("must be at least {} seconds", min)));
+    }
+    Ok(())
+}
+
+fn with_daily_usage<R>(f: impl FnOnce(&mut DailyUsage) -> R) -> R {
+    let today = time() / NANOS_PER_DAY;
+    DAILY_USAGE.with(|usage| {
+        let mut usage = usage.borrow_mut();
+        if usage.day != today {
+            *usage = DailyUsage { day: today, ..DailyUsage::default() };
+        }
+        f(&mut usage)
+    })
+}
+
+/// Reserve one HTTP outcall against the daily caps of the principal's organization.
+pub fn reserve_outcall(principal: &str) -> ApiResult<()> {
+    let config = config();
+    if !config.enabled {
+        return Ok(());
+    }
+    let organization = organization_of(principal);
+    with_daily_usage(|usage| {
+        let used = usage.outcalls_by_organization.get(&organization).copied().unwrap_or(0);
+        if used >= config.daily_outcalls_per_organization {
+            return Err(BlockTraceError::quota(format!("daily outcall cap of {} reached for organization {}", config.daily_outcalls_per_organization, organization), None));
+        }
+        if usage.outcalls_total >= config.daily_outcalls_total {
+            return Err(BlockTraceError::quota(format!("canister-wide daily outcall cap of {} reached", config.daily_outcalls_total), None));
+        }
+        *usage.outcalls_by_organization.entry(organization).or_insert(0) += 1;
+        usage.outcalls_total += 1;
+        Ok(())
+    })
+}
+
+/// Reserve one threshold signature against the daily caps of the principal's organization.
+pub fn reserve_signature(principal: &str) -> ApiResult<()> {
+    let config = config();
+    if !config.enabled {
+        return Ok(());
+    }
+    let organization = organization_of(principal);
+    with_daily_usage(|usage| {
+        let used = usage.signatures_by_organization.get(&organization).copied().unwrap_or(0);
+        if used >= config.daily_signatures_per_organization {
+            return Err(BlockTraceError::quota(format!("daily signature cap of {} reached for organization {}", config.daily_signatures_per_organization, organization), None));
+        }
+        if usage.signatures_total >= config.daily_signatures_total {
+            return Err(BlockTraceError::quota(format!("canister-wide daily signature cap of {} reached", config.daily_signatures_total), None));
+        }
+        *usage.signatures_by_organization.entry(organization).or_insert(0) += 1;
+        usage.signatures_total += 1;
+        Ok(())
+    })
+}
+
+pub(crate) fn snapshot() -> QuotaState {
+    QuotaState {
+        config: Some(config()),
+        daily_usage: DAILY_USAGE.with(|usage| usage.borrow().clone()),
+    }
+}
+
+pub(crate) fn restore(state: QuotaState) {
+    QUOTA_CONFIG.with(|c| *c.borrow_mut() = state.config.unwrap_or_default());
+    DAILY_USAGE.with(|usage| *usage.borrow_mut() = state.daily_usage);
+}
+
+#[update]
+#[candid_method(update)]
+fn set_quota_config(new_config: QuotaConfig) -> ApiResult<QuotaConfig> {
+    if !is_admin(&format!("{}", ic_cdk::caller())) {
+        return Err(BlockTraceError::unauthorized("Only an admin can change quotas"));
+    }
+    QUOTA_CONFIG.with(|c| *c.borrow_mut() = new_config.clone());
+    // Buckets are re-created lazily with the new capacities
+    BUCKETS.with(|buckets| buckets.borrow_mut().clear());
+    logs::info("quotas", "Quota configuration updated", vec![("enabled", new_config.enabled.to_string())]);
+    Ok(new_config)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_quota_config() -> QuotaConfig {
+    config()
+}
+
+// Admin view of current bucket levels and today's outcall/signature counters, optionally
+// restricted to one principal or organization id.
+#[query]
+#[candid_method(query)]
+fn get_quota_usage(key: Option<String>) -> ApiResult<QuotaUsageReport> {
+    if !is_admin(&format!("{}", ic_cdk::caller())) {
+        return Err(BlockTraceError::unauthorized("Only an admin can view quota usage"));
+    }
+    let config = config();
+    let now = time();
+    let wanted = |candidate: &str| key.as_ref().map(|k| k == candidate).unwrap_or(true);
+
+    let mut buckets: Vec<BucketUsage> = BUCKETS.with(|buckets| {
+        buckets
+            .borrow()
+            .iter()
+            .filter(|((_, k, _), _)| wanted(k))
+            .map(|((scope, k, operation), bucket)| {
+                let limits = config.limits(*operation);
+                let bucket_config = if scope == "principal" { &limits.per_principal } else { &limits.per_organization };
+                let mut current = bucket.clone();
+                current.refill(bucket_config, now);
+                BucketUsage {
+                    scope: scope.clone(),
+                    key: k.clone(),
+                    operation: *operation,
+                    tokens_remaining: current.tokens,
+                    capacity: bucket_config.capacity,
+                }
+            })
+            .collect()
+    });
+    buckets.sort_by(|a, b| (&a.scope, &a.key).cmp(&(&b.scope, &b.key)));
+
+    let usage = DAILY_USAGE.with(|usage| usage.borrow().clone());
+    let current_day = usage.day == now / NANOS_PER_DAY;
+    let mut organizations: Vec<OrganizationDailyUsage> = if current_day {
+        usage
+            .outcalls_by_organization
+            .keys()
+            .chain(usage.signatures_by_organization.keys())
+            .filter(|org| wanted(org))
+            .collect::<std::collections::HashSet<_>>()
+            .into_iter()
+            .map(|org| OrganizationDailyUsage {
+                organization_id: org.clone(),
+                outcalls: usage.outcalls_by_organization.get(org).copied().unwrap_or(0),
+                signatures: usage.signatures_by_organization.get(org).copied().unwrap_or(0),
+            })
+            .collect()
+    } else {
+        Vec::new()
+    };
+    organizations.sort_by(|a, b| a.organization_id.cmp(&b.organization_id));
+
+    Ok(QuotaUsageReport {
+        day_start: (now / NANOS_PER_DAY) * NANOS_PER_DAY,
+        config,
+        buckets,
+        organizations,
+        outcalls_total: if current_day { usage.outcalls_total } else { 0 },
+        signatures_total: if current_day { usage.signatures_total } else { 0 },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_refills_at_configured_rate_up_to_capacity() {
+        let config = BucketConfig { capacity: 2, refill_per_minute: 6 };
+        let mut bucket = TokenBucket { tokens: 0.0, last_refill: 0 };
+        assert_eq!(bucket.retry_after(&config), 10);
+
+        bucket.refill(&config, 10_000_000_000);
+        assert!((bucket.tokens - 1.0).abs() < 1e-9);
+
+        bucket.refill(&config, 600_000_000_000);
+        assert_eq!(bucket.tokens, 2.0);
+    }
+}
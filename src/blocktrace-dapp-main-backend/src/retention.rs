@@ -0,0 +1,429 @@
+// 🗄️ Soft deletion and data retention.
+//
+// Admin deletions move steps out of the live history into tombstones, from which they can be
+// restored until the owning organization's policy purges them. Per-organization retention
+// policies move old steps out of the heap into an archive in stable memory, which is not part of
+// the upgrade snapshot. Archived steps remain part of the product's ledger, and tombstoned steps
+// remain part of it until their deletion, so "as of" reads still see both. A daily timer applies
+// the policies.
+use candid::{candid_method, CandidType, Decode, Encode};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use ic_cdk_timers::set_timer_interval;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::audit;
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+use crate::logs;
+use crate::memory::{self, Memory};
+use crate::metrics::{self, Counter};
+use crate::organizations::organization_of;
+use crate::{Step, PRODUCT_HISTORY};
+
+const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+const RETENTION_INTERVAL_SECONDS: u64 = 86_400;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct Tombstone {
+    pub step: Step,
+    pub deleted_by: String,
+    pub deleted_at: u64,
+    pub reason: String,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct ArchivedStep {
+    pub step: Step,
+    pub organization_id: String,
+    pub archived_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct RetentionPolicy {
+    // Steps older than this are moved to the archive store
+    pub archive_after_days: u32,
+    // Tombstoned steps older than this (counted from deletion) are purged for good
+    pub purge_tombstones_after_days: Option<u32>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+pub struct RetentionRunReport {
+    pub ran_at: u64,
+    pub archived_steps: u64,
+    pub purged_tombstones: u64,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct RetentionState {
+    pub policies: HashMap<String, RetentionPolicy>,
+    pub tombstones: HashMap<String, Vec<Tombstone>>,
+    // Only populated by snapshots from before the archive moved to stable memory
+    pub archive: HashMap<String, Vec<ArchivedStep>>,
+    pub last_run: Option<RetentionRunReport>,
+}
+
+// Archived steps of one product, stored as a single stable-memory value.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct ArchivedSteps(Vec<ArchivedStep>);
+
+impl Storable for ArchivedSteps {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).expect("Failed to encode archived steps"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("Failed to decode archived steps")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    static RETENTION_POLICIES: RefCell<HashMap<String, RetentionPolicy>> = RefCell::new(HashMap::new());
+    static TOMBSTONES: RefCell<HashMap<String, Vec<Tombstone>>> = RefCell::new(HashMap::new());
+    static ARCHIVE: RefCell<StableBTreeMap<String, ArchivedSteps, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::RETENTION_ARCHIVE)));
+    static LAST_RUN: RefCell<Option<RetentionRunReport>> = const { RefCell::new(None) };
+}
+
+fn archived(product_id: &str) -> Vec<ArchivedStep> {
+    ARCHIVE.with(|store| store.borrow().get(&product_id.to_string()).map(|a| a.0).unwrap_or_default())
+}
+
+fn store_archived(records: Vec<ArchivedStep>) {
+    let mut by_product: HashMap<String, Vec<ArchivedStep>> = HashMap::new();
+    for record in records {
+        by_product.entry(record.step.product_id.clone()).or_default().push(record);
+    }
+    ARCHIVE.with(|store| {
+        let mut store = store.borrow_mut();
+        for (product_id, records) in by_product {
+            let mut existing = store.get(&product_id).unwrap_or_default();
+            existing.0.extend(records);
+            store.insert(product_id, existing);
+        }
+    });
+}
+
+/// Steps of `product_id` that left the live history but were on the ledger at `as_of` (or now):
+/// archived steps always, tombstoned steps only before they were deleted.
+pub fn retired_steps(product_id: &str, as_of: Option<u64>) -> Vec<Step> {
+    let mut steps: Vec<Step> = archived(product_id).into_iter().map(|a| a.step).collect();
+    if let Some(as_of) = as_of {
+        TOMBSTONES.with(|store| {
+            if let Some(tombstones) = store.borrow().get(product_id) {
+                steps.extend(tombstones.iter().filter(|t| as_of < t.deleted_at).map(|t| t.step.clone()));
+            }
+        });
+    }
+    steps
+}
+
+/// Every retired step of `product_id` still held: archived steps and unpurged tombstones.
+pub fn all_retired_steps(product_id: &str) -> Vec<Step> {
+    let mut steps: Vec<Step> = archived(product_id).into_iter().map(|a| a.step).collect();
+    TOMBSTONES.with(|store| {
+        steps.extend(store.borrow().get(product_id).into_iter().flatten().map(|t| t.step.clone()));
+    });
+    steps
+}
+
+/// Move removed steps into tombstones instead of dropping them.
+pub fn tombstone(removed: Vec<Step>, reason: &str) {
+    let deleted_by = format!("{}", ic_cdk::caller());
+    let deleted_at = time();
+    TOMBSTONES.with(|store| {
+        let mut store = store.borrow_mut();
+        for step in removed {
+            store.entry(step.product_id.clone()).or_default().push(Tombstone {
+                step,
+                deleted_by: deleted_by.clone(),
+                deleted_at,
+                reason: reason.to_string(),
+            });
+        }
+    });
+}
+
+/// Lowest sequence number a new step of `product_id` may use, so that steps leaving the live
+/// history never have their sequence numbers reused.
+pub fn sequence_floor(product_id: &str) -> u64 {
+    let tombstoned = TOMBSTONES.with(|store| {
+        store.borrow().get(product_id).and_then(|t| t.iter().filter_map(|t| t.step.sequence).max())
+    });
+    let archived = archived(product_id).iter().filter_map(|a| a.step.sequence).max();
+    tombstoned.max(archived).map(|s| s + 1).unwrap_or(0)
+}
+
+// Move steps older than their organization's archive cutoff from `history` into archive records.
+fn take_expired_steps(
+    history: &mut HashMap<String, Vec<Step>>,
+    policies: &HashMap<String, RetentionPolicy>,
+    now: u64,
+) -> Vec<ArchivedStep> {
+    let mut archived = Vec::new();
+    for steps in history.values_mut() {
+        let mut kept = Vec::with_capacity(steps.len());
+        for step in steps.drain(..) {
+            let organization_id = organization_of(&step.user_id);
+            let expired = policies.get(&organization_id).is_some_and(|policy| {
+                let cutoff = now.saturating_sub(policy.archive_after_days as u64 * NANOS_PER_DAY);
+                step.timestamp < cutoff
+            });
+            if expired {
+                archived.push(ArchivedStep { step, organization_id, archived_at: now });
+            } else {
+                kept.push(step);
+            }
+        }
+        *steps = kept;
+    }
+    history.retain(|_, steps| !steps.is_empty());
+    archived
+}
+
+fn purge_tombstones(policies: &HashMap<String, RetentionPolicy>, now: u64) -> u64 {
+    TOMBSTONES.with(|store| {
+        let mut store = store.borrow_mut();
+        let mut purged = 0u64;
+        for tombstones in store.values_mut() {
+            let before = tombstones.len();
+            tombstones.retain(|t| {
+                let policy = policies.get(&organization_of(&t.step.user_id));
+                match policy.and_then(|p| p.purge_tombstones_after_days) {
+                    Some(days) => t.deleted_at.saturating_add(days as u64 * NANOS_PER_DAY) > now,
+                    None => true,
+                }
+            });
+            purged += (before - tombstones.len()) as u64;
+        }
+        store.retain(|_, t| !t.is_empty());
+        purged
+    })
+}
+
+fn run_retention() -> RetentionRunReport {
+    let now = time();
+    let policies = RETENTION_POLICIES.with(|p| p.borrow().clone());
+    if policies.is_empty() {
+        return RetentionRunReport { ran_at: now, ..Default::default() };
+    }
+
+    let archived = PRODUCT_HISTORY.with(|store| take_expired_steps(&mut store.borrow_mut(), &policies, now));
+    let archived_steps = archived.len() as u64;
+    store_archived(archived);
+    let purged_tombstones = purge_tombstones(&policies, now);
+
+    let report = RetentionRunReport { ran_at: now, archived_steps, purged_tombstones };
+    logs::info(
+        "retention",
+        "Retention run finished",
+        vec![("archived_steps", report.archived_steps.to_string()), ("purged_tombstones", report.purged_tombstones.to_string())],
+    );
+    LAST_RUN.with(|last| *last.borrow_mut() = Some(report.clone()));
+    report
+}
+
+// Timers do not survive upgrades, so this is called from both init and post_upgrade.
+pub(crate) fn start_retention_timer() {
+    set_timer_interval(Duration::from_secs(RETENTION_INTERVAL_SECONDS), || {
+        metrics::increment(Counter::TimerRun);
+        run_retention();
+    });
+}
+
+pub(crate) fn snapshot() -> RetentionState {
+    RetentionState {
+        policies: RETENTION_POLICIES.with(|p| p.borrow().clone()),
+        tombstones: TOMBSTONES.with(|t| t.borrow().clone()),
+        archive: HashMap::new(),
+        last_run: LAST_RUN.with(|l| l.borrow().clone()),
+    }
+}
+
+pub(crate) fn restore(state: RetentionState) {
+    RETENTION_POLICIES.with(|p| *p.borrow_mut() = state.policies);
+    TOMBSTONES.with(|t| *t.borrow_mut() = state.tombstones);
+    store_archived(state.archive.into_values().flatten().collect());
+    LAST_RUN.with(|l| *l.borrow_mut() = state.last_run);
+}
+
+// Retention policies are configuration and survive clear_all_data.
+pub(crate) fn clear() {
+    TOMBSTONES.with(|t| t.borrow_mut().clear());
+    ARCHIVE.with(|a| a.borrow_mut().clear_new());
+}
+
+fn require_admin() -> ApiResult<()> {
+    if !is_admin(&format!("{}", ic_cdk::caller())) {
+        return Err(BlockTraceError::unauthorized("Only an admin can manage retention"));
+    }
+    Ok(())
+}
+
+#[update]
+#[candid_method(update)]
+fn set_retention_policy(organization_id: String, policy: RetentionPolicy) -> ApiResult<String> {
+    require_admin()?;
+    if organization_id.trim().is_empty() {
+        return Err(BlockTraceError::invalid("organization_id", "cannot be empty"));
+    }
+    if policy.archive_after_days == 0 {
+        return Err(BlockTraceError::invalid("archive_after_days", "must be at least 1"));
+    }
+    audit::record(
+        "set_retention_policy",
+        vec![
+            ("organization_id", organization_id.clone()),
+            ("archive_after_days", policy.archive_after_days.to_string()),
+            ("purge_tombstones_after_days", format!("{:?}", policy.purge_tombstones_after_days)),
+        ],
+        0,
+        0,
+    );
+    RETENTION_POLICIES.with(|p| p.borrow_mut().insert(organization_id.clone(), policy));
+    Ok(format!("Retention policy set for {}", organization_id))
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_retention_policy(organization_id: String) -> ApiResult<String> {
+    require_admin()?;
+    match RETENTION_POLICIES.with(|p| p.borrow_mut().remove(&organization_id)) {
+        Some(_) => {
+            audit::record("remove_retention_policy", vec![("organization_id", organization_id.clone())], 0, 0);
+            Ok(format!("Retention policy removed for {}", organization_id))
+        }
+        None => Err(BlockTraceError::not_found("Retention policy", organization_id)),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn get_retention_policy(organization_id: String) -> Option<RetentionPolicy> {
+    RETENTION_POLICIES.with(|p| p.borrow().get(&organization_id).cloned())
+}
+
+#[update]
+#[candid_method(update)]
+fn run_retention_now() -> ApiResult<RetentionRunReport> {
+    require_admin()?;
+    let report = run_retention();
+    audit::record("run_retention_now", vec![], 0, report.archived_steps + report.purged_tombstones);
+    Ok(report)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_last_retention_run() -> Option<RetentionRunReport> {
+    LAST_RUN.with(|l| l.borrow().clone())
+}
+
+// Same visibility rule as get_product_history: callers see their own archived steps.
+#[query]
+#[candid_method(query)]
+fn get_archived_steps(product_id: String, caller_principal: String) -> Vec<ArchivedStep> {
+    let mut steps: Vec<ArchivedStep> = archived(&product_id).into_iter().filter(|r| r.step.user_id == caller_principal).collect();
+    steps.sort_by_key(|r| r.step.timestamp);
+    steps
+}
+
+#[query]
+#[candid_method(query)]
+fn get_tombstones(product_id: String) -> ApiResult<Vec<Tombstone>> {
+    require_admin()?;
+    Ok(TOMBSTONES.with(|store| store.borrow().get(&product_id).cloned().unwrap_or_default()))
+}
+
+// Undo soft deletions for a product by moving its tombstoned steps back into the live history.
+#[update]
+#[candid_method(update)]
+fn restore_tombstoned_steps(product_id: String) -> ApiResult<String> {
+    require_admin()?;
+    let tombstones = TOMBSTONES.with(|store| store.borrow_mut().remove(&product_id)).unwrap_or_default();
+    if tombstones.is_empty() {
+        return Err(BlockTraceError::not_found("Tombstoned steps for product", product_id));
+    }
+    let restored = tombstones.len();
+    PRODUCT_HISTORY.with(|store| {
+        let mut map = store.borrow_mut();
+        let steps = map.entry(product_id.clone()).or_default();
+        steps.extend(tombstones.into_iter().map(|t| t.step));
+        steps.sort_by_key(|s| s.sequence);
+    });
+    audit::record("restore_tombstoned_steps", vec![("product_id", product_id.clone())], 1, restored as u64);
+    Ok(format!("Restored {} steps for product {}", restored, product_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::history;
+    use crate::tests::test_step;
+
+    #[test]
+    fn expired_steps_move_to_archive_for_organizations_with_a_policy() {
+        let now = 400 * NANOS_PER_DAY;
+        let mut history = HashMap::new();
+        history.insert(
+            "P1".to_string(),
+            vec![test_step("P1", "old_org", 10 * NANOS_PER_DAY), test_step("P1", "old_org", 390 * NANOS_PER_DAY)],
+        );
+        history.insert("P2".to_string(), vec![test_step("P2", "no_policy", 0)]);
+        let mut policies = HashMap::new();
+        policies.insert(
+            "old_org".to_string(),
+            RetentionPolicy { archive_after_days: 365, purge_tombstones_after_days: None },
+        );
+
+        let archived = take_expired_steps(&mut history, &policies, now);
+        assert_eq!(archived.len(), 1);
+        assert_eq!(archived[0].step.timestamp, 10 * NANOS_PER_DAY);
+        assert_eq!(history["P1"].len(), 1);
+        assert_eq!(history["P2"].len(), 1);
+    }
+
+    #[test]
+    fn retired_steps_stay_on_the_ledger() {
+        store_archived(vec![ArchivedStep { step: test_step("R1", "org", 5), organization_id: "org".to_string(), archived_at: 50 }]);
+        TOMBSTONES.with(|store| {
+            store.borrow_mut().insert(
+                "R1".to_string(),
+                vec![Tombstone { step: test_step("R1", "org", 10), deleted_by: "admin".to_string(), deleted_at: 100, reason: String::new() }],
+            )
+        });
+        assert_eq!(retired_steps("R1", None).len(), 1);
+        assert_eq!(retired_steps("R1", Some(99)).len(), 2);
+        assert_eq!(retired_steps("R1", Some(100)).len(), 1);
+    }
+
+    #[test]
+    fn archiving_the_first_step_keeps_the_product_owner() {
+        let mut first = test_step("P1", "maker", NANOS_PER_DAY);
+        first.sequence = Some(0);
+        let mut second = test_step("P1", "carrier", 399 * NANOS_PER_DAY);
+        second.sequence = Some(1);
+        PRODUCT_HISTORY.with(|store| store.borrow_mut().insert("P1".to_string(), vec![first, second]));
+        history::record_owner("P1");
+
+        let policies = HashMap::from([(
+            "maker".to_string(),
+            RetentionPolicy { archive_after_days: 365, purge_tombstones_after_days: None },
+        )]);
+        let archived = PRODUCT_HISTORY.with(|store| take_expired_steps(&mut store.borrow_mut(), &policies, 400 * NANOS_PER_DAY));
+        assert_eq!(archived.len(), 1);
+        store_archived(archived);
+        assert_eq!(history::product_owner("P1").as_deref(), Some("maker"));
+
+        // Without a pinned owner the archived first step still decides
+        history::clear();
+        assert_eq!(history::product_owner("P1").as_deref(), Some("maker"));
+    }
+}
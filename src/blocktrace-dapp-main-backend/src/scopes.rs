@@ -0,0 +1,206 @@
+// 🧾 Scope 1/2/3 classification of step emissions.
+//
+// Emissions are classified relative to one organization. Fuel burnt in vehicles or sites the
+// organization operates is Scope 1 and electricity it buys to run them is Scope 2. Transport it
+// purchases from carriers, and everything other organizations emit while handling its products, is
+// Scope 3. Whether a step's transport is operated in-house comes from `transport_owned`, falling
+// back to the recorder's role.
+use candid::{candid_method, CandidType};
+use ic_cdk_macros::query;
+use serde::{Deserialize, Serialize};
+
+use crate::emissions::{step_emission, EmissionFactorRef, EmissionMethod};
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::governance::is_admin;
+use crate::history::{self, is_involved};
+use crate::organizations::organization_of;
+use crate::{resolve_caller, Step, PRODUCT_HISTORY};
+
+// Roles whose transport is bought in rather than operated by the recording organization
+const THIRD_PARTY_ROLES: [&str; 5] = ["carrier", "logistics", "3pl", "forwarder", "courier"];
+const ELECTRIC_FUELS: [&str; 3] = ["electric", "electricity", "bev"];
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq)]
+pub enum EmissionScope {
+    Scope1,
+    Scope2,
+    Scope3,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct ScopeTotals {
+    pub scope1_kg: f64,
+    pub scope2_kg: f64,
+    pub scope3_kg: f64,
+}
+
+impl ScopeTotals {
+    fn add(&mut self, scope: EmissionScope, kg: f64) {
+        match scope {
+            EmissionScope::Scope1 => self.scope1_kg += kg,
+            EmissionScope::Scope2 => self.scope2_kg += kg,
+            EmissionScope::Scope3 => self.scope3_kg += kg,
+        }
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct StepScopeEmission {
+    pub sequence: u64,
+    pub organization_id: String,
+    pub scope: EmissionScope,
+    pub kg_co2e: f64,
+    pub method: EmissionMethod,
+    pub factor: Option<EmissionFactorRef>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct EmissionsBreakdown {
+    pub product_id: String,
+    // Organization the scopes are relative to: the product owner's
+    pub organization_id: String,
+    pub totals: ScopeTotals,
+    pub steps: Vec<StepScopeEmission>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct OrganizationEmissions {
+    pub organization_id: String,
+    pub period_start: u64,
+    pub period_end: u64,
+    pub totals: ScopeTotals,
+    pub product_count: u32,
+    pub step_count: u32,
+}
+
+fn operates_transport(step: &Step) -> bool {
+    step.transport_owned.unwrap_or_else(|| {
+        let role = step.role.to_lowercase();
+        !THIRD_PARTY_ROLES.iter().any(|r| role.contains(r))
+    })
+}
+
+/// Scope of `step` for `organization_id`, or None when the step is not part of its inventory.
+pub fn classify(step: &Step, organization_id: &str, product_organization: &str) -> Option<EmissionScope> {
+    if organization_of(&step.user_id) != organization_id {
+        return (product_organization == organization_id).then_some(EmissionScope::Scope3);
+    }
+    if !operates_transport(step) {
+        return Some(EmissionScope::Scope3);
+    }
+    let fuel = step.fuel_type.as_deref().unwrap_or_default().to_lowercase();
+    if ELECTRIC_FUELS.contains(&fuel.trim()) {
+        Some(EmissionScope::Scope2)
+    } else {
+        Some(EmissionScope::Scope1)
+    }
+}
+
+fn product_organization(product_id: &str) -> String {
+    history::product_owner(product_id).map(|owner| organization_of(&owner)).unwrap_or_default()
+}
+
+/// Scope totals of `steps` relative to the owner of the product they belong to.
+pub fn scope_totals(product_id: &str, steps: &[Step]) -> ScopeTotals {
+    let organization_id = product_organization(product_id);
+    let mut totals = ScopeTotals::default();
+    for step in steps {
+        if let (Some(emission), Some(scope)) = (step_emission(step), classify(step, &organization_id, &organization_id)) {
+            totals.add(scope, emission.kg_co2e);
+        }
+    }
+    totals
+}
+
+#[query]
+#[candid_method(query)]
+fn get_emissions_breakdown(product_id: String, caller_principal: String, as_of: Option<u64>) -> ApiResult<EmissionsBreakdown> {
+    let caller = resolve_caller(caller_principal);
+    if !is_involved(&product_id, &caller) && !is_admin(&caller) {
+        return Err(BlockTraceError::unauthorized("Only parties involved in the product can see its emissions"));
+    }
+    let steps = history::history_as_of(&product_id, as_of);
+    if steps.is_empty() {
+        return Err(BlockTraceError::not_found("Product", product_id));
+    }
+    let organization_id = product_organization(&product_id);
+    let mut totals = ScopeTotals::default();
+    let mut breakdown = Vec::new();
+    for step in &steps {
+        let (Some(emission), Some(scope)) = (step_emission(step), classify(step, &organization_id, &organization_id)) else {
+            continue;
+        };
+        totals.add(scope, emission.kg_co2e);
+        breakdown.push(StepScopeEmission {
+            sequence: step.sequence.unwrap_or_default(),
+            organization_id: organization_of(&step.user_id),
+            scope,
+            kg_co2e: emission.kg_co2e,
+            method: emission.method,
+            factor: emission.factor,
+        });
+    }
+    Ok(EmissionsBreakdown { product_id, organization_id, totals, steps: breakdown })
+}
+
+// Totals for a reporting period [period_start, period_end) in nanoseconds: the organization's own
+// steps on any product plus other organizations' steps on its products.
+#[query]
+#[candid_method(query)]
+fn get_organization_emissions(
+    organization_id: String,
+    period_start: u64,
+    period_end: u64,
+    caller_principal: String,
+) -> ApiResult<OrganizationEmissions> {
+    let caller = resolve_caller(caller_principal);
+    if organization_of(&caller) != organization_id && !is_admin(&caller) {
+        return Err(BlockTraceError::unauthorized("Only members of the organization can see its emissions"));
+    }
+    if period_end <= period_start {
+        return Err(BlockTraceError::invalid("period_end", "must be after period_start"));
+    }
+    let products: Vec<(String, Vec<Step>)> = PRODUCT_HISTORY.with(|store| store.borrow().iter().map(|(id, steps)| (id.clone(), steps.clone())).collect());
+    let mut totals = ScopeTotals::default();
+    let (mut product_count, mut step_count) = (0u32, 0u32);
+    for (product_id, steps) in products {
+        let product_org = product_organization(&product_id);
+        let mut counted = false;
+        for step in steps.iter().filter(|s| (period_start..period_end).contains(&s.timestamp)) {
+            if let (Some(emission), Some(scope)) = (step_emission(step), classify(step, &organization_id, &product_org)) {
+                totals.add(scope, emission.kg_co2e);
+                step_count += 1;
+                counted = true;
+            }
+        }
+        product_count += counted as u32;
+    }
+    Ok(OrganizationEmissions { organization_id, period_start, period_end, totals, product_count, step_count })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_step;
+
+    #[test]
+    fn classifies_by_organization_role_and_fuel() {
+        let mut own_truck = test_step("P1", "maker", 1);
+        own_truck.fuel_type = Some("diesel".to_string());
+        assert_eq!(classify(&own_truck, "maker", "maker"), Some(EmissionScope::Scope1));
+
+        own_truck.fuel_type = Some("Electric".to_string());
+        assert_eq!(classify(&own_truck, "maker", "maker"), Some(EmissionScope::Scope2));
+
+        own_truck.transport_owned = Some(false);
+        assert_eq!(classify(&own_truck, "maker", "maker"), Some(EmissionScope::Scope3));
+
+        let mut carrier = test_step("P1", "haulier", 2);
+        carrier.role = "Logistics Carrier".to_string();
+        assert_eq!(classify(&carrier, "maker", "maker"), Some(EmissionScope::Scope3));
+        assert_eq!(classify(&carrier, "haulier", "maker"), Some(EmissionScope::Scope3));
+        carrier.transport_owned = Some(true);
+        assert_eq!(classify(&carrier, "haulier", "maker"), Some(EmissionScope::Scope1));
+        assert_eq!(classify(&carrier, "retailer", "maker"), None);
+    }
+}
@@ -0,0 +1,423 @@
+// 🔢 Unit-level serialization for batches.
+//
+// The owner of a batch (whoever created the products that carry it) requests serials for it, and
+// only the owner and custodians of those products (through accepted handovers) can reference them
+// in steps or change their status. Serials are derived from the subnet's randomness, so they
+// cannot be guessed from earlier ones, and carry a GS1 SGTIN element string when the batch has a
+// GTIN. Steps reference serials, which lets a consumer or pharmacy verify a single pack and lets
+// anomaly detection follow individual units.
+use candid::{candid_method, CandidType};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use crate::anomalies::{self, Finding};
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::history;
+use crate::logs;
+use crate::{authenticated_caller, Step, PRODUCT_HISTORY};
+
+const MAX_SERIALS_PER_REQUEST: u32 = 1_000;
+const MAX_SERIALS_PER_STEP: usize = 1_000;
+// Crockford base32: no I, L, O or U, so serials survive being read aloud or retyped
+const SERIAL_ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+const SERIAL_LENGTH: usize = 16;
+
+#[derive(Clone, Copy, Debug, CandidType, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SerialStatus {
+    Commissioned,
+    Packed,
+    Shipped,
+    Sold,
+    Decommissioned,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct SerialStatusChange {
+    pub status: SerialStatus,
+    pub changed_by: String,
+    pub changed_at: u64,
+}
+
+// Where a serial was last referenced by a step.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct SerialSighting {
+    pub product_id: String,
+    pub sequence: u64,
+    pub location: String,
+    pub gps_latitude: Option<f64>,
+    pub gps_longitude: Option<f64>,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct SerialRecord {
+    pub serial: String,
+    pub sgtin: Option<String>,
+    pub batch_number: String,
+    pub issued_to: String,
+    pub issued_at: u64,
+    pub status: SerialStatus,
+    pub status_history: Vec<SerialStatusChange>,
+    pub last_seen: Option<SerialSighting>,
+}
+
+// What a consumer or pharmacy scanning a single pack gets back. Principals are not disclosed.
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct SerialVerification {
+    pub serial: String,
+    pub genuine: bool,
+    pub sgtin: Option<String>,
+    pub batch_number: Option<String>,
+    pub status: Option<SerialStatus>,
+    pub last_location: Option<String>,
+    pub last_seen_at: Option<u64>,
+    pub message: String,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize, Serialize)]
+pub struct SerialState {
+    pub serials: BTreeMap<String, SerialRecord>,
+}
+
+thread_local! {
+    static SERIALS: RefCell<SerialState> = RefCell::new(SerialState::default());
+}
+
+pub(crate) fn snapshot() -> SerialState {
+    SERIALS.with(|s| s.borrow().clone())
+}
+
+pub(crate) fn restore(state: SerialState) {
+    SERIALS.with(|s| *s.borrow_mut() = state);
+}
+
+pub(crate) fn clear() {
+    SERIALS.with(|s| s.borrow_mut().serials.clear());
+}
+
+fn derive_serial(seed: &[u8], counter: u64) -> String {
+    let digest = Sha256::new().chain_update(seed).chain_update(counter.to_be_bytes()).finalize();
+    // 16 characters of 5 bits each take the first 80 bits of the digest
+    let bits = u128::from_be_bytes(digest[..16].try_into().expect("digest is 32 bytes")) >> 48;
+    (0..SERIAL_LENGTH)
+        .rev()
+        .map(|i| SERIAL_ALPHABET[((bits >> (i * 5)) & 0x1f) as usize] as char)
+        .collect()
+}
+
+/// Accepts GTIN-8/12/13/14 with a valid check digit and returns it padded to 14 digits.
+fn normalize_gtin(gtin: &str) -> ApiResult<String> {
+    let gtin = gtin.trim();
+    if !matches!(gtin.len(), 8 | 12 | 13 | 14) || !gtin.chars().all(|c| c.is_ascii_digit()) {
+        return Err(BlockTraceError::invalid("gtin", "must be 8, 12, 13 or 14 digits"));
+    }
+    let padded = format!("{:0>14}", gtin);
+    let digits: Vec<u32> = padded.chars().filter_map(|c| c.to_digit(10)).collect();
+    let sum: u32 = digits[..13].iter().enumerate().map(|(i, d)| if i % 2 == 0 { d * 3 } else { *d }).sum();
+    if (10 - sum % 10) % 10 != digits[13] {
+        return Err(BlockTraceError::invalid("gtin", "check digit does not match"));
+    }
+    Ok(padded)
+}
+
+// Products with a step carrying the batch number.
+fn batch_products(batch_number: &str) -> Vec<String> {
+    PRODUCT_HISTORY.with(|store| {
+        store
+            .borrow()
+            .iter()
+            .filter(|(_, steps)| steps.iter().any(|s| s.batch_number.as_deref() == Some(batch_number)))
+            .map(|(product_id, _)| product_id.clone())
+            .collect()
+    })
+}
+
+// Owner shared by every product carrying the batch. None when they were created by different
+// principals, so recording a batch number on a product of one's own does not claim the batch.
+fn owner_of(products: &[String]) -> Option<String> {
+    let mut owners = products.iter().map(|product_id| history::product_owner(product_id));
+    let first = owners.next()??;
+    owners.all(|owner| owner.as_deref() == Some(first.as_str())).then_some(first)
+}
+
+// The batch owner, or a principal that took custody of one of its products in a handover.
+fn is_party(products: &[String], principal: &str) -> bool {
+    owner_of(products).is_some() && products.iter().any(|product_id| history::is_involved(product_id, principal))
+}
+
+pub fn batch_owner(batch_number: &str) -> Option<String> {
+    owner_of(&batch_products(batch_number))
+}
+
+fn involved_in_batch(batch_number: &str, principal: &str) -> bool {
+    is_party(&batch_products(batch_number), principal)
+}
+
+// The step must be recorded on one of the owner's products by a party to the batch.
+fn check_batch_party(products: &[String], batch_number: &str, product_owner: &str, step: &Step) -> ApiResult<()> {
+    let owner = owner_of(products)
+        .ok_or_else(|| BlockTraceError::unauthorized(format!("Batch {} has no single owner", batch_number)))?;
+    if product_owner != owner {
+        return Err(BlockTraceError::unauthorized(format!("Serials of batch {} can only be used on its owner's products", batch_number)));
+    }
+    if !is_party(products, &step.user_id) {
+        return Err(BlockTraceError::unauthorized(format!("Not a party to batch {}", batch_number)));
+    }
+    Ok(())
+}
+
+/// Serials referenced by a step must exist, belong to the step's batch and still be in circulation,
+/// and the step's author must be a party to the batch.
+pub fn check_step_serials(step: &Step) -> ApiResult<()> {
+    let Some(serials) = step.serials.as_ref() else {
+        return Ok(());
+    };
+    if serials.len() > MAX_SERIALS_PER_STEP {
+        return Err(BlockTraceError::invalid("serials", format!("at most {} per step", MAX_SERIALS_PER_STEP)));
+    }
+    let Some(batch_number) = step.batch_number.as_deref() else {
+        return Err(BlockTraceError::invalid("serials", "steps referencing serials must carry their batch_number"));
+    };
+    let product_owner = history::product_owner(&step.product_id).unwrap_or_else(|| step.user_id.clone());
+    check_batch_party(&batch_products(batch_number), batch_number, &product_owner, step)?;
+    SERIALS.with(|s| {
+        let state = s.borrow();
+        for serial in serials {
+            match state.serials.get(serial) {
+                None => return Err(BlockTraceError::not_found("Serial", serial.clone())),
+                Some(record) if record.batch_number != batch_number => {
+                    return Err(BlockTraceError::invalid("serials", format!("{} belongs to batch {}", serial, record.batch_number)))
+                }
+                Some(record) if record.status == SerialStatus::Decommissioned => {
+                    return Err(BlockTraceError::conflict(format!("Serial {} is decommissioned", serial)))
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Records the step as the latest sighting of each serial it references and returns anomalies for
+/// serials last seen elsewhere at the same time.
+pub(crate) fn record_sightings(step: &Step) -> Vec<Finding> {
+    let Some(serials) = step.serials.as_ref() else {
+        return vec![];
+    };
+    let sighting = SerialSighting {
+        product_id: step.product_id.clone(),
+        sequence: step.sequence.unwrap_or_default(),
+        location: step.location.clone(),
+        gps_latitude: step.gps_latitude,
+        gps_longitude: step.gps_longitude,
+        timestamp: step.timestamp,
+    };
+    SERIALS.with(|s| {
+        let mut state = s.borrow_mut();
+        let mut findings = Vec::new();
+        for serial in serials {
+            if let Some(record) = state.serials.get_mut(serial) {
+                if let Some(previous) = record.last_seen.as_ref() {
+                    findings.extend(anomalies::serial_seen_elsewhere(serial, previous, step));
+                }
+                record.last_seen = Some(sighting.clone());
+            }
+        }
+        findings
+    })
+}
+
+fn issue(batch_number: &str, count: u32, sgtin_prefix: Option<&str>, owner: &str, seed: &[u8], now: u64) -> Vec<SerialRecord> {
+    SERIALS.with(|s| {
+        let mut state = s.borrow_mut();
+        let mut issued = Vec::with_capacity(count as usize);
+        let mut counter = 0u64;
+        while issued.len() < count as usize {
+            let serial = derive_serial(seed, counter);
+            counter += 1;
+            if state.serials.contains_key(&serial) {
+                continue;
+            }
+            let record = SerialRecord {
+                serial: serial.clone(),
+                sgtin: sgtin_prefix.map(|gtin| format!("(01){}(21){}", gtin, serial)),
+                batch_number: batch_number.to_string(),
+                issued_to: owner.to_string(),
+                issued_at: now,
+                status: SerialStatus::Commissioned,
+                status_history: vec![SerialStatusChange {
+                    status: SerialStatus::Commissioned,
+                    changed_by: owner.to_string(),
+                    changed_at: now,
+                }],
+                last_seen: None,
+            };
+            state.serials.insert(serial, record.clone());
+            issued.push(record);
+        }
+        issued
+    })
+}
+
+#[update]
+#[candid_method(update)]
+async fn issue_serials(batch_number: String, count: u32, gtin: Option<String>, caller_principal: String) -> ApiResult<Vec<SerialRecord>> {
+    let caller = authenticated_caller(&caller_principal)?;
+    if batch_owner(&batch_number).as_deref() != Some(caller.as_str()) {
+        return Err(BlockTraceError::unauthorized("Only the batch owner can issue serials"));
+    }
+    if count == 0 || count > MAX_SERIALS_PER_REQUEST {
+        return Err(BlockTraceError::invalid("count", format!("must be between 1 and {}", MAX_SERIALS_PER_REQUEST)));
+    }
+    let gtin = gtin.as_deref().map(normalize_gtin).transpose()?;
+    let (seed,): (Vec<u8>,) = ic_cdk::api::management_canister::main::raw_rand()
+        .await
+        .map_err(|(code, msg)| BlockTraceError::external("raw_rand", format!("{:?} - {}", code, msg)))?;
+
+    let issued = issue(&batch_number, count, gtin.as_deref(), &caller, &seed, time());
+    logs::info(
+        "serials",
+        "Serials issued",
+        vec![("batch_number", batch_number), ("count", count.to_string()), ("issued_to", caller)],
+    );
+    Ok(issued)
+}
+
+// Statuses only move forward; a decommissioned serial stays decommissioned.
+#[update]
+#[candid_method(update)]
+fn update_serial_status(serials: Vec<String>, status: SerialStatus, caller_principal: String) -> ApiResult<Vec<SerialRecord>> {
+    let caller = authenticated_caller(&caller_principal)?;
+    set_status(&serials, status, &caller, time())
+}
+
+fn set_status(serials: &[String], status: SerialStatus, caller: &str, now: u64) -> ApiResult<Vec<SerialRecord>> {
+    SERIALS.with(|s| {
+        let mut state = s.borrow_mut();
+        for serial in serials {
+            let record = state.serials.get(serial).ok_or_else(|| BlockTraceError::not_found("Serial", serial.clone()))?;
+            if !involved_in_batch(&record.batch_number, caller) {
+                return Err(BlockTraceError::unauthorized(format!("Not a party to batch {}", record.batch_number)));
+            }
+            if status <= record.status {
+                return Err(BlockTraceError::conflict(format!("Serial {} is already {:?}", serial, record.status)));
+            }
+        }
+        let mut updated = Vec::with_capacity(serials.len());
+        for serial in serials {
+            if let Some(record) = state.serials.get_mut(serial) {
+                record.status = status;
+                record.status_history.push(SerialStatusChange { status, changed_by: caller.to_string(), changed_at: now });
+                updated.push(record.clone());
+            }
+        }
+        Ok(updated)
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn list_batch_serials(batch_number: String, caller_principal: String) -> ApiResult<Vec<SerialRecord>> {
+    let caller = authenticated_caller(&caller_principal)?;
+    if !involved_in_batch(&batch_number, &caller) {
+        return Err(BlockTraceError::unauthorized(format!("Not a party to batch {}", batch_number)));
+    }
+    Ok(SERIALS.with(|s| s.borrow().serials.values().filter(|r| r.batch_number == batch_number).cloned().collect()))
+}
+
+// Public check of a single pack; accepts the bare serial or its SGTIN element string.
+#[query]
+#[candid_method(query)]
+fn verify_serial(serial: String) -> SerialVerification {
+    let trimmed = serial.trim();
+    let key = trimmed.rsplit_once("(21)").map(|(_, s)| s).unwrap_or(trimmed).to_uppercase();
+    let record = SERIALS.with(|s| s.borrow().serials.get(&key).cloned());
+    let Some(record) = record else {
+        return SerialVerification {
+            serial: key,
+            genuine: false,
+            sgtin: None,
+            batch_number: None,
+            status: None,
+            last_location: None,
+            last_seen_at: None,
+            message: "Unknown serial: this pack was not issued through BlockTrace".to_string(),
+        };
+    };
+    let message = match record.status {
+        SerialStatus::Commissioned | SerialStatus::Packed | SerialStatus::Shipped => "Genuine pack in the supply chain",
+        SerialStatus::Sold => "Genuine pack already recorded as sold; treat a second sale as suspect",
+        SerialStatus::Decommissioned => "Pack was decommissioned and must not be sold or dispensed",
+    };
+    SerialVerification {
+        serial: record.serial,
+        genuine: true,
+        sgtin: record.sgtin,
+        batch_number: Some(record.batch_number),
+        status: Some(record.status),
+        last_location: record.last_seen.as_ref().map(|s| s.location.clone()),
+        last_seen_at: record.last_seen.as_ref().map(|s| s.timestamp),
+        message: message.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_step;
+
+    #[test]
+    fn issues_unique_serials_with_sgtin() {
+        let gtin = normalize_gtin("4006381333931").unwrap();
+        assert_eq!(gtin, "04006381333931");
+        assert!(normalize_gtin("4006381333932").is_err());
+
+        let issued = issue("B-1", 50, Some(&gtin), "owner", b"seed", 1);
+        let again = issue("B-1", 5, None, "owner", b"seed", 2);
+        let mut all: Vec<&String> = issued.iter().chain(again.iter()).map(|r| &r.serial).collect();
+        assert!(all.iter().all(|s| s.len() == SERIAL_LENGTH && s.bytes().all(|b| SERIAL_ALPHABET.contains(&b))));
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 55);
+        assert_eq!(issued[0].sgtin, Some(format!("(01)04006381333931(21){}", issued[0].serial)));
+
+        let verified = verify_serial(issued[0].sgtin.clone().unwrap());
+        assert!(verified.genuine);
+        assert_eq!(verified.status, Some(SerialStatus::Commissioned));
+        assert!(!verify_serial("0000000000000000".to_string()).genuine);
+    }
+
+    fn record(product_id: &str, user_id: &str, timestamp: u64, handover: bool) {
+        let mut step = test_step(product_id, user_id, timestamp);
+        step.batch_number = Some("B-1".to_string());
+        step.sequence = Some(timestamp);
+        if handover {
+            step.status = Some(crate::custody::HANDOVER_STATUS.to_string());
+        }
+        PRODUCT_HISTORY.with(|store| store.borrow_mut().entry(product_id.to_string()).or_default().push(step));
+    }
+
+    #[test]
+    fn only_the_owner_and_custodians_use_serials() {
+        record("P1", "maker", 1, false);
+        record("P1", "stranger", 2, false);
+        record("P1", "carrier", 3, true);
+        let products = batch_products("B-1");
+        assert_eq!(owner_of(&products).as_deref(), Some("maker"));
+        assert!(check_batch_party(&products, "B-1", "maker", &test_step("P1", "carrier", 4)).is_ok());
+        assert!(check_batch_party(&products, "B-1", "maker", &test_step("P1", "stranger", 4)).is_err());
+        assert!(check_batch_party(&products, "B-1", "stranger", &test_step("P9", "stranger", 4)).is_err());
+
+        let serials: Vec<String> = issue("B-1", 2, None, "maker", b"seed", 1).into_iter().map(|r| r.serial).collect();
+        assert!(matches!(set_status(&serials, SerialStatus::Decommissioned, "stranger", 5), Err(BlockTraceError::Unauthorized { .. })));
+        assert!(set_status(&serials, SerialStatus::Shipped, "carrier", 5).is_ok());
+        assert!(set_status(&serials[..1], SerialStatus::Decommissioned, "maker", 6).is_ok());
+
+        record("P9", "stranger", 1, false);
+        assert_eq!(owner_of(&batch_products("B-1")), None);
+    }
+}
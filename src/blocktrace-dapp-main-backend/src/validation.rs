@@ -0,0 +1,280 @@
+// ✅ Field limits and semantic checks applied to every step before it is stored.
+//
+// All violations are collected and returned together, so a client can fix a form in one go
+// instead of resubmitting once per problem. Limits are admin-configurable and persisted.
+use candid::{candid_method, CandidType};
+use ic_cdk_macros::{query, update};
+use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+
+use crate::errors::{ApiResult, BlockTraceError, FieldViolation};
+use crate::governance::is_admin;
+use crate::Step;
+
+const NANOS_PER_DAY: u64 = 86_400_000_000_000;
+const DEFAULT_MAX_VEHICLE_CLASS_LENGTH: u32 = 50;
+const DEFAULT_MAX_FUEL_TYPE_LENGTH: u32 = 50;
+const DEFAULT_MAX_USER_ID_LENGTH: u32 = 128;
+const DEFAULT_MAX_BLOCKCHAIN_HASH_LENGTH: u32 = 256;
+const DEFAULT_MAX_SERIAL_LENGTH: u32 = 64;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize, PartialEq)]
+pub struct ValidationLimits {
+    // Maximum lengths in bytes
+    pub max_product_id_length: u32,
+    pub max_actor_name_length: u32,
+    pub max_role_length: u32,
+    pub max_action_length: u32,
+    pub max_location_length: u32,
+    pub max_notes_length: u32,
+    pub max_status_length: u32,
+    pub max_transport_mode_length: u32,
+    pub max_batch_number_length: u32,
+    pub max_certification_hash_length: u32,
+    pub min_temperature_celsius: f64,
+    pub max_temperature_celsius: f64,
+    pub max_quality_score: u8,
+    // How far past the step time an estimated arrival may lie; catches unit mix-ups
+    pub max_arrival_horizon_days: u64,
+    // Optional so that limits persisted before they existed still decode; None uses the default
+    pub max_vehicle_class_length: Option<u32>,
+    pub max_fuel_type_length: Option<u32>,
+    pub max_user_id_length: Option<u32>,
+    pub max_blockchain_hash_length: Option<u32>,
+    // Applies to each serial a step references
+    pub max_serial_length: Option<u32>,
+}
+
+impl Default for ValidationLimits {
+    fn default() -> Self {
+        ValidationLimits {
+            max_product_id_length: 128,
+            max_actor_name_length: 200,
+            max_role_length: 100,
+            max_action_length: 200,
+            max_location_length: 300,
+            max_notes_length: 4_096,
+            max_status_length: 50,
+            max_transport_mode_length: 50,
+            max_batch_number_length: 128,
+            max_certification_hash_length: 256,
+            min_temperature_celsius: -100.0,
+            max_temperature_celsius: 150.0,
+            max_quality_score: 100,
+            max_arrival_horizon_days: 366,
+            max_vehicle_class_length: Some(DEFAULT_MAX_VEHICLE_CLASS_LENGTH),
+            max_fuel_type_length: Some(DEFAULT_MAX_FUEL_TYPE_LENGTH),
+            max_user_id_length: Some(DEFAULT_MAX_USER_ID_LENGTH),
+            max_blockchain_hash_length: Some(DEFAULT_MAX_BLOCKCHAIN_HASH_LENGTH),
+            max_serial_length: Some(DEFAULT_MAX_SERIAL_LENGTH),
+        }
+    }
+}
+
+thread_local! {
+    static LIMITS: RefCell<ValidationLimits> = RefCell::new(ValidationLimits::default());
+}
+
+pub(crate) fn snapshot() -> ValidationLimits {
+    LIMITS.with(|l| l.borrow().clone())
+}
+
+pub(crate) fn restore(limits: ValidationLimits) {
+    LIMITS.with(|l| *l.borrow_mut() = limits);
+}
+
+fn violations(step: &Step, step_time: u64, limits: &ValidationLimits) -> Vec<FieldViolation> {
+    let mut found: Vec<FieldViolation> = Vec::new();
+    let mut violation = |field: &str, reason: String| found.push(FieldViolation { field: field.to_string(), reason });
+
+    let required = [
+        ("product_id", &step.product_id, limits.max_product_id_length),
+        ("actor_name", &step.actor_name, limits.max_actor_name_length),
+        ("role", &step.role, limits.max_role_length),
+        ("action", &step.action, limits.max_action_length),
+        ("location", &step.location, limits.max_location_length),
+    ];
+    for (field, value, max) in required {
+        if value.trim().is_empty() {
+            violation(field, "cannot be empty".to_string());
+        } else if value.len() > max as usize {
+            violation(field, format!("exceeds {} bytes", max));
+        }
+    }
+    // Set by the canister from the caller, but legacy and admin-assigned owners are free text
+    let max_user_id_length = limits.max_user_id_length.unwrap_or(DEFAULT_MAX_USER_ID_LENGTH);
+    if step.user_id.len() > max_user_id_length as usize {
+        violation("user_id", format!("exceeds {} bytes", max_user_id_length));
+    }
+    let optional = [
+        ("notes", &step.notes, limits.max_notes_length),
+        ("status", &step.status, limits.max_status_length),
+        ("transport_mode", &step.transport_mode, limits.max_transport_mode_length),
+        ("vehicle_class", &step.vehicle_class, limits.max_vehicle_class_length.unwrap_or(DEFAULT_MAX_VEHICLE_CLASS_LENGTH)),
+        ("fuel_type", &step.fuel_type, limits.max_fuel_type_length.unwrap_or(DEFAULT_MAX_FUEL_TYPE_LENGTH)),
+        ("batch_number", &step.batch_number, limits.max_batch_number_length),
+        ("certification_hash", &step.certification_hash, limits.max_certification_hash_length),
+        (
+            "blockchain_hash",
+            &step.blockchain_hash,
+            limits.max_blockchain_hash_length.unwrap_or(DEFAULT_MAX_BLOCKCHAIN_HASH_LENGTH),
+        ),
+    ];
+    for (field, value, max) in optional {
+        if value.as_ref().is_some_and(|v| v.len() > max as usize) {
+            violation(field, format!("exceeds {} bytes", max));
+        }
+    }
+    let max_serial_length = limits.max_serial_length.unwrap_or(DEFAULT_MAX_SERIAL_LENGTH);
+    if step.serials.iter().flatten().any(|serial| serial.len() > max_serial_length as usize) {
+        violation("serials", format!("each serial must not exceed {} bytes", max_serial_length));
+    }
+
+    match (step.gps_latitude, step.gps_longitude) {
+        (Some(_), None) | (None, Some(_)) => {
+            violation("gps_latitude", "latitude and longitude must be provided together".to_string())
+        }
+        _ => {}
+    }
+    if step.gps_latitude.is_some_and(|lat| !(-90.0..=90.0).contains(&lat)) {
+        violation("gps_latitude", "must be between -90 and 90".to_string());
+    }
+    if step.gps_longitude.is_some_and(|lon| !(-180.0..=180.0).contains(&lon)) {
+        violation("gps_longitude", "must be between -180 and 180".to_string());
+    }
+    if step.humidity_percent.is_some_and(|h| !(0.0..=100.0).contains(&h)) {
+        violation("humidity_percent", "must be between 0 and 100".to_string());
+    }
+    if step
+        .temperature_celsius
+        .is_some_and(|t| !(limits.min_temperature_celsius..=limits.max_temperature_celsius).contains(&t))
+    {
+        violation(
+            "temperature_celsius",
+            format!("must be between {} and {}", limits.min_temperature_celsius, limits.max_temperature_celsius),
+        );
+    }
+    if step.quality_score.is_some_and(|q| q > limits.max_quality_score) {
+        violation("quality_score", format!("must be between 0 and {}", limits.max_quality_score));
+    }
+    let non_negative = [
+        ("distance_km", step.distance_km),
+        ("carbon_footprint_kg", step.carbon_footprint_kg),
+        ("cost_usd", step.cost_usd),
+        ("load_tonnes", step.load_tonnes),
+    ];
+    for (field, value) in non_negative {
+        if value.is_some_and(|v| !v.is_finite() || v < 0.0) {
+            violation(field, "must be a non-negative number".to_string());
+        }
+    }
+
+    if let Some(eta) = step.estimated_arrival {
+        let horizon = step_time.saturating_add(limits.max_arrival_horizon_days.saturating_mul(NANOS_PER_DAY));
+        if eta < step_time {
+            violation("estimated_arrival", "cannot be before the step time (nanoseconds since epoch)".to_string());
+        } else if eta > horizon {
+            violation(
+                "estimated_arrival",
+                format!("more than {} days after the step time", limits.max_arrival_horizon_days),
+            );
+        }
+    }
+    found
+}
+
+/// Checks `step` as if recorded at `step_time` against the current limits.
+pub fn validate_step(step: &Step, step_time: u64) -> ApiResult<()> {
+    let found = LIMITS.with(|l| violations(step, step_time, &l.borrow()));
+    if found.is_empty() {
+        Ok(())
+    } else {
+        Err(BlockTraceError::ValidationFailed { violations: found })
+    }
+}
+
+#[update]
+#[candid_method(update)]
+fn set_validation_limits(limits: ValidationLimits) -> ApiResult<ValidationLimits> {
+    if !is_admin(&format!("{}", ic_cdk::caller())) {
+        return Err(BlockTraceError::unauthorized("Only an admin can change validation limits"));
+    }
+    if limits.min_temperature_celsius > limits.max_temperature_celsius {
+        return Err(BlockTraceError::invalid("min_temperature_celsius", "must not exceed max_temperature_celsius"));
+    }
+    LIMITS.with(|l| *l.borrow_mut() = limits.clone());
+    Ok(limits)
+}
+
+#[query]
+#[candid_method(query)]
+fn get_validation_limits() -> ValidationLimits {
+    snapshot()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_step;
+
+    #[test]
+    fn reports_every_violation_at_once() {
+        let now = 1_000 * NANOS_PER_DAY;
+        let mut step = test_step("P1", "owner", now);
+        assert!(violations(&step, now, &ValidationLimits::default()).is_empty());
+
+        step.actor_name = " ".to_string();
+        step.notes = Some("x".repeat(2_000_000));
+        step.gps_latitude = Some(91.0);
+        step.gps_longitude = Some(10.0);
+        step.humidity_percent = Some(120.0);
+        step.quality_score = Some(101);
+        step.distance_km = Some(f64::NAN);
+        step.cost_usd = Some(-1.0);
+        step.estimated_arrival = Some(now / 1_000_000); // milliseconds by mistake
+        let fields: Vec<String> = violations(&step, now, &ValidationLimits::default()).into_iter().map(|v| v.field).collect();
+        assert_eq!(
+            fields,
+            vec!["actor_name", "notes", "gps_latitude", "humidity_percent", "quality_score", "distance_km", "cost_usd", "estimated_arrival"]
+        );
+    }
+
+    #[test]
+    fn every_text_field_has_its_own_limit() {
+        let now = 1_000 * NANOS_PER_DAY;
+        let limits = ValidationLimits {
+            max_transport_mode_length: 100,
+            max_vehicle_class_length: Some(4),
+            max_fuel_type_length: Some(6),
+            max_user_id_length: Some(8),
+            max_blockchain_hash_length: Some(10),
+            max_serial_length: Some(12),
+            ..ValidationLimits::default()
+        };
+        let fields = |step: &Step| violations(step, now, &limits).into_iter().map(|v| v.field).collect::<Vec<_>>();
+
+        let mut step = test_step("P1", "owner", now);
+        step.vehicle_class = Some("truck".to_string());
+        assert_eq!(fields(&step), vec!["vehicle_class"]);
+
+        let mut step = test_step("P1", "owner", now);
+        step.fuel_type = Some("biodiesel".to_string());
+        assert_eq!(fields(&step), vec!["fuel_type"]);
+
+        let step = test_step("P1", "principal-x", now);
+        assert_eq!(fields(&step), vec!["user_id"]);
+
+        let mut step = test_step("P1", "owner", now);
+        step.blockchain_hash = Some("0x".repeat(6));
+        assert_eq!(fields(&step), vec!["blockchain_hash"]);
+
+        let mut step = test_step("P1", "owner", now);
+        step.serials = Some(vec!["0123456789AB".to_string(), "0123456789ABC".to_string()]);
+        assert_eq!(fields(&step), vec!["serials"]);
+
+        // Limits persisted before these fields existed fall back to the defaults
+        let legacy = ValidationLimits { max_user_id_length: None, ..ValidationLimits::default() };
+        assert!(violations(&test_step("P1", &"x".repeat(128), now), now, &legacy).is_empty());
+        assert_eq!(violations(&test_step("P1", &"x".repeat(129), now), now, &legacy).len(), 1);
+    }
+}
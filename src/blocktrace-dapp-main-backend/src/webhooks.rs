@@ -0,0 +1,478 @@
+// 📡 Outbound webhooks: subscribers register an endpoint and a filter, and the canister POSTs a
+// signed JSON notification through HTTP outcalls whenever a matching step or alert is recorded.
+//
+// Every request carries:
+//   X-BlockTrace-Event      event type ("step_added" or "alert")
+//   X-BlockTrace-Delivery   delivery id, stable across retries (replicas may send duplicates)
+//   X-BlockTrace-Timestamp  nanoseconds at signing time
+//   X-BlockTrace-Signature  "sha256=" + hex(HMAC-SHA256(secret, "{timestamp}.{body}"))
+use candid::{candid_method, CandidType};
+use hmac::{Hmac, Mac};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs, TransformContext,
+};
+use ic_cdk::api::time;
+use ic_cdk_macros::{query, update};
+use ic_cdk_timers::set_timer;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+use crate::errors::{ApiResult, BlockTraceError};
+use crate::logs;
+use crate::metrics::{self, Counter};
+use crate::Step;
+
+const MAX_ATTEMPTS: u32 = 5;
+const BASE_BACKOFF_SECONDS: u64 = 30;
+const DELIVERY_LOG_CAPACITY: usize = 500;
+const WEBHOOK_CYCLES: u128 = 25_000_000_000;
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct WebhookFilter {
+    pub product_id: Option<String>,
+    pub batch_number: Option<String>,
+    pub action: Option<String>,
+    pub include_steps: bool,
+    // Alert types to deliver, e.g. "esg_score_change" or "certificate_flag"; "*" matches all
+    pub alert_types: Vec<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct WebhookSubscription {
+    pub id: String,
+    pub owner: String,
+    pub url: String,
+    pub filter: WebhookFilter,
+    pub active: bool,
+    pub created_at: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct WebhookAlert {
+    pub alert_type: String,
+    pub product_id: String,
+    pub batch_number: Option<String>,
+    pub message: String,
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize, Serialize)]
+pub struct WebhookDeliveryRecord {
+    pub delivery_id: u64,
+    pub subscription_id: String,
+    pub event_type: String,
+    pub attempt: u32,
+    pub status_code: Option<u16>,
+    pub success: bool,
+    pub error: Option<String>,
+    pub timestamp: u64,
+    pub next_retry_at: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct WebhookState {
+    pub subscriptions: HashMap<String, WebhookSubscription>,
+    pub secrets: HashMap<String, String>,
+    pub deliveries: Vec<WebhookDeliveryRecord>,
+    pub next_subscription_id: u64,
+    pub next_delivery_id: u64,
+}
+
+#[derive(Clone, Debug)]
+struct PendingDelivery {
+    delivery_id: u64,
+    subscription_id: String,
+    event_type: String,
+    body: String,
+    attempt: u32,
+}
+
+thread_local! {
+    static SUBSCRIPTIONS: RefCell<HashMap<String, WebhookSubscription>> = RefCell::new(HashMap::new());
+    static SECRETS: RefCell<HashMap<String, String>> = RefCell::new(HashMap::new());
+    static DELIVERY_LOG: RefCell<VecDeque<WebhookDeliveryRecord>> = const { RefCell::new(VecDeque::new()) };
+    static NEXT_SUBSCRIPTION_ID: RefCell<u64> = const { RefCell::new(0) };
+    static NEXT_DELIVERY_ID: RefCell<u64> = const { RefCell::new(0) };
+}
+
+pub fn sign_payload(secret: &str, timestamp: u64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+fn text_matches(expected: &Option<String>, actual: Option<&str>) -> bool {
+    match expected {
+        None => true,
+        Some(expected) => actual.map(|a| a.trim().eq_ignore_ascii_case(expected.trim())).unwrap_or(false),
+    }
+}
+
+fn matches_step(filter: &WebhookFilter, step: &Step) -> bool {
+    filter.include_steps
+        && text_matches(&filter.product_id, Some(&step.product_id))
+        && text_matches(&filter.batch_number, step.batch_number.as_deref())
+        && text_matches(&filter.action, Some(&step.action))
+}
+
+fn matches_alert(filter: &WebhookFilter, alert: &WebhookAlert) -> bool {
+    filter.alert_types.iter().any(|t| t == "*" || t.eq_ignore_ascii_case(&alert.alert_type))
+        && text_matches(&filter.product_id, Some(&alert.product_id))
+        && (filter.batch_number.is_none() || text_matches(&filter.batch_number, alert.batch_number.as_deref()))
+}
+
+fn next_delivery_id() -> u64 {
+    NEXT_DELIVERY_ID.with(|n| {
+        let mut next = n.borrow_mut();
+        let id = *next;
+        *next += 1;
+        id
+    })
+}
+
+fn record_delivery(record: WebhookDeliveryRecord) {
+    DELIVERY_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        if log.len() >= DELIVERY_LOG_CAPACITY {
+            log.pop_front();
+        }
+        log.push_back(record);
+    });
+}
+
+fn enqueue(subscription_ids: Vec<String>, event_type: &str, data: serde_json::Value) {
+    for subscription_id in subscription_ids {
+        let delivery_id = next_delivery_id();
+        let body = serde_json::json!({
+            "event": event_type,
+            "delivery_id": delivery_id,
+            "subscription_id": subscription_id,
+            "timestamp": time(),
+            "data": data,
+        })
+        .to_string();
+        schedule(
+            PendingDelivery {
+                delivery_id,
+                subscription_id,
+                event_type: event_type.to_string(),
+                body,
+                attempt: 1,
+            },
+            Duration::ZERO,
+        );
+    }
+}
+
+// Retries live in timers only; deliveries still pending during an upgrade are dropped.
+fn schedule(delivery: PendingDelivery, delay: Duration) {
+    set_timer(delay, move || ic_cdk::spawn(deliver(delivery)));
+}
+
+async fn deliver(delivery: PendingDelivery) {
+    let subscription = SUBSCRIPTIONS.with(|s| s.borrow().get(&delivery.subscription_id).cloned());
+    let secret = SECRETS.with(|s| s.borrow().get(&delivery.subscription_id).cloned());
+    let (subscription, secret) = match (subscription, secret) {
+        (Some(sub), Some(secret)) if sub.active => (sub, secret),
+        _ => return,
+    };
+
+    if let Err(e) = crate::quotas::reserve_outcall(&subscription.owner) {
+        record_delivery(WebhookDeliveryRecord {
+            delivery_id: delivery.delivery_id,
+            subscription_id: delivery.subscription_id.clone(),
+            event_type: delivery.event_type.clone(),
+            attempt: delivery.attempt,
+            status_code: None,
+            success: false,
+            error: Some(e.to_string()),
+            timestamp: time(),
+            next_retry_at: None,
+        });
+        return;
+    }
+
+    let signed_at = time();
+    let request = CanisterHttpRequestArgument {
+        url: subscription.url.clone(),
+        method: HttpMethod::POST,
+        body: Some(delivery.body.clone().into_bytes()),
+        max_response_bytes: Some(2_048),
+        headers: vec![
+            HttpHeader { name: "Content-Type".to_string(), value: "application/json".to_string() },
+            HttpHeader { name: "User-Agent".to_string(), value: "BlockTrace-ICP/1.0".to_string() },
+            HttpHeader { name: "X-BlockTrace-Event".to_string(), value: delivery.event_type.clone() },
+            HttpHeader { name: "X-BlockTrace-Delivery".to_string(), value: delivery.delivery_id.to_string() },
+            HttpHeader { name: "X-BlockTrace-Timestamp".to_string(), value: signed_at.to_string() },
+            HttpHeader { name: "X-BlockTrace-Signature".to_string(), value: sign_payload(&secret, signed_at, &delivery.body) },
+        ],
+        transform: Some(TransformContext::from_name(
+            "transform_webhook_response".to_string(),
+            serde_json::to_vec(&()).unwrap(),
+        )),
+    };
+
+    let (status_code, error) = match http_request(request, WEBHOOK_CYCLES).await {
+        Ok((response,)) => {
+            metrics::increment(Counter::OutcallSucceeded);
+            let status: u16 = response.status.0.try_into().unwrap_or(500);
+            if (200..300).contains(&status) {
+                (Some(status), None)
+            } else {
+                (Some(status), Some(format!("Endpoint responded with HTTP {}", status)))
+            }
+        }
+        Err((code, message)) => {
+            metrics::increment(Counter::OutcallFailed);
+            (None, Some(format!("{:?}: {}", code, message)))
+        }
+    };
+
+    let success = error.is_none();
+    let retry_delay = if !success && delivery.attempt < MAX_ATTEMPTS {
+        Some(Duration::from_secs(BASE_BACKOFF_SECONDS << (delivery.attempt - 1)))
+    } else {
+        None
+    };
+
+    record_delivery(WebhookDeliveryRecord {
+        delivery_id: delivery.delivery_id,
+        subscription_id: delivery.subscription_id.clone(),
+        event_type: delivery.event_type.clone(),
+        attempt: delivery.attempt,
+        status_code,
+        success,
+        error: error.clone(),
+        timestamp: time(),
+        next_retry_at: retry_delay.map(|d| time() + d.as_nanos() as u64),
+    });
+
+    match (error, retry_delay) {
+        (None, _) => {
+            logs::info(
+                "webhooks",
+                "Webhook delivered",
+                vec![("delivery_id", delivery.delivery_id.to_string()), ("url", subscription.url), ("attempt", delivery.attempt.to_string())],
+            );
+        }
+        (Some(err), Some(delay)) => {
+            logs::warn(
+                "webhooks",
+                "Webhook delivery failed, retrying",
+                vec![("delivery_id", delivery.delivery_id.to_string()), ("error", err), ("retry_in_seconds", delay.as_secs().to_string())],
+            );
+            schedule(PendingDelivery { attempt: delivery.attempt + 1, ..delivery }, delay);
+        }
+        (Some(err), None) => {
+            logs::error(
+                "webhooks",
+                "Webhook delivery abandoned",
+                vec![("delivery_id", delivery.delivery_id.to_string()), ("attempts", delivery.attempt.to_string()), ("error", err)],
+            );
+        }
+    }
+}
+
+/// Notify subscribers involved in the product that a step was appended.
+pub fn notify_step_added(step: &Step) {
+    let targets: Vec<String> = SUBSCRIPTIONS.with(|s| {
+        s.borrow()
+            .values()
+            .filter(|sub| sub.active && matches_step(&sub.filter, step))
+            .filter(|sub| crate::history::is_involved(&step.product_id, &sub.owner))
+            .map(|sub| sub.id.clone())
+            .collect()
+    });
+    if !targets.is_empty() {
+        enqueue(targets, "step_added", serde_json::to_value(step).unwrap_or_default());
+    }
+}
+
+/// Notify subscribers involved in the product about an alert raised by the canister.
+pub fn notify_alert(alert: WebhookAlert) {
+    let targets: Vec<String> = SUBSCRIPTIONS.with(|s| {
+        s.borrow()
+            .values()
+            .filter(|sub| sub.active && matches_alert(&sub.filter, &alert))
+            .filter(|sub| crate::history::is_involved(&alert.product_id, &sub.owner))
+            .map(|sub| sub.id.clone())
+            .collect()
+    });
+    if !targets.is_empty() {
+        enqueue(targets, "alert", serde_json::to_value(&alert).unwrap_or_default());
+    }
+}
+
+pub(crate) fn snapshot() -> WebhookState {
+    WebhookState {
+        subscriptions: SUBSCRIPTIONS.with(|s| s.borrow().clone()),
+        secrets: SECRETS.with(|s| s.borrow().clone()),
+        deliveries: DELIVERY_LOG.with(|log| log.borrow().iter().cloned().collect()),
+        next_subscription_id: NEXT_SUBSCRIPTION_ID.with(|n| *n.borrow()),
+        next_delivery_id: NEXT_DELIVERY_ID.with(|n| *n.borrow()),
+    }
+}
+
+pub(crate) fn restore(state: WebhookState) {
+    SUBSCRIPTIONS.with(|s| *s.borrow_mut() = state.subscriptions);
+    SECRETS.with(|s| *s.borrow_mut() = state.secrets);
+    DELIVERY_LOG.with(|log| *log.borrow_mut() = state.deliveries.into_iter().collect());
+    NEXT_SUBSCRIPTION_ID.with(|n| *n.borrow_mut() = state.next_subscription_id);
+    NEXT_DELIVERY_ID.with(|n| *n.borrow_mut() = state.next_delivery_id);
+}
+
+pub(crate) fn clear() {
+    restore(WebhookState::default());
+}
+
+fn owned_subscription(subscription_id: &str, caller: &str) -> ApiResult<WebhookSubscription> {
+    let subscription = SUBSCRIPTIONS
+        .with(|s| s.borrow().get(subscription_id).cloned())
+        .ok_or_else(|| BlockTraceError::not_found("Webhook", subscription_id))?;
+    if subscription.owner != caller {
+        return Err(BlockTraceError::unauthorized("Only the owner of a webhook can manage it"));
+    }
+    Ok(subscription)
+}
+
+#[update]
+#[candid_method(update)]
+fn register_webhook(url: String, secret: String, filter: WebhookFilter) -> ApiResult<WebhookSubscription> {
+    let url = url.trim().to_string();
+    // Plain http is accepted so local replicas can target a stand-in server
+    if !(url.starts_with("https://") || url.starts_with("http://")) {
+        return Err(BlockTraceError::invalid("url", "must start with https:// or http://"));
+    }
+    if secret.len() < 16 {
+        return Err(BlockTraceError::invalid("secret", "must be at least 16 characters"));
+    }
+    if !filter.include_steps && filter.alert_types.is_empty() {
+        return Err(BlockTraceError::invalid("filter", "must include steps or at least one alert type"));
+    }
+
+    let id = NEXT_SUBSCRIPTION_ID.with(|n| {
+        let mut next = n.borrow_mut();
+        *next += 1;
+        format!("wh-{}", *next)
+    });
+    let subscription = WebhookSubscription {
+        id: id.clone(),
+        owner: format!("{}", ic_cdk::caller()),
+        url,
+        filter,
+        active: true,
+        created_at: time(),
+    };
+    SUBSCRIPTIONS.with(|s| s.borrow_mut().insert(id.clone(), subscription.clone()));
+    SECRETS.with(|s| s.borrow_mut().insert(id.clone(), secret));
+
+    logs::info(
+        "webhooks",
+        "Webhook registered",
+        vec![("id", id.to_string()), ("owner", subscription.owner.clone()), ("url", subscription.url.clone())],
+    );
+    Ok(subscription)
+}
+
+#[update]
+#[candid_method(update)]
+fn set_webhook_active(subscription_id: String, active: bool) -> ApiResult<WebhookSubscription> {
+    let mut subscription = owned_subscription(&subscription_id, &format!("{}", ic_cdk::caller()))?;
+    subscription.active = active;
+    SUBSCRIPTIONS.with(|s| s.borrow_mut().insert(subscription_id, subscription.clone()));
+    Ok(subscription)
+}
+
+#[update]
+#[candid_method(update)]
+fn delete_webhook(subscription_id: String) -> ApiResult<String> {
+    owned_subscription(&subscription_id, &format!("{}", ic_cdk::caller()))?;
+    SUBSCRIPTIONS.with(|s| s.borrow_mut().remove(&subscription_id));
+    SECRETS.with(|s| s.borrow_mut().remove(&subscription_id));
+    Ok(format!("Webhook {} deleted", subscription_id))
+}
+
+#[query]
+#[candid_method(query)]
+fn list_my_webhooks() -> Vec<WebhookSubscription> {
+    let caller = format!("{}", ic_cdk::caller());
+    SUBSCRIPTIONS.with(|s| {
+        let mut subs: Vec<WebhookSubscription> = s.borrow().values().filter(|sub| sub.owner == caller).cloned().collect();
+        subs.sort_by_key(|sub| sub.created_at);
+        subs
+    })
+}
+
+// Most recent deliveries first.
+#[query]
+#[candid_method(query)]
+fn get_webhook_deliveries(subscription_id: String, limit: u32) -> ApiResult<Vec<WebhookDeliveryRecord>> {
+    owned_subscription(&subscription_id, &format!("{}", ic_cdk::caller()))?;
+    Ok(DELIVERY_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .rev()
+            .filter(|record| record.subscription_id == subscription_id)
+            .take(limit.clamp(1, 100) as usize)
+            .cloned()
+            .collect()
+    }))
+}
+
+// Only the status code matters to us, so drop headers and body to keep replicas in agreement.
+#[query]
+#[candid_method(query)]
+fn transform_webhook_response(args: TransformArgs) -> HttpResponse {
+    HttpResponse {
+        status: args.response.status.clone(),
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_step;
+
+    #[test]
+    fn signature_is_hmac_sha256_over_timestamp_and_body() {
+        let signature = sign_payload("key", 1, "body");
+        assert!(signature.starts_with("sha256="));
+        assert_eq!(signature.len(), "sha256=".len() + 64);
+        assert_eq!(signature, sign_payload("key", 1, "body"));
+        assert_ne!(signature, sign_payload("key", 2, "body"));
+        assert_ne!(signature, sign_payload("other", 1, "body"));
+    }
+
+    #[test]
+    fn filters_match_product_batch_and_action() {
+        let mut step = test_step("P1", "alice", 0);
+        step.batch_number = Some("BATCH001".to_string());
+        let filter = WebhookFilter {
+            product_id: Some("P1".to_string()),
+            batch_number: None,
+            action: Some("production complete".to_string()),
+            include_steps: true,
+            alert_types: vec![],
+        };
+        assert!(matches_step(&filter, &step));
+        assert!(!matches_step(&WebhookFilter { batch_number: Some("BATCH002".to_string()), ..filter.clone() }, &step));
+        assert!(!matches_step(&WebhookFilter { include_steps: false, ..filter.clone() }, &step));
+
+        let alert = WebhookAlert {
+            alert_type: "esg_score_change".to_string(),
+            product_id: "P1".to_string(),
+            batch_number: None,
+            message: String::new(),
+            timestamp: 0,
+        };
+        assert!(!matches_alert(&filter, &alert));
+        assert!(matches_alert(&WebhookFilter { alert_types: vec!["*".to_string()], ..filter }, &alert));
+    }
+}
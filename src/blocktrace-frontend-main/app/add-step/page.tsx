@@ -1,6 +1,6 @@
 "use client";
 
-import React, { useState, useEffect } from "react";
+import React, { useState, useEffect, useRef } from "react";
 import { nftClient } from "@/lib/nft-service";
 import { useRouter } from "next/navigation";
 import {
@@ -104,6 +104,11 @@ export default function AddStepPage() {
   const [isConnecting, setIsConnecting] = useState(false);
   const [isConnected, setIsConnected] = useState(false);
   const [isSubmitting, setIsSubmitting] = useState(false);
+  // Reused when the same form is resubmitted after a dropped connection, so the step isn't added twice
+  const submissionKey = useRef<string | null>(null);
+  useEffect(() => {
+    submissionKey.current = null;
+  }, [formData]);
   const [successMessage, setSuccessMessage] = useState<string | null>(null);
   const [errorMessage, setErrorMessage] = useState<string | null>(null);
   const [connectionStatus, setConnectionStatus] = useState<ConnectionStatus | null>(null);
@@ -269,6 +274,7 @@ export default function AddStepPage() {
       return setErrorMessage("❗ Not connected — please refresh");
 
     setIsSubmitting(true);
+    submissionKey.current ??= crypto.randomUUID();
     try {
       const res = await icpService.addStep({
         product_id: formData.productId.trim(),
@@ -291,7 +297,7 @@ export default function AddStepPage() {
         distance_km: formData.distanceKm ? parseFloat(formData.distanceKm) : undefined,
         cost_usd: formData.costUsd ? parseFloat(formData.costUsd) : undefined,
         blockchain_hash: formData.blockchainHash || undefined,
-      }, userProfile.principal);
+      }, userProfile.principal, submissionKey.current);
       if ("Ok" in res) {
         // Optional: Mint NFT (but don't redirect)
         let successMsg = "✅ Step successfully added to blockchain!";
@@ -166,7 +166,7 @@ export default function EnhancedTrackProductPage() {
       gpsLongitude: Array.isArray(s.gps_longitude) && s.gps_longitude.length > 0 ? s.gps_longitude[0] : undefined,
       batchNumber: Array.isArray(s.batch_number) && s.batch_number.length > 0 ? s.batch_number[0] : undefined,
       certificationHash: Array.isArray(s.certification_hash) && s.certification_hash.length > 0 ? s.certification_hash[0] : undefined,
-      estimatedArrival: Array.isArray(s.estimated_arrival) && s.estimated_arrival.length > 0 ? Number(s.estimated_arrival[0]) : undefined,
+      estimatedArrival: Array.isArray(s.estimated_arrival) && s.estimated_arrival.length > 0 ? Number(s.estimated_arrival[0]) / 1e6 : undefined,
       actualArrival: Array.isArray(s.actual_arrival) && s.actual_arrival.length > 0 ? Number(s.actual_arrival[0]) : undefined,
       qualityScore: Array.isArray(s.quality_score) && s.quality_score.length > 0 ? s.quality_score[0] : undefined,
       carbonFootprintKg: Array.isArray(s.carbon_footprint_kg) && s.carbon_footprint_kg.length > 0 ? s.carbon_footprint_kg[0] : undefined,
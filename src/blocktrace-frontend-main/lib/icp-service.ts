@@ -40,13 +40,51 @@ export type Step = {
   blockchain_hash?: [] | [string];
 };
 
+// Mirrors the backend's BlockTraceError variant; branch on the key, not on the message.
+export type BlockTraceError =
+  | { NotFound: { resource: string; id: string } }
+  | { Unauthorized: { reason: string } }
+  | { InvalidInput: { field: string; reason: string } }
+  | { ValidationFailed: { violations: Array<{ field: string; reason: string }> } }
+  | { QuotaExceeded: { reason: string; retry_after_seconds: [] | [bigint] } }
+  | { ExternalServiceFailed: { service: string; reason: string } }
+  | { Conflict: { reason: string } }
+  | { StaleHistory: { product_id: string; last_sequence: [] | [bigint]; last_step_hash: [] | [string] } }
+  | { ApprovalRequired: { method: string; threshold: number; admin_count: number } };
+
+export const describeBlockTraceError = (error: BlockTraceError): string => {
+  if ('NotFound' in error) return `${error.NotFound.resource} ${error.NotFound.id} not found`;
+  if ('Unauthorized' in error) return `Unauthorized: ${error.Unauthorized.reason}`;
+  if ('InvalidInput' in error) return `Invalid ${error.InvalidInput.field}: ${error.InvalidInput.reason}`;
+  if ('ValidationFailed' in error) {
+    return `Validation failed: ${error.ValidationFailed.violations.map((v) => `${v.field} ${v.reason}`).join('; ')}`;
+  }
+  if ('QuotaExceeded' in error) return `Quota exceeded: ${error.QuotaExceeded.reason}`;
+  if ('ExternalServiceFailed' in error) return `${error.ExternalServiceFailed.service} failed: ${error.ExternalServiceFailed.reason}`;
+  if ('Conflict' in error) return error.Conflict.reason;
+  if ('StaleHistory' in error) return `Product ${error.StaleHistory.product_id} changed since you loaded it; refresh and retry`;
+  return `${error.ApprovalRequired.method} requires admin approval`;
+};
+
+export type AddStepOptions = {
+  idempotency_key: [] | [string];
+  expected_last_sequence: [] | [bigint];
+  expected_last_step_hash: [] | [string];
+};
+
+// `Err` stays a readable message for display; `error` carries the typed variant.
 export type AddStepResult = {
   Ok?: string;
   Err?: string;
+  error?: BlockTraceError;
 };
 
 type BlockTraceService = {
-  add_step: (step: Step, caller_principal: string) => Promise<AddStepResult>;
+  add_step: (
+    step: Step,
+    caller_principal: string,
+    options: [] | [AddStepOptions],
+  ) => Promise<{ Ok: string } | { Err: BlockTraceError }>;
   get_product_history: (productId: string, caller_principal: string) => Promise<Step[]>;
   get_user_products: (caller_principal: string) => Promise<string[]>;
   get_total_steps_count: () => Promise<bigint>;
@@ -84,9 +122,33 @@ export const idlFactory = ({ IDL }: any) => {
     'blockchain_hash': IDL.Opt(IDL.Text),
   });
 
+  const BlockTraceError = IDL.Variant({
+    'NotFound': IDL.Record({ 'resource': IDL.Text, 'id': IDL.Text }),
+    'Unauthorized': IDL.Record({ 'reason': IDL.Text }),
+    'InvalidInput': IDL.Record({ 'field': IDL.Text, 'reason': IDL.Text }),
+    'ValidationFailed': IDL.Record({
+      'violations': IDL.Vec(IDL.Record({ 'field': IDL.Text, 'reason': IDL.Text })),
+    }),
+    'QuotaExceeded': IDL.Record({ 'reason': IDL.Text, 'retry_after_seconds': IDL.Opt(IDL.Nat64) }),
+    'ExternalServiceFailed': IDL.Record({ 'service': IDL.Text, 'reason': IDL.Text }),
+    'Conflict': IDL.Record({ 'reason': IDL.Text }),
+    'StaleHistory': IDL.Record({
+      'product_id': IDL.Text,
+      'last_sequence': IDL.Opt(IDL.Nat64),
+      'last_step_hash': IDL.Opt(IDL.Text),
+    }),
+    'ApprovalRequired': IDL.Record({ 'method': IDL.Text, 'threshold': IDL.Nat8, 'admin_count': IDL.Nat32 }),
+  });
+
+  const AddStepOptions = IDL.Record({
+    'idempotency_key': IDL.Opt(IDL.Text),
+    'expected_last_sequence': IDL.Opt(IDL.Nat64),
+    'expected_last_step_hash': IDL.Opt(IDL.Text),
+  });
+
   const AddStepResult = IDL.Variant({
     'Ok': IDL.Text,
-    'Err': IDL.Text,
+    'Err': BlockTraceError,
   });
 
   const ESGScore = IDL.Record({
@@ -100,7 +162,7 @@ export const idlFactory = ({ IDL }: any) => {
   });
 
   return IDL.Service({
-    'add_step': IDL.Func([Step, IDL.Text], [AddStepResult], []),
+    'add_step': IDL.Func([Step, IDL.Text, IDL.Opt(AddStepOptions)], [AddStepResult], []),
     'get_product_history': IDL.Func([IDL.Text, IDL.Text], [IDL.Vec(Step)], ['query']),
     'get_user_products': IDL.Func([IDL.Text], [IDL.Vec(IDL.Text)], ['query']),
     'get_total_steps_count': IDL.Func([], [IDL.Nat64], ['query']),
@@ -229,13 +291,14 @@ class ICPService {
     gps_longitude?: number;
     batch_number?: string;
     certification_hash?: string;
+    // Milliseconds since epoch; the canister stores nanoseconds
     estimated_arrival?: number;
     quality_score?: number;
     carbon_footprint_kg?: number;
     distance_km?: number;
     cost_usd?: number;
     blockchain_hash?: string;
-  }, userPrincipal: string): Promise<AddStepResult> {
+  }, userPrincipal: string, idempotencyKey?: string): Promise<AddStepResult> {
     await this.ensureConnected();
     // If caller didn't pass a principal, try to resolve it here to avoid orphaned steps.
     let resolvedPrincipal = userPrincipal && userPrincipal.trim() ? userPrincipal : "";
@@ -287,7 +350,7 @@ class ICPService {
       gps_longitude: data.gps_longitude !== undefined ? [data.gps_longitude] : [],
       batch_number: data.batch_number ? [data.batch_number] : [],
       certification_hash: data.certification_hash ? [data.certification_hash] : [],
-      estimated_arrival: data.estimated_arrival ? [BigInt(data.estimated_arrival)] : [],
+      estimated_arrival: data.estimated_arrival ? [BigInt(Math.round(data.estimated_arrival)) * BigInt(1_000_000)] : [],
       actual_arrival: [],
       quality_score: data.quality_score !== undefined ? [data.quality_score] : [],
       carbon_footprint_kg: data.carbon_footprint_kg !== undefined ? [data.carbon_footprint_kg] : [],
@@ -297,9 +360,18 @@ class ICPService {
     };
     
     console.log("Sending enhanced step to backend:", step);
-    const result = await this.actor!.add_step(step, resolvedPrincipal);
+    // Retrying with the same key returns the original result instead of adding a duplicate step
+    const options: AddStepOptions = {
+      idempotency_key: idempotencyKey ? [idempotencyKey] : [],
+      expected_last_sequence: [],
+      expected_last_step_hash: [],
+    };
+    const result = await this.actor!.add_step(step, resolvedPrincipal, [options]);
     console.log("Enhanced backend response:", result);
-    return result;
+    if ('Err' in result) {
+      return { Err: describeBlockTraceError(result.Err), error: result.Err };
+    }
+    return { Ok: result.Ok };
   }
 
   async getUserProducts(userPrincipal: string): Promise<string[]> {
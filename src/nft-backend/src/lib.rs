@@ -1,12 +1,35 @@
 use candid::{CandidType, Deserialize, Principal};
 use ic_cdk::api::caller as ic_caller;
 use ic_cdk::api::time as ic_time;
-use ic_cdk_macros::{init, query, update};
+use ic_cdk_macros::{init, post_upgrade, pre_upgrade, query, update};
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 
 pub type TokenId = u64;
 
+// Typed errors so clients can branch on the failure kind instead of parsing messages
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, thiserror::Error)]
+pub enum NftError {
+    #[error("{resource} {id} not found")]
+    NotFound { resource: String, id: String },
+    #[error("Unauthorized: {reason}")]
+    Unauthorized { reason: String },
+    #[error("Invalid {field}: {reason}")]
+    InvalidInput { field: String, reason: String },
+    #[error("{reason}")]
+    Conflict { reason: String },
+}
+
+impl NftError {
+    fn unauthorized(reason: &str) -> Self {
+        NftError::Unauthorized { reason: reason.to_string() }
+    }
+
+    fn token_not_found(token_id: TokenId) -> Self {
+        NftError::NotFound { resource: "Token".to_string(), id: token_id.to_string() }
+    }
+}
+
 #[derive(CandidType, Deserialize, Clone, Debug)]
 pub struct MutableFlags {
     pub transferable: bool,
@@ -54,6 +77,56 @@ thread_local! {
     static SIMPLE_NFTS: RefCell<HashMap<TokenId, SimpleMetadata>> = RefCell::new(HashMap::new());
     // Ultra-simple passport map: id -> JSON string
     static PASSPORTS: RefCell<HashMap<TokenId, String>> = RefCell::new(HashMap::new());
+    // Structured event log, bounded to MAX_LOG_ENTRIES and kept across upgrades
+    static EVENT_LOG: RefCell<VecDeque<LogEntry>> = const { RefCell::new(VecDeque::new()) };
+}
+
+const MAX_LOG_ENTRIES: usize = 1_000;
+const MAX_LOG_QUERY_LIMIT: u32 = 500;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct LogEntry {
+    pub id: u64,
+    pub timestamp: u64,
+    pub level: LogLevel,
+    pub component: String,
+    pub message: String,
+    pub fields: Vec<(String, String)>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, Default)]
+pub struct LogFilter {
+    pub min_level: Option<LogLevel>,
+    pub component: Option<String>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    pub limit: Option<u32>,
+}
+
+fn log_event(level: LogLevel, component: &str, message: &str, fields: Vec<(&str, String)>) {
+    EVENT_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        let id = log.back().map(|e| e.id + 1).unwrap_or(0);
+        if log.len() >= MAX_LOG_ENTRIES {
+            log.pop_front();
+        }
+        log.push_back(LogEntry {
+            id,
+            timestamp: ic_time(),
+            level,
+            component: component.to_string(),
+            message: message.to_string(),
+            fields: fields.into_iter().map(|(k, v)| (k.to_string(), v)).collect(),
+        });
+    });
 }
 
 fn is_manufacturer(p: &Principal) -> bool {
@@ -72,10 +145,11 @@ pub struct MintArgs {
 }
 
 #[update]
-pub fn mint_nft(args: MintArgs) -> Result<TokenId, String> {
+pub fn mint_nft(args: MintArgs) -> Result<TokenId, NftError> {
     let caller = ic_caller();
     if !(is_manufacturer(&caller)) {
-        return Err("Only manufacturer can mint".into());
+        log_event(LogLevel::Warn, "mint", "Mint rejected: caller is not a manufacturer", vec![("caller", caller.to_text())]);
+        return Err(NftError::unauthorized("Only manufacturer can mint"));
     }
     let token_id = NEXT_ID.with(|n| {
         let mut nmut = n.borrow_mut();
@@ -84,15 +158,23 @@ pub fn mint_nft(args: MintArgs) -> Result<TokenId, String> {
         id
     });
 
-    ic_cdk::println!("Minting NFT - token_id: {}, owner: {}", token_id, args.owner_principal.to_text());
-
     NFTS.with(|nfts| {
         let mut map = nfts.borrow_mut();
         map.insert(token_id, args.metadata.clone());
     });
     OWNERS.with(|o| o.borrow_mut().insert(token_id, args.owner_principal));
 
-    ic_cdk::println!("NFT minted and stored. Total tokens: {}", NFTS.with(|n| n.borrow().len()));
+    log_event(
+        LogLevel::Info,
+        "mint",
+        "NFT minted",
+        vec![
+            ("token_id", token_id.to_string()),
+            ("owner", args.owner_principal.to_text()),
+            ("batch_id", args.metadata.batch_id.clone()),
+            ("total_tokens", NFTS.with(|n| n.borrow().len()).to_string()),
+        ],
+    );
     Ok(token_id)
 }
 
@@ -104,10 +186,10 @@ pub fn get_metadata(token_id: TokenId) -> Option<Metadata> {
 }
 
 #[update]
-pub fn transfer(token_id: TokenId, new_owner: Principal) -> Result<(), String> {
+pub fn transfer(token_id: TokenId, new_owner: Principal) -> Result<(), NftError> {
     let caller = ic_caller();
     if !is_owner(token_id, &caller) {
-        return Err("Only current owner can transfer".into());
+        return Err(NftError::unauthorized("Only current owner can transfer"));
     }
     let transferable = NFTS.with(|n| {
         n.borrow()
@@ -116,7 +198,7 @@ pub fn transfer(token_id: TokenId, new_owner: Principal) -> Result<(), String> {
             .unwrap_or(false)
     });
     if !transferable {
-        return Err("Token is not transferable".into());
+        return Err(NftError::Conflict { reason: "Token is not transferable".to_string() });
     }
     OWNERS.with(|o| o.borrow_mut().insert(token_id, new_owner));
     // append transfer to history
@@ -124,12 +206,12 @@ pub fn transfer(token_id: TokenId, new_owner: Principal) -> Result<(), String> {
 }
 
 #[update]
-pub fn append_history(token_id: TokenId, event: String) -> Result<(), String> {
+pub fn append_history(token_id: TokenId, event: String) -> Result<(), NftError> {
     let caller = ic_caller();
     append_history_internal(token_id, event, caller)
 }
 
-fn append_history_internal(token_id: TokenId, event: String, actor: Principal) -> Result<(), String> {
+fn append_history_internal(token_id: TokenId, event: String, actor: Principal) -> Result<(), NftError> {
     // Permissions: manufacturer, owner, or allowed roles
     let allowed_by_role = NFTS.with(|n| {
         n.borrow().get(&token_id).map(|m| m.mutable_flags.allow_history_append_by_roles.clone())
@@ -140,7 +222,7 @@ fn append_history_internal(token_id: TokenId, event: String, actor: Principal) -
     });
 
     if !(is_manufacturer(&actor) || is_owner(token_id, &actor) || has_role) {
-        return Err("Not authorized to append history".into());
+        return Err(NftError::unauthorized("Not authorized to append history"));
     }
 
     let evt = HistoryEvent { timestamp_nanos: ic_time(), event, actor };
@@ -151,7 +233,7 @@ fn append_history_internal(token_id: TokenId, event: String, actor: Principal) -
             meta.history.push(evt);
             Ok(())
         } else {
-            Err("Token not found".into())
+            Err(NftError::token_not_found(token_id))
         }
     })
 }
@@ -162,23 +244,23 @@ pub fn owner_of(token_id: TokenId) -> Option<Principal> {
 }
 
 #[update]
-pub fn set_role(principal: Principal, role: String) -> Result<(), String> {
+pub fn set_role(principal: Principal, role: String) -> Result<(), NftError> {
     // Only manufacturer can grant roles
     let caller = ic_caller();
     if !is_manufacturer(&caller) {
-        return Err("Only manufacturer can set roles".into());
+        return Err(NftError::unauthorized("Only manufacturer can set roles"));
     }
     ROLES.with(|r| r.borrow_mut().insert(principal, role));
     Ok(())
 }
 
 #[update]
-pub fn add_manufacturer(principal: Principal) -> Result<(), String> {
+pub fn add_manufacturer(principal: Principal) -> Result<(), NftError> {
     let caller = ic_caller();
     // bootstrap: if no manufacturers exist, first caller can add themselves
     let can_add = MANUFACTURERS.with(|m| m.borrow().is_empty()) || is_manufacturer(&caller);
     if !can_add {
-        return Err("Not authorized".into());
+        return Err(NftError::unauthorized("Only a manufacturer can add manufacturers"));
     }
     MANUFACTURERS.with(|m| {
         let mut v = m.borrow_mut();
@@ -219,9 +301,9 @@ pub fn mint_nft_simple(metadata: SimpleMetadata) -> TokenId {
         *nmut += 1;
         id
     });
-    ic_cdk::println!("mint_nft_simple: token_id {}", token_id);
+    let batch_id = metadata.batch_id.clone();
     SIMPLE_NFTS.with(|m| { m.borrow_mut().insert(token_id, metadata); });
-    ic_cdk::println!("mint_nft_simple: total {}", SIMPLE_NFTS.with(|m| m.borrow().len()));
+    log_event(LogLevel::Info, "mint", "Simple NFT minted", vec![("token_id", token_id.to_string()), ("batch_id", batch_id)]);
     token_id
 }
 
@@ -247,7 +329,7 @@ pub fn mint_passport(data: String) -> TokenId {
         *nmut += 1;
         id
     });
-    ic_cdk::println!("mint_passport: id {} data {}", id, data);
+    log_event(LogLevel::Info, "mint", "Passport minted", vec![("token_id", id.to_string()), ("bytes", data.len().to_string())]);
     PASSPORTS.with(|p| { p.borrow_mut().insert(id, data); });
     id
 }
@@ -259,12 +341,47 @@ pub fn get_passport(id: TokenId) -> Option<String> {
     res
 }
 
+// Newest first; restricted to manufacturers since entries name owners and callers
+#[query]
+pub fn get_logs(filter: LogFilter) -> Result<Vec<LogEntry>, NftError> {
+    if !is_manufacturer(&ic_caller()) {
+        return Err(NftError::unauthorized("Only manufacturers can read the event log"));
+    }
+    let limit = filter.limit.unwrap_or(100).min(MAX_LOG_QUERY_LIMIT) as usize;
+    Ok(EVENT_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .rev()
+            .filter(|e| filter.min_level.is_none_or(|level| e.level >= level))
+            .filter(|e| filter.component.as_ref().is_none_or(|c| e.component.eq_ignore_ascii_case(c)))
+            .filter(|e| filter.since.is_none_or(|since| e.timestamp >= since))
+            .filter(|e| filter.until.is_none_or(|until| e.timestamp <= until))
+            .take(limit)
+            .cloned()
+            .collect()
+    }))
+}
+
 #[init]
 fn init() {
     let caller = ic_caller();
     MANUFACTURERS.with(|m| m.borrow_mut().push(caller));
 }
 
+#[pre_upgrade]
+fn pre_upgrade() {
+    let log: Vec<LogEntry> = EVENT_LOG.with(|l| l.borrow().iter().cloned().collect());
+    ic_cdk::storage::stable_save((log,)).expect("Failed to save event log before upgrade");
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    // Older versions saved nothing to stable memory; start with an empty log then
+    if let Ok((log,)) = ic_cdk::storage::stable_restore::<(Vec<LogEntry>,)>() {
+        EVENT_LOG.with(|l| *l.borrow_mut() = log.into_iter().collect());
+    }
+}
+
 // Export candid
 ic_cdk::export_candid!();
 
@@ -36,6 +36,18 @@ echo ""
 echo "🔐 Testing t-ECDSA - Cross-chain Proof Generation..."
 dfx canister call blocktrace_backend generate_cross_chain_proof '("PROD123", "ethereum")'
 
+# Test 4: Webhooks - point at a local stand-in server that answers POST with 2xx
+WEBHOOK_URL=${WEBHOOK_URL:-"http://localhost:8000/webhook"}
+echo ""
+echo "📡 Registering webhook at $WEBHOOK_URL..."
+dfx canister call blocktrace_backend register_webhook "(\"$WEBHOOK_URL\", \"local-test-secret-0123\", record {
+    product_id = opt \"PROD123\";
+    batch_number = null;
+    action = null;
+    include_steps = true;
+    alert_types = vec { \"*\" };
+})"
+
 # Add a sample product step first
 echo ""
 echo "📦 Adding sample product step..."
@@ -64,6 +76,12 @@ dfx canister call blocktrace_backend add_step '(record {
     blockchain_hash = opt "0x123abc";
 })'
 
+# Webhook deliveries for the step above
+echo ""
+echo "📡 Checking Webhook Deliveries..."
+dfx canister call blocktrace_backend list_my_webhooks
+dfx canister call blocktrace_backend get_webhook_deliveries '("wh-1", 10)'
+
 # Test HTTP Outcalls with real-time carbon data
 echo ""
 echo "🌐 Testing Real-time Carbon Data API..."